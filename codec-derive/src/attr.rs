@@ -1,9 +1,11 @@
 use proc_macro2::{Span, TokenStream};
 use quote::{quote_spanned, ToTokens, TokenStreamExt};
 use syn::parse::{Parse, ParseStream, Parser};
+use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
 use syn::{
-    parenthesized, Attribute, DeriveInput, Error, Expr, ExprPath, Field, Ident, Result, Variant,
+    parenthesized, Attribute, Data, DeriveInput, Error, Expr, ExprPath, Field, Ident, Lit, LitStr,
+    Meta, MetaNameValue, NestedMeta, Path, Result, Token, Variant,
 };
 
 pub fn get_variant_tag(variant: &Variant) -> Result<CodecMeta> {
@@ -14,11 +16,77 @@ pub fn get_variant_tag(variant: &Variant) -> Result<CodecMeta> {
 pub fn is_unknown(field: &Field) -> Result<bool> {
     match extract_codec_meta(&field.attrs)? {
         Some(CodecMeta::Unknown(_)) => Ok(true),
-        Some(CodecMeta::Tag(_)) => Err(Error::new(field.span(), "Invalid use of `tag` here")),
+        Some(CodecMeta::Tag(_) | CodecMeta::Range(_)) => {
+            Err(Error::new(field.span(), "Invalid use of `tag` here"))
+        }
         None => Ok(false),
     }
 }
 
+/// Options given with `#[codec(...)]` on a field.
+#[derive(Default)]
+pub struct FieldAttrs {
+    /// Print the field as hex in `CodecDebug`.
+    pub hex_debug: bool,
+    /// Encode and decode the field through `codec::Le`.
+    pub little_endian: bool,
+    /// Encode and decode the `Option` field through `codec::OptionDiscriminant`.
+    pub option_discriminant: bool,
+    /// Module whose `encode` and `decode` functions are used for the field instead of its
+    /// `Encode` and `Decode` implementations.
+    pub with: Option<Path>,
+    /// First protocol version with the field, it is decoded as `Default::default()` from data of
+    /// older versions.
+    pub version_gate: Option<u32>,
+}
+
+pub fn extract_field_attrs(field: &Field) -> Result<FieldAttrs> {
+    let mut field_attrs = FieldAttrs::default();
+
+    for meta in extract_codec_list(&field.attrs)? {
+        match meta {
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("hex_debug") => {
+                field_attrs.hex_debug = true
+            }
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("little_endian") => {
+                field_attrs.little_endian = true
+            }
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("option_discriminant") => {
+                field_attrs.option_discriminant = true
+            }
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                path,
+                lit: Lit::Str(module),
+                ..
+            })) if path.is_ident("with") => field_attrs.with = Some(module.parse()?),
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                path,
+                lit: Lit::Str(version),
+                ..
+            })) if path.is_ident("version_gate") => {
+                field_attrs.version_gate = Some(version.value().parse().map_err(|_| {
+                    Error::new(version.span(), "expected a protocol version number")
+                })?)
+            }
+            meta => return Err(Error::new(meta.span(), "Unknown `codec` attribute")),
+        }
+    }
+
+    let wrappers = [
+        field_attrs.little_endian,
+        field_attrs.option_discriminant,
+        field_attrs.with.is_some(),
+    ];
+    if wrappers.into_iter().filter(|&wrapper| wrapper).count() > 1 {
+        return Err(Error::new(
+            field.span(),
+            "only one of `little_endian`, `option_discriminant` and `with` can be used",
+        ));
+    }
+
+    Ok(field_attrs)
+}
+
 fn extract_codec_meta(attributes: &[Attribute]) -> Result<Option<CodecMeta>> {
     let mut codec_attr = None;
 
@@ -80,11 +148,134 @@ pub fn extract_converter(input: &DeriveInput) -> Result<Option<Converter>> {
     .map(Some)
 }
 
+/// Options given with `#[codec(...)]` on the struct or enum itself.
+#[derive(Default)]
+pub struct ContainerAttrs {
+    pub deny_unknown: bool,
+    pub assert_exhaustive: bool,
+    pub transparent: bool,
+    /// Pad the encoding of a struct with zero bytes to a multiple of this many bytes.
+    pub pad_to: Option<usize>,
+}
+
+pub fn extract_container_attrs(input: &DeriveInput) -> Result<ContainerAttrs> {
+    let mut container_attrs = ContainerAttrs::default();
+
+    for meta in extract_codec_list(&input.attrs)? {
+        match meta {
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("deny_unknown") => {
+                container_attrs.deny_unknown = true
+            }
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("assert_exhaustive") => {
+                container_attrs.assert_exhaustive = true
+            }
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("transparent") => {
+                container_attrs.transparent = true
+            }
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                path,
+                lit: Lit::Int(alignment),
+                ..
+            })) if path.is_ident("pad_to") => {
+                let pad_to = alignment.base10_parse()?;
+                if pad_to == 0 {
+                    return Err(Error::new(alignment.span(), "`pad_to` must be at least 1"));
+                }
+                container_attrs.pad_to = Some(pad_to);
+            }
+            meta => return Err(Error::new(meta.span(), "Unknown `codec` attribute")),
+        }
+    }
+
+    if container_attrs.pad_to.is_some() {
+        if !matches!(input.data, Data::Struct(_)) {
+            return Err(Error::new(
+                input.ident.span(),
+                "`pad_to` can only be used on a struct",
+            ));
+        }
+        if container_attrs.transparent {
+            return Err(Error::new(
+                input.ident.span(),
+                "`pad_to` can not be used together with `transparent`",
+            ));
+        }
+    }
+
+    Ok(container_attrs)
+}
+
+fn extract_codec_list(attributes: &[Attribute]) -> Result<Vec<NestedMeta>> {
+    let mut list = Vec::new();
+
+    for attr in attributes {
+        // Its arguments are expressions, which are not meta items, `CodecDisplay` parses it
+        if !attr.path.is_ident("codec") || is_display_attr(attr) {
+            continue;
+        }
+        match attr.parse_meta()? {
+            Meta::List(meta) => list.extend(meta.nested),
+            meta => return Err(Error::new(meta.span(), "Expected `codec(...)`")),
+        }
+    }
+
+    Ok(list)
+}
+
+/// `#[codec(display = "...", args...)]` given on the struct.
+pub struct DisplayAttr {
+    pub format: LitStr,
+    pub args: Punctuated<Expr, Token![,]>,
+}
+
+fn is_display_attr(attr: &Attribute) -> bool {
+    attr.path.is_ident("codec")
+        && attr
+            .parse_args_with(|stream: ParseStream| {
+                let is_display = stream.peek(kw::display) && stream.peek2(Token![=]);
+                stream.parse::<TokenStream>()?;
+                Ok(is_display)
+            })
+            .unwrap_or(false)
+}
+
+pub fn extract_display_attr(input: &DeriveInput) -> Result<DisplayAttr> {
+    let mut display_attrs = input.attrs.iter().filter(|attr| is_display_attr(attr));
+    let attr = display_attrs.next().ok_or_else(|| {
+        Error::new(
+            input.ident.span(),
+            "Missing `#[codec(display = \"...\", ...)]` attribute",
+        )
+    })?;
+    if let Some(attr) = display_attrs.next() {
+        return Err(Error::new(
+            attr.span(),
+            "only one instance of `display` is permitted",
+        ));
+    }
+
+    attr.parse_args_with(|stream: ParseStream| {
+        stream.parse::<kw::display>()?;
+        stream.parse::<Token![=]>()?;
+        let format = stream.parse()?;
+        let args = if stream.is_empty() {
+            Punctuated::new()
+        } else {
+            stream.parse::<Token![,]>()?;
+            Punctuated::parse_terminated(stream)?
+        };
+        Ok(DisplayAttr { format, args })
+    })
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Clone)]
 pub enum CodecMeta {
     Unknown(Span),
     Tag(Expr),
+    /// `#[tag(range = ...)]` with the range pattern, the variant keeps the actual tag in its
+    /// `#[unknown]` field.
+    Range(TokenStream),
 }
 
 impl CodecMeta {
@@ -94,7 +285,17 @@ impl CodecMeta {
         } else {
             let content;
             parenthesized!(content in stream);
-            Expr::parse(&content).map(CodecMeta::Tag)
+            if content.peek(kw::range) && content.peek2(Token![=]) {
+                content.parse::<kw::range>()?;
+                content.parse::<Token![=]>()?;
+                let range: TokenStream = content.parse()?;
+                if range.is_empty() {
+                    return Err(content.error("expected a range pattern"));
+                }
+                Ok(CodecMeta::Range(range))
+            } else {
+                Expr::parse(&content).map(CodecMeta::Tag)
+            }
         }
     }
 
@@ -103,14 +304,19 @@ impl CodecMeta {
     }
 
     pub fn opt_unknown(&self) -> Option<CodecMeta> {
-        if let CodecMeta::Unknown(_) = self {
-            Some(self.clone())
-        } else {
-            None
+        match self {
+            CodecMeta::Unknown(_) => Some(self.clone()),
+            CodecMeta::Range(range) => Some(CodecMeta::Unknown(range.span())),
+            CodecMeta::Tag(_) => None,
         }
     }
 }
 
+mod kw {
+    syn::custom_keyword!(display);
+    syn::custom_keyword!(range);
+}
+
 impl ToTokens for CodecMeta {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         match self {
@@ -118,6 +324,9 @@ impl ToTokens for CodecMeta {
                 _unknown
             }),
             CodecMeta::Tag(expr) => expr.to_tokens(tokens),
+            CodecMeta::Range(range) => tokens.append_all(quote_spanned! { range.span() =>
+                _unknown @ (#range)
+            }),
         }
     }
 }
@@ -1,4 +1,4 @@
-//! The Decode and Encode derive macros.
+//! The Decode, Encode, EncodedSize, CodecDebug and CodecDisplay derive macros.
 //!
 //! ```
 //! # use codec_derive::{Decode, Encode};
@@ -12,15 +12,48 @@ use proc_macro2::TokenStream;
 use quote::{quote, quote_spanned};
 use syn::spanned::Spanned;
 use syn::{
-    parse_macro_input, parse_quote, Data, DeriveInput, Error, Fields, GenericParam, Generics,
-    Ident, Index, Path, Result, TypeParamBound,
+    parse_macro_input, parse_quote, Data, DeriveInput, Error, Field, Fields, GenericParam,
+    Generics, Ident, Index, Path, Result, Type, TypeParamBound,
 };
 
 mod attr;
-use attr::{CodecMeta, Converter};
+use attr::{CodecMeta, ContainerAttrs, Converter};
 
 /// The `Decode` derive macro.
-#[proc_macro_derive(Decode, attributes(tag, unknown))]
+///
+/// An enum marked with `#[codec(deny_unknown)]` does not need an `#[unknown]` variant, instead
+/// decoding a tag that matches no variant returns `DecodeError::UnknownVariant`. The tag type
+/// must then be convertible into `u64`.
+///
+/// An enum marked with `#[codec(assert_exhaustive)]` panics instead when decoding a tag that
+/// matches no variant, so its `#[unknown]` variant is never decoded. This surfaces protocol
+/// violations loudly in tests, the tag type must implement `Debug`.
+///
+/// A variant marked with `#[tag(range = 0x80..=0x8F)]` decodes every tag matching the range
+/// pattern, keeping the tag in its `#[unknown]` field. Exact tags are matched first, then ranges
+/// in the order of declaration, and the single `#[unknown]` variant last.
+///
+/// A field marked with `#[codec(version_gate = "2")]` is only decoded when the read buffer's
+/// `protocol_version` is at least 2 or unknown, otherwise it is `Default::default()`. Encoding
+/// always writes it.
+///
+/// A struct with a single field marked with `#[codec(transparent)]` decodes exactly like that
+/// field.
+///
+/// A struct marked with `#[codec(pad_to = 4)]` skips the padding its encoding is followed by, up
+/// to the next multiple of 4 bytes counted from the start of the struct.
+///
+/// An `Ignore` field eats all the remaining data, so it can only be the last field:
+///
+/// ```compile_fail
+/// # use codec_derive::Decode;
+/// #[derive(Decode)]
+/// struct Header {
+///     rest: codec::Ignore,
+///     kind: u8,
+/// }
+/// ```
+#[proc_macro_derive(Decode, attributes(tag, unknown, codec))]
 pub fn decode_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let mut input = parse_macro_input!(input as DeriveInput);
 
@@ -34,7 +67,12 @@ pub fn decode_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream
         Err(err) => return err.to_compile_error().into(),
     };
 
-    decode_data(name, &input.data, converter.as_ref())
+    let container_attrs = match attr::extract_container_attrs(&input) {
+        Ok(container_attrs) => container_attrs,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    decode_data(name, &input.data, converter.as_ref(), &container_attrs)
         .map(|impl_decode| {
             quote! {
                 impl #impl_generics ::codec::Decode for #name #ty_generics #where_clause {
@@ -51,7 +89,13 @@ pub fn decode_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream
 }
 
 /// The `Encode` derive macro.
-#[proc_macro_derive(Encode, attributes(tag, unknown))]
+///
+/// A struct with a single field marked with `#[codec(transparent)]` encodes exactly like that
+/// field.
+///
+/// A struct marked with `#[codec(pad_to = 4)]` is followed by zero bytes up to the next multiple
+/// of 4 bytes.
+#[proc_macro_derive(Encode, attributes(tag, unknown, codec))]
 pub fn encode_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let mut input = parse_macro_input!(input as DeriveInput);
 
@@ -65,7 +109,18 @@ pub fn encode_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream
         Err(err) => return err.to_compile_error().into(),
     };
 
-    encode_data(name, &input.data, converter.as_ref())
+    let container_attrs = match attr::extract_container_attrs(&input) {
+        Ok(container_attrs) => container_attrs,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    let impl_encode = if container_attrs.transparent {
+        encode_transparent(name, &input.data)
+    } else {
+        encode_data(name, &input.data, converter.as_ref(), &container_attrs)
+    };
+
+    impl_encode
         .map(|impl_encode| {
             quote! {
                 impl #impl_generics ::codec::Encode for #name #ty_generics #where_clause {
@@ -82,6 +137,111 @@ pub fn encode_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream
         .into()
 }
 
+/// The `EncodedSize` derive macro.
+///
+/// Implements `EncodedSize` for a struct by summing the `ENCODED_SIZE` of its fields, including
+/// the padding of `#[codec(pad_to = N)]`. Every field must have a fixed size too:
+///
+/// ```compile_fail
+/// # use codec_derive::EncodedSize;
+/// #[derive(EncodedSize)]
+/// struct Message {
+///     kind: u8,
+///     payload: Vec<u8>,
+/// }
+/// ```
+#[proc_macro_derive(EncodedSize, attributes(codec))]
+pub fn encoded_size_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+
+    let name = &input.ident;
+
+    add_trait_bounds(&mut input.generics, &parse_quote!(::codec::EncodedSize));
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    encoded_size_struct(&input)
+        .map(|size| {
+            quote! {
+                impl #impl_generics ::codec::EncodedSize for #name #ty_generics #where_clause {
+                    const ENCODED_SIZE: usize = #size;
+                }
+            }
+        })
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// The `CodecDebug` derive macro.
+///
+/// Implements `Debug` like the standard derive does, except that fields marked with
+/// `#[codec(hex_debug)]` are printed as a `0x` prefixed hex string. Such fields must implement
+/// `AsRef<[u8]>`.
+#[proc_macro_derive(CodecDebug, attributes(codec))]
+pub fn debug_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+
+    let name = &input.ident;
+
+    add_trait_bounds(&mut input.generics, &parse_quote!(::core::fmt::Debug));
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    debug_data(name, &input.data)
+        .map(|impl_debug| {
+            quote! {
+                impl #impl_generics ::core::fmt::Debug for #name #ty_generics #where_clause {
+                    fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                        struct HexDebug<'a>(&'a [u8]);
+
+                        impl ::core::fmt::Debug for HexDebug<'_> {
+                            fn fmt(
+                                &self,
+                                f: &mut ::core::fmt::Formatter<'_>
+                            ) -> ::core::fmt::Result {
+                                f.write_str("0x")?;
+                                for byte in self.0 {
+                                    write!(f, "{:02x}", byte)?;
+                                }
+                                Ok(())
+                            }
+                        }
+
+                        #impl_debug
+                    }
+                }
+            }
+        })
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// The `CodecDisplay` derive macro.
+///
+/// Implements `Display` for a struct marked with
+/// `#[codec(display = "Packet({source} -> {target}, {} bytes)", source, target, payload.len())]`,
+/// formatting the listed arguments with the format string. Arguments naming a field are passed
+/// by name, so they are referenced as `{source}`. Other expressions, which can use the fields
+/// too, fill the positional placeholders in order.
+#[proc_macro_derive(CodecDisplay, attributes(codec))]
+pub fn display_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    display_struct(&input)
+        .map(|impl_display| {
+            quote! {
+                impl #impl_generics ::core::fmt::Display for #name #ty_generics #where_clause {
+                    fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                        #impl_display
+                    }
+                }
+            }
+        })
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
 fn add_trait_bounds(generics: &mut Generics, bound: &TypeParamBound) {
     for param in &mut generics.params {
         if let GenericParam::Type(type_param) = param {
@@ -91,6 +251,8 @@ fn add_trait_bounds(generics: &mut Generics, bound: &TypeParamBound) {
 }
 
 fn decode_fields(name: Path, fields: &Fields, unknown: Option<CodecMeta>) -> Result<TokenStream> {
+    check_ignore_is_last(fields)?;
+
     match fields {
         Fields::Named(fields) => {
             let impl_fields = fields
@@ -98,7 +260,6 @@ fn decode_fields(name: Path, fields: &Fields, unknown: Option<CodecMeta>) -> Res
                 .iter()
                 .map(|field| {
                     let field_name = &field.ident;
-                    let field_ty = &field.ty;
 
                     match (attr::is_unknown(field)?, &unknown) {
                         (true, Some(meta)) => Ok(quote! {
@@ -107,9 +268,10 @@ fn decode_fields(name: Path, fields: &Fields, unknown: Option<CodecMeta>) -> Res
                         (true, None) => {
                             Err(Error::new(field.span(), "`unknown` can not be used here"))
                         }
-                        (false, _) => Ok(quote_spanned! { field.span() =>
-                            #field_name: <#field_ty as ::codec::Decode>::decode(read_buffer)?
-                        }),
+                        (false, _) => {
+                            let value = decode_field(field)?;
+                            Ok(quote! { #field_name: #value })
+                        }
                     }
                 })
                 .collect::<Result<Vec<_>>>()?;
@@ -125,20 +287,12 @@ fn decode_fields(name: Path, fields: &Fields, unknown: Option<CodecMeta>) -> Res
             let impl_fields = fields
                 .unnamed
                 .iter()
-                .map(|field| {
-                    let field_ty = &field.ty;
-
-                    match (attr::is_unknown(field)?, &unknown) {
-                        (true, Some(meta)) => Ok(quote! {
-                            #meta
-                        }),
-                        (true, None) => {
-                            Err(Error::new(field.span(), "`unknown` can not be used here"))
-                        }
-                        (false, _) => Ok(quote_spanned! { field.span() =>
-                            <#field_ty as ::codec::Decode>::decode(read_buffer)?
-                        }),
-                    }
+                .map(|field| match (attr::is_unknown(field)?, &unknown) {
+                    (true, Some(meta)) => Ok(quote! {
+                        #meta
+                    }),
+                    (true, None) => Err(Error::new(field.span(), "`unknown` can not be used here")),
+                    (false, _) => decode_field(field),
                 })
                 .collect::<Result<Vec<_>>>()?;
 
@@ -151,9 +305,149 @@ fn decode_fields(name: Path, fields: &Fields, unknown: Option<CodecMeta>) -> Res
     }
 }
 
-fn decode_data(name: &Ident, data: &Data, converter: Option<&Converter>) -> Result<TokenStream> {
+/// Decode a single field, going through the wrapper type selected by its `codec` attributes.
+/// Reject `Ignore` fields followed by other fields, since it eats all the data left for them.
+///
+/// The check goes by the name of the type, so it does not see through type aliases.
+fn check_ignore_is_last(fields: &Fields) -> Result<()> {
+    let mut fields = fields.iter().peekable();
+    while let Some(field) = fields.next() {
+        let is_ignore = match &field.ty {
+            Type::Path(path) => path
+                .path
+                .segments
+                .last()
+                .is_some_and(|segment| segment.ident == "Ignore"),
+            _ => false,
+        };
+        if is_ignore && fields.peek().is_some() {
+            return Err(Error::new(
+                field.ty.span(),
+                "`Ignore` eats all the remaining data, it can only be the last field",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn decode_field(field: &Field) -> Result<TokenStream> {
+    let field_ty = &field.ty;
+    let field_attrs = attr::extract_field_attrs(field)?;
+
+    let value = if field_attrs.little_endian {
+        quote_spanned! { field.span() =>
+            <::codec::Le<#field_ty> as ::codec::Decode>::decode(read_buffer)?.0
+        }
+    } else if field_attrs.option_discriminant {
+        quote_spanned! { field.span() =>
+            ::codec::OptionDiscriminant::into_inner(::codec::Decode::decode(read_buffer)?)
+        }
+    } else if let Some(module) = &field_attrs.with {
+        quote_spanned! { field.span() =>
+            #module::decode(read_buffer)?
+        }
+    } else {
+        quote_spanned! { field.span() =>
+            <#field_ty as ::codec::Decode>::decode(read_buffer)?
+        }
+    };
+
+    match field_attrs.version_gate {
+        Some(version) => Ok(quote_spanned! { field.span() =>
+            if ::codec::decode::ReadBuffer::protocol_version(&*read_buffer)
+                .map_or(true, |version| version >= #version)
+            {
+                #value
+            } else {
+                ::core::default::Default::default()
+            }
+        }),
+        None => Ok(value),
+    }
+}
+
+/// The only field of a struct marked with `#[codec(transparent)]`.
+fn transparent_field<'a>(name: &Ident, data: &'a Data) -> Result<&'a Field> {
+    let field = match data {
+        Data::Struct(data) if data.fields.len() == 1 => data.fields.iter().next().unwrap(),
+        _ => {
+            return Err(Error::new(
+                name.span(),
+                "`transparent` can only be used on a struct with a single field",
+            ))
+        }
+    };
+    let field_attrs = attr::extract_field_attrs(field)?;
+    if field_attrs.little_endian
+        || field_attrs.option_discriminant
+        || field_attrs.with.is_some()
+        || field_attrs.version_gate.is_some()
+    {
+        return Err(Error::new(
+            field.span(),
+            "`little_endian`, `option_discriminant`, `with` and `version_gate` can not be used \
+             with `transparent`",
+        ));
+    }
+    Ok(field)
+}
+
+fn decode_transparent(name: &Ident, data: &Data) -> Result<TokenStream> {
+    let field = transparent_field(name, data)?;
+    let field_ty = &field.ty;
+    let constructor = match &field.ident {
+        Some(field_name) => quote! { |inner| #name { #field_name: inner } },
+        None => quote! { #name },
+    };
+
+    Ok(quote! {
+        <#field_ty as ::codec::Decode>::decode(read_buffer).map(#constructor)
+    })
+}
+
+fn decode_data(
+    name: &Ident,
+    data: &Data,
+    converter: Option<&Converter>,
+    container_attrs: &ContainerAttrs,
+) -> Result<TokenStream> {
     match data {
-        Data::Struct(data) => decode_fields(name.clone().into(), &data.fields, None),
+        Data::Struct(_) if container_attrs.deny_unknown => Err(Error::new(
+            name.span(),
+            "`deny_unknown` can only be used on an enum",
+        )),
+
+        Data::Struct(_) if container_attrs.assert_exhaustive => Err(Error::new(
+            name.span(),
+            "`assert_exhaustive` can only be used on an enum",
+        )),
+
+        Data::Enum(_) if container_attrs.deny_unknown && container_attrs.assert_exhaustive => {
+            Err(Error::new(
+                name.span(),
+                "`assert_exhaustive` can not be used together with `deny_unknown`",
+            ))
+        }
+
+        _ if container_attrs.transparent => decode_transparent(name, data),
+
+        Data::Struct(data) => {
+            let impl_fields = decode_fields(name.clone().into(), &data.fields, None)?;
+            match container_attrs.pad_to {
+                Some(pad_to) => Ok(quote! {
+                    let mut counted = ::codec::decode::Counted::new(read_buffer);
+                    let value: ::core::result::Result<Self, ReadBufferMacroInternal::Error> = {
+                        let read_buffer = &mut counted;
+                        #impl_fields
+                    };
+                    let value = value?;
+                    let padding = ::codec::Pad::<#pad_to>::after(counted.consumed());
+                    padding.skip(read_buffer)?;
+                    Ok(value)
+                }),
+                None => Ok(impl_fields),
+            }
+        }
 
         Data::Enum(data) => {
             let tag_constants = if let Some(converter) = converter {
@@ -164,9 +458,16 @@ fn decode_data(name: &Ident, data: &Data, converter: Option<&Converter>) -> Resu
                     .map(|(index, variant)| -> Result<TokenStream> {
                         let current_tag = attr::get_variant_tag(variant)?;
 
-                        if current_tag.is_unknown() {
-                            return Ok(quote! {});
-                        }
+                        let current_tag = match current_tag {
+                            CodecMeta::Unknown(_) => return Ok(quote! {}),
+                            CodecMeta::Range(range) => {
+                                return Err(Error::new(
+                                    range.span(),
+                                    "`range` can not be used together with a tag converter",
+                                ))
+                            }
+                            CodecMeta::Tag(expr) => expr,
+                        };
 
                         let name = Ident::new(&format!("_{}", index), variant.span());
 
@@ -179,13 +480,30 @@ fn decode_data(name: &Ident, data: &Data, converter: Option<&Converter>) -> Resu
                 Vec::new()
             };
 
-            let impl_variants = data
+            let mut unknown_variants = data.variants.iter().filter(
+                |variant| matches!(attr::get_variant_tag(variant), Ok(tag) if tag.is_unknown()),
+            );
+            if let (Some(_), Some(second)) = (unknown_variants.next(), unknown_variants.next()) {
+                return Err(Error::new(
+                    second.span(),
+                    "only one `unknown` variant is permitted, use `tag(range = ...)` for others",
+                ));
+            }
+
+            let mut impl_variants = data
                 .variants
                 .iter()
                 .enumerate()
-                .map(|(index, variant)| -> Result<TokenStream> {
+                .map(|(index, variant)| -> Result<(u8, TokenStream)> {
                     let current_tag = attr::get_variant_tag(variant)?;
 
+                    if container_attrs.deny_unknown && current_tag.is_unknown() {
+                        return Err(Error::new(
+                            variant.span(),
+                            "`unknown` can not be used together with `deny_unknown`",
+                        ));
+                    }
+
                     let variant_name = &variant.ident;
                     let decode_variant = decode_fields(
                         parse_quote!(#name::#variant_name),
@@ -193,26 +511,62 @@ fn decode_data(name: &Ident, data: &Data, converter: Option<&Converter>) -> Resu
                         current_tag.opt_unknown(),
                     )?;
 
-                    if converter.is_some() && !current_tag.is_unknown() {
-                        let const_name = Ident::new(&format!("_{}", index), variant.span());
-                        Ok(quote! {
-                            #const_name => #decode_variant
-                        })
-                    } else {
-                        Ok(quote! {
-                            #current_tag => #decode_variant
-                        })
+                    // Exact tags are matched first, then ranges and finally the `unknown` variant
+                    match current_tag {
+                        CodecMeta::Tag(_) if converter.is_some() => {
+                            let const_name = Ident::new(&format!("_{}", index), variant.span());
+                            Ok((0, quote! { #const_name => #decode_variant }))
+                        }
+                        CodecMeta::Tag(_) => Ok((0, quote! { #current_tag => #decode_variant })),
+                        CodecMeta::Range(_) => Ok((1, quote! { #current_tag => #decode_variant })),
+                        CodecMeta::Unknown(_) if container_attrs.assert_exhaustive => {
+                            Ok((2, quote! { _ => unexpected_tag(tag) }))
+                        }
+                        CodecMeta::Unknown(_) => {
+                            Ok((2, quote! { #current_tag => #decode_variant }))
+                        }
                     }
                 })
                 .collect::<Result<Vec<_>>>()?;
+            impl_variants.sort_by_key(|(order, _)| *order);
+            let has_unknown = impl_variants.iter().any(|(order, _)| *order == 2);
+            let impl_variants = impl_variants.into_iter().map(|(_, tokens)| tokens);
+
+            let deny_unknown = if container_attrs.deny_unknown {
+                quote! {
+                    , _ => Err(::codec::decode::DecodeError::UnknownVariant(
+                        ::core::convert::Into::<u64>::into(tag)
+                    ).into())
+                }
+            } else if container_attrs.assert_exhaustive && !has_unknown {
+                quote! { , _ => unexpected_tag(tag) }
+            } else {
+                quote! {}
+            };
+
+            let unexpected_tag = if container_attrs.assert_exhaustive {
+                let message = format!("Unexpected tag of `{}`: {{:?}}", name);
+                quote! {
+                    #[cold]
+                    #[inline(never)]
+                    fn unexpected_tag<T: ::core::fmt::Debug>(tag: T) -> ! {
+                        panic!(#message, tag)
+                    }
+                }
+            } else {
+                quote! {}
+            };
 
             Ok(quote! {
+                #unexpected_tag
+
                 let tag = ::codec::Decode::decode(read_buffer)?;
 
                 #(#tag_constants)*
 
                 match tag {
                     #(#impl_variants),*
+                    #deny_unknown
                 }
             })
         }
@@ -274,8 +628,9 @@ fn field_list(fields: &Fields) -> TokenStream {
                 .filter(|field| !attr::is_unknown(field).unwrap_or(false))
                 .map(|field| &field.ident);
 
+            // Every field can be `#[unknown]`, so each binding brings its own comma
             quote! {
-                { #(#fields),* , .. }
+                { #(#fields ,)* .. }
             }
         }
 
@@ -312,9 +667,7 @@ fn encode_fields(with_self: bool, fields: &Fields) -> TokenStream {
                     quote! { #field_name }
                 };
 
-                quote_spanned! { field.span() =>
-                    ::codec::Encode::encode(#field_name, write_buffer)?
-                }
+                encode_field(field, field_name)
             });
 
             quote! {
@@ -336,9 +689,7 @@ fn encode_fields(with_self: bool, fields: &Fields) -> TokenStream {
                     quote! { #name }
                 };
 
-                quote_spanned! { field.span() =>
-                    ::codec::Encode::encode(#field_name, write_buffer)?
-                }
+                encode_field(field, field_name)
             });
 
             quote! {
@@ -350,13 +701,67 @@ fn encode_fields(with_self: bool, fields: &Fields) -> TokenStream {
     }
 }
 
-fn encode_data(name: &Ident, data: &Data, converter: Option<&Converter>) -> Result<TokenStream> {
+/// Encode a single field given a reference to it, going through the wrapper type selected by its
+/// `codec` attributes.
+fn encode_field(field: &Field, field_ref: TokenStream) -> TokenStream {
+    let field_attrs = attr::extract_field_attrs(field).unwrap_or_default();
+
+    if field_attrs.little_endian {
+        quote_spanned! { field.span() =>
+            ::codec::Encode::encode(&::codec::Le(*#field_ref), write_buffer)?
+        }
+    } else if field_attrs.option_discriminant {
+        quote_spanned! { field.span() =>
+            ::codec::Encode::encode(
+                &::codec::OptionDiscriminant(::core::option::Option::as_ref(#field_ref)),
+                write_buffer,
+            )?
+        }
+    } else if let Some(module) = &field_attrs.with {
+        quote_spanned! { field.span() =>
+            #module::encode(#field_ref, write_buffer)?
+        }
+    } else {
+        quote_spanned! { field.span() =>
+            ::codec::Encode::encode(#field_ref, write_buffer)?
+        }
+    }
+}
+
+fn encode_transparent(name: &Ident, data: &Data) -> Result<TokenStream> {
+    let field = transparent_field(name, data)?;
+    let field_name = match &field.ident {
+        Some(field_name) => quote! { #field_name },
+        None => {
+            let index = Index::from(0);
+            quote! { #index }
+        }
+    };
+
+    Ok(quote! {
+        ::codec::Encode::encode(&self.#field_name, write_buffer)
+    })
+}
+
+fn encode_data(
+    name: &Ident,
+    data: &Data,
+    converter: Option<&Converter>,
+    container_attrs: &ContainerAttrs,
+) -> Result<TokenStream> {
     match data {
         Data::Struct(data) => {
             let impl_fields = encode_fields(true, &data.fields);
-            Ok(quote! {
-                Ok(#impl_fields)
-            })
+            match container_attrs.pad_to {
+                Some(pad_to) => Ok(quote! {
+                    let unpadded = #impl_fields;
+                    let padding = ::codec::Pad::<#pad_to>::after(unpadded);
+                    Ok(unpadded + ::codec::Encode::encode(&padding, write_buffer)?)
+                }),
+                None => Ok(quote! {
+                    Ok(#impl_fields)
+                }),
+            }
         }
 
         Data::Enum(data) => {
@@ -388,6 +793,15 @@ fn encode_data(name: &Ident, data: &Data, converter: Option<&Converter>) -> Resu
                                 #name::#variant_name { .. } => { #expr },
                             })
                         }
+                        CodecMeta::Range(range) => match extract_unknown(&variant.fields) {
+                            Some(extract_unknown) => Ok(quote! {
+                                #name::#variant_name #extract_unknown,
+                            }),
+                            None => Err(Error::new(
+                                range.span(),
+                                "a `range` variant needs an `#[unknown]` field holding its tag",
+                            )),
+                        },
                     }
                 })
                 .collect::<Result<Vec<_>>>()?;
@@ -425,6 +839,178 @@ fn encode_data(name: &Ident, data: &Data, converter: Option<&Converter>) -> Resu
     }
 }
 
+fn debug_fields(name: Path, label: &Ident, fields: &Fields) -> Result<TokenStream> {
+    let label = label.to_string();
+    let field_value = |field: &syn::Field, binding: &Ident| -> Result<TokenStream> {
+        if attr::extract_field_attrs(field)?.hex_debug {
+            Ok(quote! { &HexDebug(::core::convert::AsRef::<[u8]>::as_ref(#binding)) })
+        } else {
+            Ok(quote! { #binding })
+        }
+    };
+
+    match fields {
+        Fields::Named(fields) => {
+            let bindings: Vec<_> = fields.named.iter().map(|field| &field.ident).collect();
+            let impl_fields = fields
+                .named
+                .iter()
+                .map(|field| {
+                    let field_name = field.ident.as_ref().expect("named field");
+                    let value = field_value(field, field_name)?;
+                    let field_label = field_name.to_string();
+                    Ok(quote! { .field(#field_label, #value) })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(quote! {
+                #name { #(#bindings),* } => f.debug_struct(#label) #(#impl_fields)* .finish()
+            })
+        }
+
+        Fields::Unnamed(fields) => {
+            let bindings: Vec<_> = fields
+                .unnamed
+                .iter()
+                .enumerate()
+                .map(|(index, field)| Ident::new(&format!("_{}", index), field.span()))
+                .collect();
+            let impl_fields = fields
+                .unnamed
+                .iter()
+                .zip(&bindings)
+                .map(|(field, binding)| {
+                    let value = field_value(field, binding)?;
+                    Ok(quote! { .field(#value) })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(quote! {
+                #name ( #(#bindings),* ) => f.debug_tuple(#label) #(#impl_fields)* .finish()
+            })
+        }
+
+        Fields::Unit => Ok(quote! {
+            #name => f.write_str(#label)
+        }),
+    }
+}
+
+fn debug_data(name: &Ident, data: &Data) -> Result<TokenStream> {
+    let arms = match data {
+        Data::Struct(data) => vec![debug_fields(name.clone().into(), name, &data.fields)?],
+
+        Data::Enum(data) => data
+            .variants
+            .iter()
+            .map(|variant| {
+                let variant_name = &variant.ident;
+                debug_fields(
+                    parse_quote!(#name::#variant_name),
+                    variant_name,
+                    &variant.fields,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?,
+
+        Data::Union(_) => {
+            return Err(Error::new(
+                name.span(),
+                "CodecDebug is not implemented for `union`",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        match self {
+            #(#arms),*
+        }
+    })
+}
+
+fn encoded_size_struct(input: &DeriveInput) -> Result<TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "EncodedSize is only implemented for `struct`",
+            ))
+        }
+    };
+    let container_attrs = attr::extract_container_attrs(input)?;
+
+    let field_sizes = fields
+        .iter()
+        .map(|field| {
+            let field_ty = &field.ty;
+            let field_attrs = attr::extract_field_attrs(field)?;
+            if field_attrs.with.is_some() || field_attrs.option_discriminant {
+                return Err(Error::new(
+                    field.span(),
+                    "the encoded size of this field is not known",
+                ));
+            }
+            Ok(if field_attrs.little_endian {
+                quote_spanned! { field_ty.span() =>
+                    <::codec::Le<#field_ty> as ::codec::EncodedSize>::ENCODED_SIZE
+                }
+            } else {
+                quote_spanned! { field_ty.span() =>
+                    <#field_ty as ::codec::EncodedSize>::ENCODED_SIZE
+                }
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let unpadded = quote! { 0 #(+ #field_sizes)* };
+    Ok(match container_attrs.pad_to {
+        Some(pad_to) => quote! {{
+            let unpadded = #unpadded;
+            unpadded + (#pad_to - unpadded % #pad_to) % #pad_to
+        }},
+        None => unpadded,
+    })
+}
+
+fn display_struct(input: &DeriveInput) -> Result<TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "CodecDisplay is only implemented for `struct`",
+            ))
+        }
+    };
+    let display_attr = attr::extract_display_attr(input)?;
+
+    let field_names: Vec<_> = match fields {
+        Fields::Named(fields) => fields.named.iter().flat_map(|field| &field.ident).collect(),
+        Fields::Unnamed(_) | Fields::Unit => Vec::new(),
+    };
+    let bindings = match fields {
+        Fields::Named(_) => quote! {
+            #[allow(unused_variables)]
+            let Self { #(#field_names),* } = self;
+        },
+        Fields::Unnamed(_) | Fields::Unit => TokenStream::new(),
+    };
+
+    let is_field = |arg: &&syn::Expr| match arg {
+        syn::Expr::Path(path) => field_names.iter().any(|&name| path.path.is_ident(name)),
+        _ => false,
+    };
+    // Named arguments have to follow the positional ones
+    let (named, positional): (Vec<_>, Vec<_>) = display_attr.args.iter().partition(is_field);
+    let format = &display_attr.format;
+
+    Ok(quote! {
+        #bindings
+        ::core::write!(f, #format, #(#positional,)* #(#named = #named),*)
+    })
+}
+
 fn call_converter(converter: Option<&Converter>, expr: TokenStream) -> TokenStream {
     if let Some(converter) = converter {
         let converter = &converter.0;
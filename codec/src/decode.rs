@@ -1,13 +1,38 @@
 //! Network order decoding of types.
-use std::convert::Infallible;
-use std::fmt::Debug;
-use std::mem;
+#[cfg(feature = "alloc")]
+use alloc::{borrow::Cow, collections::BTreeSet, string::String, sync::Arc, vec::Vec};
+use core::convert::Infallible;
+use core::fmt::Debug;
+use core::mem;
+use core::num::Wrapping;
+use core::ops::RangeInclusive;
+#[cfg(feature = "std")]
+use std::collections::HashSet;
+#[cfg(feature = "std")]
+use std::hash::Hash;
 
-use crate::{Ignore, Opaque, SizeWrapper};
+#[cfg(feature = "alloc")]
+use crate::Opaque;
+use crate::{Ignore, Le, OptionDiscriminant, PrimitiveInt, SizeWrapper};
 
-/// The error returned by a read buffer when it has insufficient bytes.
-#[derive(Debug)]
-pub struct DecodeError;
+/// The error returned when data can not be decoded.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The read buffer has insufficient bytes.
+    InsufficientBytes,
+    /// A size prefix does not match the data following it.
+    InvalidSize,
+    /// A string is not valid UTF-8.
+    InvalidUtf8,
+    /// Embedded JSON can not be deserialized into the expected type. Only returned with the
+    /// `serde-bridge` feature, but always defined so that enabling it does not break matches.
+    InvalidJson,
+    /// A tag that does not match any variant of an enum with `#[codec(deny_unknown)]`, or a
+    /// discriminant of an `OptionDiscriminant` other than `0x00` or `0x01`.
+    UnknownVariant(u64),
+    /// A valid tag other than the one expected at this position, e.g. a frame of another type.
+    UnexpectedVariant(u64),
+}
 
 /// A read buffer where data can be decoded from.
 pub trait ReadBuffer {
@@ -21,7 +46,154 @@ pub trait ReadBuffer {
     fn fill_buf(&mut self, size: usize) -> Result<&[u8], Self::Error>;
 
     /// Return all available bytes in this read buffer.
+    ///
+    /// This must be bounded by what the buffer already holds. Read buffers over a stream can
+    /// not collect the rest of it, especially in `no_std` environments without an allocator.
     fn fill_all(&mut self) -> &[u8];
+
+    /// Protocol version the data was written in, which decides whether fields marked with
+    /// `#[codec(version_gate = "N")]` are present. `None` if unknown, then all fields are.
+    fn protocol_version(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// A read buffer whose next bytes can be inspected without taking them.
+///
+/// Separate from `ReadBuffer` so that read buffers which can not look ahead still implement it.
+pub trait PeekBuffer: ReadBuffer {
+    /// Whether the next bytes are `prefix`, without taking them. Lets magic bytes be checked
+    /// before decoding the rest.
+    ///
+    /// Like `ReadBuffer::fill_all`, this only looks at what the buffer already holds.
+    fn starts_with(&self, prefix: &[u8]) -> bool;
+}
+
+/// A read buffer that knows the protocol version of its data, see `ReadBuffer::protocol_version`.
+///
+/// Size prefixed values are decoded with the same version.
+#[derive(Debug)]
+pub struct Versioned<R> {
+    inner: R,
+    version: Option<u32>,
+}
+
+impl<R: ReadBuffer> Versioned<R> {
+    /// Decode the data of `inner` as written in protocol `version`.
+    pub fn new(inner: R, version: u32) -> Self {
+        Self {
+            inner,
+            version: Some(version),
+        }
+    }
+
+    /// Extract the wrapped read buffer.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: ReadBuffer> ReadBuffer for Versioned<R> {
+    type Error = R::Error;
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn fill_buf(&mut self, size: usize) -> Result<&[u8], Self::Error> {
+        self.inner.fill_buf(size)
+    }
+
+    fn fill_all(&mut self) -> &[u8] {
+        self.inner.fill_all()
+    }
+
+    fn protocol_version(&self) -> Option<u32> {
+        self.version
+    }
+}
+
+impl<R: PeekBuffer> PeekBuffer for Versioned<R> {
+    fn starts_with(&self, prefix: &[u8]) -> bool {
+        self.inner.starts_with(prefix)
+    }
+}
+
+/// A read buffer counting the bytes taken from it, used to find where the padding of a
+/// `#[codec(pad_to = N)]` struct starts.
+#[derive(Debug)]
+pub struct Counted<'a, R> {
+    inner: &'a mut R,
+    consumed: usize,
+}
+
+impl<'a, R: ReadBuffer> Counted<'a, R> {
+    /// Count the bytes taken from `inner` from now on.
+    pub fn new(inner: &'a mut R) -> Self {
+        Self { inner, consumed: 0 }
+    }
+
+    /// Number of bytes taken so far.
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+}
+
+impl<R: ReadBuffer> ReadBuffer for Counted<'_, R> {
+    type Error = R::Error;
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn fill_buf(&mut self, size: usize) -> Result<&[u8], Self::Error> {
+        let buf = self.inner.fill_buf(size)?;
+        self.consumed += buf.len();
+        Ok(buf)
+    }
+
+    fn fill_all(&mut self) -> &[u8] {
+        let buf = self.inner.fill_all();
+        self.consumed += buf.len();
+        buf
+    }
+
+    fn protocol_version(&self) -> Option<u32> {
+        self.inner.protocol_version()
+    }
+}
+
+impl<R: PeekBuffer> PeekBuffer for Counted<'_, R> {
+    fn starts_with(&self, prefix: &[u8]) -> bool {
+        self.inner.starts_with(prefix)
+    }
+}
+
+/// Lets helpers taking a read buffer by value be called with `&mut read_buffer`.
+impl<R: ReadBuffer> ReadBuffer for &mut R {
+    type Error = R::Error;
+
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+
+    fn fill_buf(&mut self, size: usize) -> Result<&[u8], Self::Error> {
+        (**self).fill_buf(size)
+    }
+
+    fn fill_all(&mut self) -> &[u8] {
+        (**self).fill_all()
+    }
+
+    fn protocol_version(&self) -> Option<u32> {
+        (**self).protocol_version()
+    }
+}
+
+impl<R: PeekBuffer> PeekBuffer for &mut R {
+    fn starts_with(&self, prefix: &[u8]) -> bool {
+        (**self).starts_with(prefix)
+    }
 }
 
 impl ReadBuffer for &[u8] {
@@ -33,7 +205,7 @@ impl ReadBuffer for &[u8] {
 
     fn fill_buf(&mut self, size: usize) -> Result<&[u8], Self::Error> {
         if self.len() < size {
-            return Err(DecodeError);
+            return Err(DecodeError::InsufficientBytes);
         }
 
         let (current, left) = self.split_at(size);
@@ -46,6 +218,25 @@ impl ReadBuffer for &[u8] {
     }
 }
 
+impl PeekBuffer for &[u8] {
+    fn starts_with(&self, prefix: &[u8]) -> bool {
+        <[u8]>::starts_with(self, prefix)
+    }
+}
+
+/// Take the next `N` bytes of `read_buffer` as an array.
+pub fn take<const N: usize, R: ReadBuffer>(read_buffer: &mut R) -> Result<[u8; N], R::Error> {
+    let mut array = [0; N];
+    array.copy_from_slice(read_buffer.fill_buf(N)?);
+    Ok(array)
+}
+
+#[cfg(feature = "alloc")]
+/// Take the next `n` bytes of `read_buffer` as an owned vector.
+pub fn take_vec<R: ReadBuffer>(n: usize, read_buffer: &mut R) -> Result<Vec<u8>, R::Error> {
+    read_buffer.fill_buf(n).map(<[u8]>::to_vec)
+}
+
 /// An interface for types that can be decoded from network ordered bytes
 ///
 /// There is a derive macro provided in `codec_derive` that automatically generates
@@ -54,12 +245,47 @@ pub trait Decode: Sized {
     /// Decode the current type from the given `read_buffer`, reading bytes from it in network
     /// order.
     fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error>;
+
+    /// Decode `SIZE` instances of the current type one after another.
+    ///
+    /// Types can override this when their elements can be read in one go, like `u8` does.
+    fn decode_array<R: ReadBuffer, const SIZE: usize>(
+        read_buffer: &mut R,
+    ) -> Result<[Self; SIZE], R::Error> {
+        let mut error = None;
+        let array = [(); SIZE].map(|_| match error {
+            Some(_) => None,
+            None => Self::decode(read_buffer).map_err(|e| error = Some(e)).ok(),
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(array.map(|elem| elem.expect("decoded without error"))),
+        }
+    }
+
+    /// Decode instances of the current type one after another until `read_buffer` is empty.
+    #[cfg(feature = "alloc")]
+    fn decode_all<R: ReadBuffer>(read_buffer: &mut R) -> Result<Vec<Self>, R::Error> {
+        let mut vector = Vec::new();
+
+        while !read_buffer.is_empty() {
+            vector.push(Self::decode(read_buffer)?);
+        }
+
+        Ok(vector)
+    }
 }
 
 impl Decode for u8 {
     fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
         read_buffer.fill_buf(1).map(|buf| buf[0])
     }
+
+    fn decode_array<R: ReadBuffer, const SIZE: usize>(
+        read_buffer: &mut R,
+    ) -> Result<[Self; SIZE], R::Error> {
+        take(read_buffer)
+    }
 }
 
 impl Decode for u16 {
@@ -81,6 +307,34 @@ impl Decode for u32 {
     }
 }
 
+impl Decode for u64 {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        take(read_buffer).map(u64::from_be_bytes)
+    }
+}
+
+/// Decoded from its two's complement byte.
+impl Decode for i8 {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        take(read_buffer).map(i8::from_be_bytes)
+    }
+}
+
+impl<T: PrimitiveInt> Decode for Le<T> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        read_buffer
+            .fill_buf(T::BYTE_SIZE)
+            .map(|buf| Le(T::from_le_slice(buf)))
+    }
+}
+
+/// Decoded exactly like `T`.
+impl<T: Decode> Decode for Wrapping<T> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        T::decode(read_buffer).map(Wrapping)
+    }
+}
+
 impl Decode for () {
     fn decode<R: ReadBuffer>(_: &mut R) -> Result<Self, R::Error> {
         Ok(())
@@ -97,25 +351,65 @@ impl<T: Decode> Decode for Option<T> {
     }
 }
 
+impl<T: Decode> Decode for OptionDiscriminant<T> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        match u8::decode(read_buffer)? {
+            0x00 => Ok(OptionDiscriminant(None)),
+            0x01 => T::decode(read_buffer).map(|value| OptionDiscriminant(Some(value))),
+            discriminant => Err(DecodeError::UnknownVariant(discriminant.into()).into()),
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
 impl<Size: Into<usize> + Decode> Decode for Opaque<Size> {
     fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
         let len = Size::decode(read_buffer)?.into();
-        read_buffer
-            .fill_buf(len)
-            .map(<[u8]>::to_vec)
-            .map(Opaque::from)
+        take_vec(len, read_buffer).map(Opaque::from)
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<T: Decode> Decode for Vec<T> {
     fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
-        let mut vector = Vec::new();
+        T::decode_all(read_buffer)
+    }
+}
+
+#[cfg(feature = "std")]
+/// Eats the whole remaining data, like `Vec<T>`. Duplicate elements are collapsed.
+impl<T: Decode + Eq + Hash> Decode for HashSet<T> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        let mut set = HashSet::new();
 
         while !read_buffer.is_empty() {
-            vector.push(T::decode(read_buffer)?);
+            set.insert(T::decode(read_buffer)?);
         }
 
-        Ok(vector)
+        Ok(set)
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// Eats the whole remaining data, like `Vec<T>`. Duplicate elements are collapsed.
+impl<T: Decode + Ord> Decode for BTreeSet<T> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        let mut set = BTreeSet::new();
+
+        while !read_buffer.is_empty() {
+            set.insert(T::decode(read_buffer)?);
+        }
+
+        Ok(set)
+    }
+}
+
+/// Decoded from its start followed by its end.
+impl<T: Decode> Decode for RangeInclusive<T> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        let start = T::decode(read_buffer)?;
+        let end = T::decode(read_buffer)?;
+        Ok(start..=end)
     }
 }
 
@@ -127,25 +421,79 @@ where
     fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
         let size = Size::decode(read_buffer)?
             .try_into()
-            .map_err(|_| DecodeError)?;
+            .map_err(|_| DecodeError::InvalidSize)?;
 
-        let left = &mut read_buffer.fill_buf(size)?;
+        let version = read_buffer.protocol_version();
+        let left = &mut Versioned {
+            inner: read_buffer.fill_buf(size)?,
+            version,
+        };
 
         let value = T::decode(left)?;
 
         if left.is_empty() {
             Ok(SizeWrapper::new(value))
         } else {
-            Err(DecodeError.into())
+            Err(DecodeError::InvalidSize.into())
         }
     }
 }
 
-impl<const SIZE: usize> Decode for [u8; SIZE] {
+impl<T: Decode, const SIZE: usize> Decode for [T; SIZE] {
     fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
-        let mut array = [0; SIZE];
-        array.copy_from_slice(read_buffer.fill_buf(SIZE)?);
-        Ok(array)
+        T::decode_array(read_buffer)
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// Eats the whole remaining data, like `Vec<u8>`.
+impl Decode for Cow<'_, [u8]> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        Ok(Cow::Owned(read_buffer.fill_all().to_vec()))
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// UTF-8 bytes prepended with their length as `u32`.
+impl Decode for String {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        let len = u32::decode(read_buffer)?
+            .try_into()
+            .map_err(|_| DecodeError::InvalidSize)?;
+        let bytes = take_vec(len, read_buffer)?;
+        String::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8.into())
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Decode for Cow<'_, str> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        String::decode(read_buffer).map(Cow::Owned)
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// Take the bytes prepended with their length as `u32`.
+fn take_blob<R: ReadBuffer>(read_buffer: &mut R) -> Result<&[u8], R::Error> {
+    let len = u32::decode(read_buffer)?
+        .try_into()
+        .map_err(|_| DecodeError::InvalidSize)?;
+    read_buffer.fill_buf(len)
+}
+
+#[cfg(feature = "alloc")]
+/// Bytes prepended with their length as `u32`, copied into a new allocation.
+impl Decode for Arc<[u8]> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        take_blob(read_buffer).map(Arc::from)
+    }
+}
+
+#[cfg(feature = "bytes")]
+/// Decoded like `Arc<[u8]>`.
+impl Decode for bytes::Bytes {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        take_blob(read_buffer).map(bytes::Bytes::copy_from_slice)
     }
 }
 
@@ -157,8 +505,11 @@ impl Decode for Ignore {
     }
 }
 
+/// `Infallible` has no values, so a field of this type can never be decoded.
+///
+/// Trying to decode this will panic.
 impl Decode for Infallible {
     fn decode<R: ReadBuffer>(_: &mut R) -> Result<Self, R::Error> {
-        Err(DecodeError.into())
+        unreachable!("Can not decode `Infallible`");
     }
 }
@@ -1,15 +1,37 @@
 //! Network order encoding of types.
-use std::convert::{Infallible, TryFrom};
-use std::fmt::Debug;
-use std::mem;
-use std::slice;
+#[cfg(feature = "alloc")]
+use alloc::{borrow::Cow, collections::BTreeSet, string::String, sync::Arc, vec::Vec};
+use core::convert::{Infallible, TryFrom};
+use core::fmt::{self, Debug, Display};
+use core::mem;
+use core::num::Wrapping;
+use core::ops::RangeInclusive;
+use core::slice;
+#[cfg(feature = "std")]
+use std::collections::HashSet;
+#[cfg(feature = "std")]
+use std::error::Error;
 
-use crate::{Ignore, Opaque, SizeWrapper};
+#[cfg(feature = "alloc")]
+use crate::Opaque;
+use crate::{Ignore, Le, OptionDiscriminant, Pad, PrimitiveInt, SizeWrapper};
+
+#[cfg(feature = "std")]
+pub mod scratch;
 
 /// The error returned by a slice when it is full and no more data can be encoded into it.
 #[derive(Debug, PartialEq, Eq)]
 pub struct BufferOverflow;
 
+impl Display for BufferOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("write buffer overflow")
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for BufferOverflow {}
+
 /// A write buffer where data can be encoded into.
 pub trait WriteBuffer {
     /// The error returned by this write buffer if it does not have any more space left to fill
@@ -58,6 +80,7 @@ impl WriteBuffer for &mut [u8] {
     }
 }
 
+#[cfg(feature = "alloc")]
 impl WriteBuffer for Vec<u8> {
     type Error = Infallible;
 
@@ -83,13 +106,56 @@ pub trait DataSize: TryFrom<usize> + Encode {
     ///
     /// This needs to be known in advance, so they can be skipped during encoding until the total
     /// size is known.
-    const BYTE_SIZE: usize = std::mem::size_of::<Self>();
+    const BYTE_SIZE: usize = mem::size_of::<Self>();
 }
 
 impl DataSize for u8 {}
 impl DataSize for u16 {}
 impl DataSize for u32 {}
 
+/// Types whose values are always encoded into the same number of bytes.
+///
+/// There is a derive macro provided in `codec_derive` that sums the sizes of the fields of a
+/// struct, so buffers can be sized at compile time: `[0; ServerKey::ENCODED_SIZE]`.
+pub trait EncodedSize {
+    /// The number of bytes written when encoding any value of this type.
+    const ENCODED_SIZE: usize;
+}
+
+macro_rules! impl_encoded_size {
+    ($($ty:ty),*) => {$(
+        impl EncodedSize for $ty {
+            const ENCODED_SIZE: usize = mem::size_of::<$ty>();
+        }
+    )*};
+}
+
+impl_encoded_size!(u8, u16, u32, u64, i8);
+
+impl<T: PrimitiveInt> EncodedSize for Le<T> {
+    const ENCODED_SIZE: usize = T::BYTE_SIZE;
+}
+
+impl<T: EncodedSize> EncodedSize for Wrapping<T> {
+    const ENCODED_SIZE: usize = T::ENCODED_SIZE;
+}
+
+impl EncodedSize for () {
+    const ENCODED_SIZE: usize = 0;
+}
+
+impl<Size: DataSize, T: EncodedSize> EncodedSize for SizeWrapper<Size, T> {
+    const ENCODED_SIZE: usize = Size::BYTE_SIZE + T::ENCODED_SIZE;
+}
+
+impl<T: EncodedSize, const SIZE: usize> EncodedSize for [T; SIZE] {
+    const ENCODED_SIZE: usize = T::ENCODED_SIZE * SIZE;
+}
+
+impl<A: EncodedSize, B: EncodedSize> EncodedSize for (A, B) {
+    const ENCODED_SIZE: usize = A::ENCODED_SIZE + B::ENCODED_SIZE;
+}
+
 /// An interface for types that can be encoded in network order.
 ///
 /// There is a derive macro provided in `codec_derive` that automatically generates `Encode`
@@ -99,6 +165,72 @@ pub trait Encode {
     ///
     /// This can only fail if the write buffer errors out during some operation.
     fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error>;
+
+    /// Encode all elements of `slice` one after another.
+    ///
+    /// Types can override this when their elements can be written in one go, like `u8` does.
+    fn encode_slice<W: WriteBuffer>(slice: &[Self], write_buffer: &mut W) -> Result<usize, W::Error>
+    where
+        Self: Sized,
+    {
+        let mut total = 0;
+        for elem in slice {
+            total += elem.encode(write_buffer)?;
+        }
+        Ok(total)
+    }
+
+    /// Encode `self` into an array on the stack, returning it together with the number of bytes
+    /// used.
+    ///
+    /// `N` must be an upper bound of the encoded size, otherwise this fails with `BufferOverflow`.
+    fn encode_into_array<const N: usize>(&self) -> Result<([u8; N], usize), BufferOverflow> {
+        let mut array = [0; N];
+        let size = self.encode(&mut &mut array[..])?;
+        Ok((array, size))
+    }
+}
+
+#[cfg(feature = "std")]
+/// An object safe version of `WriteBuffer`, used by `DynEncode`.
+pub trait DynWriteBuffer {
+    /// Try to fill this write buffer with the bytes from `buffer`.
+    fn fill_from_dyn(&mut self, buffer: &[u8]) -> Result<(), Box<dyn Error>>;
+}
+
+#[cfg(feature = "std")]
+impl<W: WriteBuffer> DynWriteBuffer for W
+where
+    W::Error: Error + 'static,
+{
+    fn fill_from_dyn(&mut self, buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+        Ok(self.fill_from(buffer)?)
+    }
+}
+
+#[cfg(feature = "std")]
+/// An object safe version of `Encode`, implemented for every `Encode` type.
+///
+/// This allows e.g. encoding a `Vec<Box<dyn DynEncode>>` of different types. Since sizes can not
+/// be filled in later through a trait object, the value is first encoded into the scratch
+/// buffer of the current thread.
+pub trait DynEncode {
+    /// Encode `self` into the `DynWriteBuffer` in network order.
+    fn encode_dyn(&self, write_buffer: &mut dyn DynWriteBuffer) -> Result<usize, Box<dyn Error>>;
+}
+
+#[cfg(feature = "std")]
+impl<T: Encode> DynEncode for T {
+    fn encode_dyn(&self, write_buffer: &mut dyn DynWriteBuffer) -> Result<usize, Box<dyn Error>> {
+        scratch::with_scratch_buffer(|buffer| {
+            let size = match self.encode(buffer) {
+                Ok(size) => size,
+                Err(infallible) => match infallible {},
+            };
+            write_buffer.fill_from_dyn(buffer)?;
+            Ok(size)
+        })
+    }
 }
 
 impl Encode for u8 {
@@ -106,6 +238,14 @@ impl Encode for u8 {
         write_buffer.fill_from(slice::from_ref(self))?;
         Ok(1)
     }
+
+    fn encode_slice<W: WriteBuffer>(
+        slice: &[Self],
+        write_buffer: &mut W,
+    ) -> Result<usize, W::Error> {
+        write_buffer.fill_from(slice)?;
+        Ok(slice.len())
+    }
 }
 
 impl Encode for u16 {
@@ -129,6 +269,35 @@ impl Encode for u64 {
     }
 }
 
+/// Encoded as its two's complement byte.
+impl Encode for i8 {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        write_buffer.fill_from(&self.to_be_bytes())?;
+        Ok(1)
+    }
+}
+
+impl<T: PrimitiveInt> Encode for Le<T> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        write_buffer.fill_from(self.0.to_le_array().as_ref())?;
+        Ok(T::BYTE_SIZE)
+    }
+}
+
+/// Encoded exactly like `T`.
+impl<T: Encode> Encode for Wrapping<T> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        self.0.encode(write_buffer)
+    }
+}
+
+impl<const N: usize> Encode for Pad<N> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        write_buffer.fill_from(&[0; N][..self.len()])?;
+        Ok(self.len())
+    }
+}
+
 impl Encode for () {
     fn encode<W: WriteBuffer>(&self, _: &mut W) -> Result<usize, W::Error> {
         Ok(0)
@@ -143,12 +312,22 @@ impl<T: Encode> Encode for Option<T> {
     }
 }
 
+impl<T: Encode> Encode for OptionDiscriminant<T> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        match &self.0 {
+            Some(value) => Ok(1u8.encode(write_buffer)? + value.encode(write_buffer)?),
+            None => 0u8.encode(write_buffer),
+        }
+    }
+}
+
 impl<'a, T: Encode + ?Sized> Encode for &'a T {
     fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
         (*self).encode(write_buffer)
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<Size: DataSize> Encode for Opaque<Size>
 where
     <Size as TryFrom<usize>>::Error: Debug,
@@ -159,7 +338,19 @@ where
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<T: Encode> Encode for Vec<T> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        T::encode_slice(self, write_buffer)
+    }
+}
+
+#[cfg(feature = "std")]
+/// Encoded as the flat sequence of its elements, like `Vec<T>`.
+///
+/// The order of the elements is unspecified, use a `BTreeSet<T>` when the encoded bytes need to
+/// be deterministic.
+impl<T: Encode> Encode for HashSet<T> {
     fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
         let mut total = 0;
         for elem in self {
@@ -169,6 +360,27 @@ impl<T: Encode> Encode for Vec<T> {
     }
 }
 
+#[cfg(feature = "alloc")]
+/// Encoded as the flat sequence of its elements in ascending order.
+impl<T: Encode> Encode for BTreeSet<T> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        let mut total = 0;
+        for elem in self {
+            total += elem.encode(write_buffer)?;
+        }
+        Ok(total)
+    }
+}
+
+/// Encoded as its start followed by its end.
+impl<T: Encode> Encode for RangeInclusive<T> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        Ok(self.start().encode(write_buffer)? + self.end().encode(write_buffer)?)
+    }
+}
+
+/// In debug builds with `alloc`, the inner value is encoded a second time to check that the size
+/// its `Encode` returned, which is written before it, matches the bytes it wrote.
 impl<Size: DataSize, T: Encode> Encode for SizeWrapper<Size, T>
 where
     <Size as TryFrom<usize>>::Error: Debug,
@@ -180,21 +392,88 @@ where
             Ok(())
         })?;
         Size::try_from(total).unwrap().encode(size_buffer).unwrap();
+        #[cfg(feature = "alloc")]
+        debug_assert_eq!(
+            total,
+            encoded_len(&self.inner),
+            "encoded size of the value in `SizeWrapper` differs from the bytes it wrote"
+        );
         Ok(total + Size::BYTE_SIZE)
     }
 }
 
-impl<const SIZE: usize> Encode for [u8; SIZE] {
+#[cfg(feature = "alloc")]
+/// Number of bytes actually written when encoding `value`.
+fn encoded_len<T: Encode + ?Sized>(value: &T) -> usize {
+    let mut buffer = Vec::new();
+    match value.encode(&mut buffer) {
+        Ok(_) => buffer.len(),
+        Err(infallible) => match infallible {},
+    }
+}
+
+impl<T: Encode, const SIZE: usize> Encode for [T; SIZE] {
     fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
-        write_buffer.fill_from(self)?;
-        Ok(SIZE)
+        T::encode_slice(self, write_buffer)
     }
 }
 
-impl Encode for [u8] {
+/// Encoded as the flat sequence of its elements, like `Vec<T>`.
+///
+/// Elements go through `T::encode_slice`, so a `[u8]` is still written as raw bytes in one go,
+/// while other element types are encoded one by one.
+impl<T: Encode> Encode for [T] {
     fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
-        write_buffer.fill_from(self)?;
-        Ok(self.len())
+        T::encode_slice(self, write_buffer)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Encode for Cow<'_, [u8]> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        self.as_ref().encode(write_buffer)
+    }
+}
+
+/// Encoded as UTF-8 bytes prepended with their length as `u32`.
+impl Encode for str {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        Ok(u32::try_from(self.len()).unwrap().encode(write_buffer)?
+            + self.as_bytes().encode(write_buffer)?)
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// Encoded like `str`.
+impl Encode for String {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        self.as_str().encode(write_buffer)
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// Encoded like `str`.
+impl Encode for Cow<'_, str> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        self.as_ref().encode(write_buffer)
+    }
+}
+
+#[cfg(feature = "alloc")]
+/// Encoded as the bytes prepended with their length as `u32`, like `str`.
+impl Encode for Arc<[u8]> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        Ok(u32::try_from(self.len()).unwrap().encode(write_buffer)?
+            + self.as_ref().encode(write_buffer)?)
+    }
+}
+
+#[cfg(feature = "bytes")]
+/// Encoded like `Arc<[u8]>`.
+impl Encode for bytes::Bytes {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        Ok(u32::try_from(self.len()).unwrap().encode(write_buffer)?
+            + self.as_ref().encode(write_buffer)?)
     }
 }
 
@@ -204,9 +483,10 @@ impl Encode for Ignore {
     }
 }
 
+/// `Infallible` has no values, so there is never anything to encode.
 impl Encode for Infallible {
     fn encode<W: WriteBuffer>(&self, _: &mut W) -> Result<usize, W::Error> {
-        panic!("Can not encode `Infallible`");
+        match *self {}
     }
 }
 
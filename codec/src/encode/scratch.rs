@@ -0,0 +1,66 @@
+//! A per-thread buffer reused for encoding, to avoid allocating a `Vec<u8>` for every message.
+use std::cell::RefCell;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+
+/// The capacity the scratch buffer of every thread starts with.
+pub const SCRATCH_CAPACITY: usize = 4096;
+
+thread_local! {
+    static ENCODE_BUF: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(SCRATCH_CAPACITY));
+}
+
+/// Call `f` with the empty scratch buffer of the current thread.
+///
+/// Calls can be nested, the inner one then works on a buffer of its own.
+pub fn with_scratch_buffer<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Vec<u8>) -> R,
+{
+    f(&mut ScratchBuffer::take())
+}
+
+/// The scratch buffer of the current thread, taken out of it until dropped.
+///
+/// Unlike `with_scratch_buffer` this can be held across an `.await`. When dropped the buffer is
+/// given back to the thread it is dropped on, keeping its allocation for the next user.
+#[derive(Debug)]
+pub struct ScratchBuffer(Vec<u8>);
+
+impl ScratchBuffer {
+    /// Take the scratch buffer of the current thread, leaving an unallocated one in its place.
+    pub fn take() -> Self {
+        let mut buffer = ENCODE_BUF
+            .try_with(|buffer| mem::take(&mut *buffer.borrow_mut()))
+            .unwrap_or_default();
+        buffer.clear();
+        Self(buffer)
+    }
+}
+
+impl Deref for ScratchBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for ScratchBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for ScratchBuffer {
+    fn drop(&mut self) {
+        // Keep whichever buffer holds the bigger allocation, the thread may be shutting down
+        // in which case there is nothing to give the buffer back to.
+        let _ = ENCODE_BUF.try_with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            if buffer.capacity() < self.0.capacity() {
+                *buffer = mem::take(&mut self.0);
+            }
+        });
+    }
+}
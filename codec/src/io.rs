@@ -0,0 +1,120 @@
+//! Splitting the bytes of an async reader into frames.
+//!
+//! A `Decoder` knows where the frames of a protocol start and end, `FramedRead` does the
+//! reading and buffering around it. Together they turn any `tokio::io::AsyncRead` into a
+//! `Stream` of frames.
+use bytes::BytesMut;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_core::Stream;
+use std::io;
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Default number of bytes taken from the reader at once.
+pub const DEFAULT_READ_SIZE: usize = 8 * 1024;
+
+/// Takes frames off the front of a buffer of received bytes.
+pub trait Decoder {
+    /// A decoded frame.
+    type Item;
+    /// The error of a malformed frame, which also has to carry the errors of the reader.
+    type Error: From<io::Error>;
+
+    /// Remove the first frame from `src` and return it, or return `None` without touching `src`
+    /// if the frame is not complete yet.
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error>;
+}
+
+/// A `Stream` of the frames read from `inner` by `decoder`.
+///
+/// The stream ends with the reader. Bytes of an incomplete frame left at that point are
+/// reported as an `UnexpectedEof` error first.
+#[derive(Debug)]
+pub struct FramedRead<T, D> {
+    inner: T,
+    decoder: D,
+    read_buffer: Box<[u8]>,
+    buffer: BytesMut,
+    eof: bool,
+}
+
+impl<T, D> FramedRead<T, D> {
+    pub fn new(inner: T, decoder: D) -> Self {
+        Self::with_read_size(inner, decoder, DEFAULT_READ_SIZE)
+    }
+
+    /// Read up to `read_size` bytes at once, but at least one.
+    pub fn with_read_size(inner: T, decoder: D, read_size: usize) -> Self {
+        Self {
+            inner,
+            decoder,
+            read_buffer: vec![0; read_size.max(1)].into_boxed_slice(),
+            buffer: BytesMut::new(),
+            eof: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn decoder(&self) -> &D {
+        &self.decoder
+    }
+
+    pub fn decoder_mut(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+
+    /// Bytes read but not yet taken by the decoder.
+    pub fn read_buffer(&self) -> &BytesMut {
+        &self.buffer
+    }
+
+    /// Give back the reader, dropping any bytes it was read ahead.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin, D: Decoder + Unpin> Stream for FramedRead<T, D> {
+    type Item = Result<D::Item, D::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.decoder.decode(&mut this.buffer) {
+                Ok(Some(frame)) => return Poll::Ready(Some(Ok(frame))),
+                Ok(None) => (),
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+            if this.eof {
+                return Poll::Ready(None);
+            }
+
+            // A read may return any part of a frame, so the bytes are accumulated until the
+            // decoder finds a whole frame in them
+            let mut buf = ReadBuf::new(&mut this.read_buffer);
+            if let Err(e) = core::task::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf)) {
+                return Poll::Ready(Some(Err(e.into())));
+            }
+            if buf.filled().is_empty() {
+                this.eof = true;
+                if !this.buffer.is_empty() {
+                    this.buffer.clear();
+                    return Poll::Ready(Some(Err(
+                        io::Error::from(io::ErrorKind::UnexpectedEof).into()
+                    )));
+                }
+                return Poll::Ready(None);
+            }
+            this.buffer.extend_from_slice(buf.filled());
+        }
+    }
+}
@@ -1,16 +1,44 @@
 //! Utilities for decoding and encoding data types from and to network order.
-use std::marker::PhantomData;
-use std::ops::{Deref, DerefMut};
+//!
+//! Without the default `std` feature the crate is `no_std`. The `alloc` feature then still
+//! provides `Opaque`, `Vector` and the implementations for heap allocated types like `Vec<T>`
+//! and `String`.
+//!
+//! The `serde` feature implements `Serialize` and `Deserialize` for the wrapper types of the
+//! crate, so they can also appear in JSON or config files.
+//!
+//! The `io` feature adds `io::FramedRead`, which splits a tokio `AsyncRead` into the frames of
+//! any protocol with an `io::Decoder`.
+#![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+pub use codec_derive::CodecDebug;
+pub use codec_derive::CodecDisplay;
 pub use codec_derive::Decode;
 pub use codec_derive::Encode;
+pub use codec_derive::EncodedSize;
 
 pub mod decode;
 pub mod encode;
+#[cfg(feature = "io")]
+pub mod io;
+#[cfg(feature = "serde-bridge")]
+pub mod serde_bridge;
+#[cfg(feature = "serde")]
+mod serde_impls;
 
 pub use decode::Decode;
 pub use encode::Encode;
+pub use encode::EncodedSize;
 
+#[cfg(feature = "alloc")]
 /// A byte array prepended with it's size which is of type `Size`.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Opaque<Size> {
@@ -18,6 +46,7 @@ pub struct Opaque<Size> {
     phantom: PhantomData<Size>,
 }
 
+#[cfg(feature = "alloc")]
 impl<Size> Opaque<Size> {
     /// Create an empty instance of this byte array type.
     pub fn new() -> Self {
@@ -27,18 +56,45 @@ impl<Size> Opaque<Size> {
         }
     }
 
+    /// Create an empty instance of this byte array type with room for `capacity` bytes.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::from(Vec::with_capacity(capacity))
+    }
+
+    /// Create an instance of this byte array type holding a copy of `bytes`.
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        Self::from(bytes.to_vec())
+    }
+
+    /// The bytes of this array, same as through `Deref`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.inner
+    }
+
     /// Extract the byte array as a `Vec<u8>`, ignoring the `Size` type.
     pub fn into_inner(self) -> Vec<u8> {
         self.inner
     }
+
+    /// Append a byte to the end of the array.
+    pub fn push(&mut self, byte: u8) {
+        self.inner.push(byte);
+    }
+
+    /// Append a copy of `bytes` to the end of the array.
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.inner.extend_from_slice(bytes);
+    }
 }
 
+#[cfg(feature = "alloc")]
 impl<Size> Default for Opaque<Size> {
     fn default() -> Self {
         Self::new()
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<Size> From<Vec<u8>> for Opaque<Size> {
     fn from(vec: Vec<u8>) -> Self {
         Self {
@@ -48,6 +104,7 @@ impl<Size> From<Vec<u8>> for Opaque<Size> {
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<Size> Deref for Opaque<Size> {
     type Target = [u8];
 
@@ -56,6 +113,7 @@ impl<Size> Deref for Opaque<Size> {
     }
 }
 
+#[cfg(feature = "alloc")]
 impl<Size> DerefMut for Opaque<Size> {
     fn deref_mut(&mut self) -> &mut [u8] {
         &mut self.inner
@@ -83,6 +141,19 @@ impl<Size, T> SizeWrapper<Size, T> {
     pub fn into_inner(self) -> T {
         self.inner
     }
+
+    /// Transform the inner value, keeping it wrapped for re-encoding.
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> SizeWrapper<Size, U> {
+        SizeWrapper::new(f(self.inner))
+    }
+
+    /// Validate or transform the inner value, keeping it wrapped for re-encoding.
+    pub fn try_map<U, E, F>(self, f: F) -> Result<SizeWrapper<Size, U>, E>
+    where
+        F: FnOnce(T) -> Result<U, E>,
+    {
+        f(self.inner).map(SizeWrapper::new)
+    }
 }
 
 impl<Size, T> Deref for SizeWrapper<Size, T> {
@@ -105,12 +176,114 @@ impl<Size, T: Default> Default for SizeWrapper<Size, T> {
     }
 }
 
+#[cfg(feature = "alloc")]
 /// An array of elements of type `T` that are prepended with their total size in bytes, using
 /// `Size` as the type for the size.
 pub type Vector<Size, T> = SizeWrapper<Size, Vec<T>>;
 
+/// An integer that is encoded and decoded in little-endian instead of network order.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Le<T>(pub T);
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The primitive integer types, which can be wrapped in `Le`.
+///
+/// This trait is sealed and can not be implemented outside of this crate.
+pub trait PrimitiveInt: sealed::Sealed + Copy {
+    /// The number of bytes this type uses on the wire.
+    const BYTE_SIZE: usize;
+
+    /// The byte array holding an encoded integer.
+    type Bytes: AsRef<[u8]>;
+
+    /// Read the integer from exactly `BYTE_SIZE` little-endian bytes.
+    fn from_le_slice(bytes: &[u8]) -> Self;
+
+    /// The little-endian bytes of the integer.
+    fn to_le_array(self) -> Self::Bytes;
+}
+
+macro_rules! impl_primitive_int {
+    ($($ty:ty),*) => {$(
+        impl sealed::Sealed for $ty {}
+
+        impl PrimitiveInt for $ty {
+            const BYTE_SIZE: usize = core::mem::size_of::<$ty>();
+
+            type Bytes = [u8; core::mem::size_of::<$ty>()];
+
+            fn from_le_slice(bytes: &[u8]) -> Self {
+                let mut array = [0; core::mem::size_of::<$ty>()];
+                array.copy_from_slice(bytes);
+                <$ty>::from_le_bytes(array)
+            }
+
+            fn to_le_array(self) -> Self::Bytes {
+                self.to_le_bytes()
+            }
+        }
+    )*};
+}
+
+impl_primitive_int!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+/// An optional value prefixed with a discriminant byte: `0x00` for `None` and `0x01` followed by
+/// the value for `Some`.
+///
+/// Unlike `Option<T>`, an absent value does not have to be at the end of the read buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OptionDiscriminant<T>(pub Option<T>);
+
+impl<T> OptionDiscriminant<T> {
+    /// Extract the inner `Option`.
+    pub fn into_inner(self) -> Option<T> {
+        self.0
+    }
+}
+
+impl<T> From<Option<T>> for OptionDiscriminant<T> {
+    fn from(option: Option<T>) -> Self {
+        Self(option)
+    }
+}
+
+/// Zero bytes aligning the encoding of a `#[codec(pad_to = N)]` struct to a multiple of `N` bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pad<const N: usize> {
+    unpadded: usize,
+}
+
+impl<const N: usize> Pad<N> {
+    /// The padding following `unpadded` bytes.
+    pub fn after(unpadded: usize) -> Self {
+        Self { unpadded }
+    }
+
+    /// Number of zero bytes in the padding.
+    pub fn len(&self) -> usize {
+        (N - self.unpadded % N) % N
+    }
+
+    /// Whether no padding is needed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Skip the padding in `read_buffer`, without checking that it is zeroed.
+    pub fn skip<R: decode::ReadBuffer>(&self, read_buffer: &mut R) -> Result<(), R::Error> {
+        read_buffer.fill_buf(self.len()).map(|_| ())
+    }
+}
+
 /// A type that when decoded will eat the whole remaining data from `ReadBuffer`.
 ///
+/// Nothing is copied, so this works without `alloc`, but only eats what `ReadBuffer::fill_all`
+/// returns.
+///
 /// Trying to encode this will panic.
 #[derive(Clone, Debug)]
 pub struct Ignore;
@@ -0,0 +1,71 @@
+//! Encoding of `serde` types embedded in binary data, enabled with the `serde-bridge` feature.
+use std::convert::TryFrom;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::decode::{DecodeError, ReadBuffer};
+use crate::encode::WriteBuffer;
+use crate::{Decode, Encode};
+
+/// A value encoded as JSON prepended with its length as `u32`.
+///
+/// The value is serialized when the wrapper is created, so values that can not be serialized to
+/// JSON, e.g. maps with non string keys, are rejected by `new` instead of failing to encode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerdeJson<T> {
+    value: T,
+    json: Vec<u8>,
+}
+
+impl<T: Serialize> SerdeJson<T> {
+    pub fn new(value: T) -> Result<Self, serde_json::Error> {
+        let json = serde_json::to_vec(&value)?;
+        Ok(Self { value, json })
+    }
+}
+
+impl<T> SerdeJson<T> {
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Encode for SerdeJson<T> {
+    fn encode<W: WriteBuffer>(&self, write_buffer: &mut W) -> Result<usize, W::Error> {
+        Ok(u32::try_from(self.json.len())
+            .unwrap()
+            .encode(write_buffer)?
+            + self.json.encode(write_buffer)?)
+    }
+}
+
+impl<T: DeserializeOwned> Decode for SerdeJson<T> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        let len = u32::decode(read_buffer)?
+            .try_into()
+            .map_err(|_| DecodeError::InvalidSize)?;
+        let json = read_buffer.fill_buf(len)?;
+        let value = serde_json::from_slice(json).map_err(|_| DecodeError::InvalidJson)?;
+        Ok(Self {
+            value,
+            json: json.to_vec(),
+        })
+    }
+}
+
+/// Function for `#[codec(with = "codec::serde_bridge::json")]`, decoding the field like
+/// `SerdeJson` without wrapping its type.
+///
+/// Serializing a value can fail, which `Encode` can not report, so there is no `encode`. Fields
+/// which are also encoded are wrapped in `SerdeJson` instead.
+pub mod json {
+    use super::*;
+
+    pub fn decode<T: DeserializeOwned, R: ReadBuffer>(read_buffer: &mut R) -> Result<T, R::Error> {
+        SerdeJson::decode(read_buffer).map(SerdeJson::into_inner)
+    }
+}
@@ -0,0 +1,71 @@
+//! `Serialize` and `Deserialize` for the types of the crate, enabled with the `serde` feature.
+//!
+//! The wrappers serialize as the value they wrap, their encoding details (sizes, byte order)
+//! only matter on the wire. `Opaque` serializes as a base64 string.
+use alloc::vec::Vec;
+
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize, Serializer};
+use serde_with::{base64::Base64, DeserializeAs, SerializeAs};
+
+use crate::{Ignore, Le, Opaque, OptionDiscriminant, SizeWrapper};
+
+impl<Size> Serialize for Opaque<Size> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        <Base64 as SerializeAs<Vec<u8>>>::serialize_as(&self.inner, serializer)
+    }
+}
+
+impl<'de, Size> Deserialize<'de> for Opaque<Size> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <Base64 as DeserializeAs<Vec<u8>>>::deserialize_as(deserializer).map(Self::from)
+    }
+}
+
+impl<Size, T: Serialize> Serialize for SizeWrapper<Size, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.inner.serialize(serializer)
+    }
+}
+
+impl<'de, Size, T: Deserialize<'de>> Deserialize<'de> for SizeWrapper<Size, T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl<T: Serialize> Serialize for Le<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Le<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Le)
+    }
+}
+
+impl<T: Serialize> Serialize for OptionDiscriminant<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for OptionDiscriminant<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Option::deserialize(deserializer).map(OptionDiscriminant)
+    }
+}
+
+/// Serializes as `null`; deserializes from any value, which is skipped like when decoding.
+impl Serialize for Ignore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit()
+    }
+}
+
+impl<'de> Deserialize<'de> for Ignore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        IgnoredAny::deserialize(deserializer).map(|_| Ignore)
+    }
+}
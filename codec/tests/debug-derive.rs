@@ -0,0 +1,64 @@
+use codec::{CodecDebug, CodecDisplay};
+
+#[test]
+fn hex_debug_fields() {
+    #[derive(CodecDebug)]
+    struct Named {
+        number: u16,
+        #[codec(hex_debug)]
+        bytes: Vec<u8>,
+        #[codec(hex_debug)]
+        array: [u8; 2],
+    }
+    let value = Named {
+        number: 10,
+        bytes: vec![0xaa, 0x01],
+        array: [0xff, 0x00],
+    };
+    assert_eq!(
+        format!("{value:?}"),
+        "Named { number: 10, bytes: 0xaa01, array: 0xff00 }"
+    );
+
+    #[derive(CodecDebug)]
+    struct Unnamed(u8, #[codec(hex_debug)] Vec<u8>);
+    assert_eq!(format!("{:?}", Unnamed(1, vec![])), "Unnamed(1, 0x)");
+
+    #[derive(CodecDebug)]
+    enum Enum {
+        Unit,
+        Tuple(#[codec(hex_debug)] Vec<u8>),
+        Struct { value: u8 },
+    }
+    assert_eq!(format!("{:?}", Enum::Unit), "Unit");
+    assert_eq!(format!("{:?}", Enum::Tuple(vec![0x12])), "Tuple(0x12)");
+    assert_eq!(
+        format!("{:?}", Enum::Struct { value: 3 }),
+        "Struct { value: 3 }"
+    );
+}
+
+#[test]
+fn display_with_field_expressions() {
+    #[derive(CodecDisplay)]
+    #[codec(display = "Packet({source} -> {target}, {} bytes)", source, target, payload.len())]
+    struct Packet {
+        source: u8,
+        target: u8,
+        #[allow(dead_code)]
+        ttl: u8,
+        payload: Vec<u8>,
+    }
+    let packet = Packet {
+        source: 1,
+        target: 2,
+        ttl: 3,
+        payload: vec![0; 4],
+    };
+    assert_eq!(packet.to_string(), "Packet(1 -> 2, 4 bytes)");
+
+    #[derive(CodecDisplay)]
+    #[codec(display = "Unnamed({:#x})", self.0)]
+    struct Unnamed(u8);
+    assert_eq!(Unnamed(16).to_string(), "Unnamed(0x10)");
+}
@@ -1,7 +1,10 @@
+use std::collections::{BTreeSet, HashSet};
 use std::convert::identity;
+use std::ops::RangeInclusive;
+use std::panic;
 
-use codec::decode::DecodeError;
-use codec::{Decode, Vector};
+use codec::decode::{DecodeError, PeekBuffer, ReadBuffer, Versioned};
+use codec::{Decode, SizeWrapper, Vector};
 
 #[test]
 fn simple_fields() -> Result<(), DecodeError> {
@@ -169,4 +172,395 @@ fn enums_simple() -> Result<(), DecodeError> {
         }
     );
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn enums_deny_unknown() {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    #[codec(deny_unknown)]
+    enum Strict {
+        #[tag(1u8)]
+        One,
+        #[tag(2)]
+        Two,
+    }
+
+    let mut buffer: &[u8] = &[2, 1, 3];
+    assert_eq!(Strict::decode(&mut buffer), Ok(Strict::Two));
+    assert_eq!(Strict::decode(&mut buffer), Ok(Strict::One));
+    assert_eq!(
+        Strict::decode(&mut buffer),
+        Err(DecodeError::UnknownVariant(3))
+    );
+}
+
+#[test]
+fn enums_assert_exhaustive() {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    #[codec(assert_exhaustive)]
+    enum Guarded {
+        #[tag(1u8)]
+        One,
+        #[tag(range = 0x80..=0x8F)]
+        Reserved(#[unknown] u8),
+        #[allow(dead_code)]
+        #[unknown]
+        Unknown(#[unknown] u8),
+    }
+
+    let mut buffer: &[u8] = &[1, 0x81];
+    assert_eq!(Guarded::decode(&mut buffer), Ok(Guarded::One));
+    assert_eq!(Guarded::decode(&mut buffer), Ok(Guarded::Reserved(0x81)));
+    let panic = panic::catch_unwind(|| Guarded::decode(&mut [3u8].as_slice())).unwrap_err();
+    assert_eq!(
+        panic.downcast_ref::<String>().map(String::as_str),
+        Some("Unexpected tag of `Guarded`: 3")
+    );
+
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    #[codec(assert_exhaustive)]
+    enum NoUnknown {
+        #[tag(1u8)]
+        One,
+    }
+
+    assert_eq!(NoUnknown::decode(&mut [1u8].as_slice()), Ok(NoUnknown::One));
+    assert!(panic::catch_unwind(|| NoUnknown::decode(&mut [2u8].as_slice())).is_err());
+}
+
+#[test]
+fn enums_tag_ranges() -> Result<(), DecodeError> {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    enum Ranges {
+        #[unknown]
+        Unknown(#[unknown] u8),
+        #[tag(range = 0x80..=0x8F)]
+        Deprecated {
+            #[unknown]
+            tag: u8,
+        },
+        #[tag(0x81)]
+        Known,
+        #[tag(range = 0x88..)]
+        Shadowed(#[unknown] u8),
+    }
+
+    let mut buffer: &[u8] = &[0x81, 0x80, 0x8F, 0x90, 0x01];
+    assert_eq!(Ranges::decode(&mut buffer)?, Ranges::Known);
+    assert_eq!(
+        Ranges::decode(&mut buffer)?,
+        Ranges::Deprecated { tag: 0x80 }
+    );
+    assert_eq!(
+        Ranges::decode(&mut buffer)?,
+        Ranges::Deprecated { tag: 0x8F }
+    );
+    assert_eq!(Ranges::decode(&mut buffer)?, Ranges::Shadowed(0x90));
+    assert_eq!(Ranges::decode(&mut buffer)?, Ranges::Unknown(0x01));
+
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    #[codec(deny_unknown)]
+    enum StrictRanges {
+        #[tag(range = 1u8..=2)]
+        Low(#[unknown] u8),
+    }
+
+    let mut buffer: &[u8] = &[2, 3];
+    assert_eq!(StrictRanges::decode(&mut buffer), Ok(StrictRanges::Low(2)));
+    assert_eq!(
+        StrictRanges::decode(&mut buffer),
+        Err(DecodeError::UnknownVariant(3))
+    );
+    Ok(())
+}
+
+#[test]
+fn version_gated_fields() -> Result<(), DecodeError> {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    struct Info {
+        flags: u8,
+        #[codec(version_gate = "2")]
+        extra: u16,
+    }
+
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    struct Framed {
+        info: SizeWrapper<u8, Info>,
+    }
+
+    let data: &[u8] = &[1, 0, 2];
+    assert_eq!(Info::decode(&mut &data[..])?, Info { flags: 1, extra: 2 });
+    assert_eq!(
+        Info::decode(&mut Versioned::new(data, 2))?,
+        Info { flags: 1, extra: 2 }
+    );
+    let mut old = Versioned::new(data, 1);
+    assert_eq!(Info::decode(&mut old)?, Info { flags: 1, extra: 0 });
+    assert_eq!(old.into_inner(), [0, 2]);
+
+    let data: &[u8] = &[1, 7];
+    assert_eq!(
+        Framed::decode(&mut Versioned::new(data, 1))?
+            .info
+            .into_inner(),
+        Info { flags: 7, extra: 0 }
+    );
+    Ok(())
+}
+
+#[test]
+fn take_bytes() {
+    let mut buffer: &[u8] = &[1, 2, 3, 4, 5];
+    assert_eq!(codec::decode::take::<2, _>(&mut buffer), Ok([1, 2]));
+    assert_eq!(codec::decode::take_vec(2, &mut buffer), Ok(vec![3, 4]));
+    assert_eq!(
+        codec::decode::take::<2, _>(&mut buffer),
+        Err(DecodeError::InsufficientBytes)
+    );
+    assert_eq!(buffer, [5]);
+}
+
+#[test]
+fn size_wrapper_map() {
+    let mut buffer: &[u8] = &[0, 2, 1, 2];
+    let wrapped = SizeWrapper::<u16, Vec<u8>>::decode(&mut buffer).unwrap();
+
+    let summed = wrapped.clone().map(|bytes| bytes.iter().sum::<u8>());
+    assert_eq!(summed.into_inner(), 3);
+
+    let validated = wrapped
+        .clone()
+        .try_map(|bytes| if bytes.len() == 2 { Ok(bytes) } else { Err(()) });
+    assert_eq!(validated, Ok(wrapped.clone()));
+    assert_eq!(wrapped.try_map(|_| Err::<(), _>("invalid")), Err("invalid"));
+}
+
+#[test]
+fn little_endian_fields() -> Result<(), DecodeError> {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    struct Timestamps {
+        big: u64,
+        #[codec(little_endian)]
+        little: u64,
+    }
+
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    struct Unnamed(#[codec(little_endian)] i16, u16);
+
+    let mut buffer: &[u8] = &[0, 0, 0, 0, 0, 0, 1, 2, 2, 1, 0, 0, 0, 0, 0, 0];
+    assert_eq!(
+        Timestamps::decode(&mut buffer)?,
+        Timestamps {
+            big: 0x0102,
+            little: 0x0102
+        }
+    );
+
+    let mut buffer: &[u8] = &[0xfe, 0xff, 0x01, 0x02];
+    assert_eq!(Unnamed::decode(&mut buffer)?, Unnamed(-2, 0x0102));
+
+    let mut buffer: &[u8] = &[1, 2, 3];
+    assert_eq!(
+        codec::Le::<u32>::decode(&mut buffer),
+        Err(DecodeError::InsufficientBytes)
+    );
+    Ok(())
+}
+
+#[test]
+fn option_discriminant_fields() -> Result<(), DecodeError> {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    struct Optional {
+        #[codec(option_discriminant)]
+        first: Option<u16>,
+        #[codec(option_discriminant)]
+        second: Option<u8>,
+        last: u8,
+    }
+
+    let mut buffer: &[u8] = &[0, 1, 7, 9];
+    assert_eq!(
+        Optional::decode(&mut buffer)?,
+        Optional {
+            first: None,
+            second: Some(7),
+            last: 9
+        }
+    );
+
+    let mut buffer: &[u8] = &[2, 1, 7, 9];
+    assert_eq!(
+        Optional::decode(&mut buffer),
+        Err(DecodeError::UnknownVariant(2))
+    );
+    Ok(())
+}
+
+#[test]
+fn sets() -> Result<(), DecodeError> {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    struct Sets {
+        ordered: SizeWrapper<u8, BTreeSet<u16>>,
+        unordered: HashSet<u16>,
+    }
+
+    let mut buffer: &[u8] = &[4, 0, 2, 0, 1, 0, 3, 0, 4, 0, 3];
+    assert_eq!(
+        Sets::decode(&mut buffer)?,
+        Sets {
+            ordered: SizeWrapper::new(BTreeSet::from([1, 2])),
+            unordered: HashSet::from([3, 4]),
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn decode_all() {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    struct Pair(u8, u8);
+
+    let mut buffer: &[u8] = &[1, 2, 3, 4];
+    assert_eq!(
+        Pair::decode_all(&mut buffer),
+        Ok(vec![Pair(1, 2), Pair(3, 4)])
+    );
+
+    let mut buffer: &[u8] = &[1, 2, 3];
+    assert_eq!(
+        Pair::decode_all(&mut buffer),
+        Err(DecodeError::InsufficientBytes)
+    );
+}
+
+#[test]
+fn transparent() -> Result<(), DecodeError> {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    #[codec(transparent)]
+    struct Key([u8; 2]);
+
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    #[codec(transparent)]
+    struct Named {
+        inner: u16,
+    }
+
+    let mut buffer: &[u8] = &[1, 2, 3, 4];
+    assert_eq!(Key::decode(&mut buffer)?, Key([1, 2]));
+    assert_eq!(Named::decode(&mut buffer)?, Named { inner: 0x0304 });
+    assert_eq!(
+        Key::decode(&mut buffer),
+        Err(DecodeError::InsufficientBytes)
+    );
+    Ok(())
+}
+
+#[test]
+fn arrays() -> Result<(), DecodeError> {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    struct Arrays {
+        words: [u32; 4],
+        halves: [u16; 3],
+    }
+
+    let mut buffer: &[u8] = &[
+        0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 5, 0, 6, 0, 7,
+    ];
+    assert_eq!(
+        Arrays::decode(&mut buffer)?,
+        Arrays {
+            words: [1, 2, 3, 4],
+            halves: [5, 6, 7],
+        }
+    );
+
+    let mut buffer: &[u8] = &[0, 5, 0, 6, 0];
+    assert_eq!(
+        <[u16; 3]>::decode(&mut buffer),
+        Err(DecodeError::InsufficientBytes)
+    );
+    Ok(())
+}
+
+#[test]
+fn ranges() -> Result<(), DecodeError> {
+    let mut buffer: &[u8] = &[0, 0, 0, 10, 0, 1, 0, 0, 0x01, 0xbb, 0x01, 0xbb];
+    assert_eq!(RangeInclusive::<u32>::decode(&mut buffer)?, 10..=65536);
+    assert_eq!(RangeInclusive::<u16>::decode(&mut buffer)?, 443..=443);
+    assert_eq!(
+        RangeInclusive::<u16>::decode(&mut buffer),
+        Err(DecodeError::InsufficientBytes)
+    );
+    Ok(())
+}
+
+#[test]
+fn read_buffer_by_mutable_reference() -> Result<(), DecodeError> {
+    fn first_byte<R: ReadBuffer>(mut read_buffer: R) -> Result<u8, R::Error> {
+        u8::decode(&mut read_buffer)
+    }
+
+    let mut buffer: &[u8] = &[1, 2, 0, 3];
+    assert_eq!(first_byte(&mut buffer)?, 1);
+    assert_eq!(first_byte(&mut &mut buffer)?, 2);
+
+    // Wrappers can borrow the buffer instead of taking it, leaving it advanced
+    assert_eq!(u16::decode(&mut Versioned::new(&mut buffer, 1))?, 0x0003);
+    assert!(buffer.is_empty());
+    Ok(())
+}
+
+#[test]
+fn ignored_trailing_data() -> Result<(), DecodeError> {
+    #[derive(Decode)]
+    struct Header {
+        kind: u8,
+        _rest: codec::Ignore,
+    }
+
+    let mut buffer: &[u8] = &[7, 1, 2, 3];
+    assert_eq!(Header::decode(&mut buffer)?.kind, 7);
+    assert!(buffer.is_empty());
+    Ok(())
+}
+
+#[test]
+fn starts_with_does_not_consume() {
+    fn has_magic<R: PeekBuffer>(read_buffer: &R) -> bool {
+        read_buffer.starts_with(b"DE")
+    }
+
+    let mut buffer: &[u8] = b"DERP";
+    assert!(has_magic(&buffer));
+    assert!(has_magic(&Versioned::new(&mut buffer, 1)));
+    assert!(!has_magic(&&buffer[1..]));
+    assert_eq!(buffer, b"DERP");
+    assert!(!has_magic(&&buffer[..1]));
+}
+
+#[test]
+fn read_buffers_do_not_need_to_peek() -> Result<(), DecodeError> {
+    /// Only implements the required methods, like read buffers written outside this crate.
+    struct Plain<'a>(&'a [u8]);
+
+    impl ReadBuffer for Plain<'_> {
+        type Error = DecodeError;
+
+        fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        fn fill_buf(&mut self, size: usize) -> Result<&[u8], Self::Error> {
+            self.0.fill_buf(size)
+        }
+
+        fn fill_all(&mut self) -> &[u8] {
+            self.0.fill_all()
+        }
+    }
+
+    let mut buffer = Versioned::new(Plain(&[1, 2]), 1);
+    assert_eq!(u16::decode(&mut buffer)?, 0x0102);
+    assert!(buffer.is_empty());
+    Ok(())
+}
@@ -1,7 +1,11 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashSet};
+use std::num::Wrapping;
 use std::panic;
+use std::sync::Arc;
 
-use codec::encode::BufferOverflow;
-use codec::{Encode, Vector};
+use codec::encode::{BufferOverflow, DynEncode};
+use codec::{Decode, Encode, EncodedSize, Opaque, SizeWrapper, Vector};
 
 #[test]
 fn simple_fields() {
@@ -9,7 +13,7 @@ fn simple_fields() {
     struct UnitStruct;
     let mut buffer = Vec::new();
     assert_eq!(UnitStruct.encode(&mut buffer), Ok(0));
-    assert_eq!(buffer, Vec::new());
+    assert_eq!(buffer, Vec::<u8>::new());
 
     #[derive(Encode)]
     struct NamedFieldsStruct {
@@ -111,7 +115,7 @@ fn encode_in_slice() {
     assert_eq!(0x0203u16.encode(&mut view), Ok(2));
     assert_eq!(0x0405_0607u32.encode(&mut view), Ok(4));
     assert_eq!(0x08u8.encode(&mut view), Err(BufferOverflow));
-    assert_eq!((&[][..]).encode(&mut view), Ok(0));
+    assert_eq!((&[] as &[u8]).encode(&mut view), Ok(0));
     assert_eq!(slice, [1, 2, 3, 4, 5, 6, 7]);
 
     let mut slice = [0; 2];
@@ -180,4 +184,505 @@ fn enums_simple() {
         buffer,
         vec![0, 0, 0, 10, 0xaa, 0xbb, 0xcc, 0xdd, 0x01, 0x02]
     );
-}
\ No newline at end of file
+}
+
+#[test]
+fn enums_tag_ranges() {
+    #[derive(Encode)]
+    enum Ranges {
+        #[tag(0x81u8)]
+        Known,
+        #[tag(range = 0x80..=0x8F)]
+        Deprecated {
+            #[unknown]
+            tag: u8,
+            extra: u8,
+        },
+    }
+
+    let mut buffer = Vec::new();
+    assert_eq!(Ranges::Known.encode(&mut buffer), Ok(1));
+    assert_eq!(
+        Ranges::Deprecated {
+            tag: 0x85,
+            extra: 7
+        }
+        .encode(&mut buffer),
+        Ok(2)
+    );
+    assert_eq!(buffer, vec![0x81, 0x85, 7]);
+}
+
+#[test]
+fn cows() {
+    #[derive(Debug, PartialEq, Eq, Decode, Encode)]
+    struct Named<'a> {
+        name: Cow<'a, str>,
+        data: Cow<'a, [u8]>,
+    }
+
+    let value = Named {
+        name: Cow::Borrowed("dersp"),
+        data: Cow::Borrowed(&[1, 2, 3]),
+    };
+    let mut buffer = Vec::new();
+    assert_eq!(value.encode(&mut buffer), Ok(12));
+    assert_eq!(buffer, b"\0\0\0\x05dersp\x01\x02\x03");
+
+    let decoded = Named::decode(&mut buffer.as_slice()).unwrap();
+    assert!(matches!(decoded.name, Cow::Owned(_)));
+    assert!(matches!(decoded.data, Cow::Owned(_)));
+    assert_eq!(decoded, value);
+}
+
+#[test]
+fn slices() {
+    let mut buffer = Vec::new();
+    assert_eq!([1u8, 2, 3][..].encode(&mut buffer), Ok(3));
+    assert_eq!([0x0405u16, 0x0607][..].encode(&mut buffer), Ok(4));
+    assert_eq!(buffer, [1, 2, 3, 4, 5, 6, 7]);
+
+    #[derive(Encode)]
+    struct Borrowed<'a> {
+        ports: &'a [u16],
+    }
+    let mut buffer = Vec::new();
+    let value = Borrowed { ports: &[80, 443] };
+    assert_eq!(value.encode(&mut buffer), Ok(4));
+    assert_eq!(buffer, [0, 80, 1, 187]);
+}
+
+#[test]
+fn strings() {
+    #[derive(Debug, PartialEq, Eq, Decode, Encode)]
+    struct Named {
+        name: String,
+        tag: u8,
+    }
+
+    let value = Named {
+        name: "dersp".to_owned(),
+        tag: 7,
+    };
+    let mut buffer = Vec::new();
+    assert_eq!(value.encode(&mut buffer), Ok(10));
+    assert_eq!(buffer, b"\0\0\0\x05dersp\x07");
+    assert_eq!(Named::decode(&mut buffer.as_slice()).unwrap(), value);
+
+    let mut str_buffer = Vec::new();
+    assert_eq!("dersp".encode(&mut str_buffer), Ok(9));
+    assert_eq!(str_buffer, buffer[..9]);
+}
+
+#[test]
+fn shared_blobs() {
+    #[derive(Debug, PartialEq, Eq, Decode, Encode)]
+    struct Packet {
+        payload: Arc<[u8]>,
+        tag: u8,
+    }
+
+    let value = Packet {
+        payload: Arc::from(&[1, 2, 3][..]),
+        tag: 7,
+    };
+    let mut buffer = Vec::new();
+    assert_eq!(value.encode(&mut buffer), Ok(8));
+    assert_eq!(buffer, [0, 0, 0, 3, 1, 2, 3, 7]);
+    assert_eq!(Packet::decode(&mut buffer.as_slice()).unwrap(), value);
+
+    #[cfg(feature = "bytes")]
+    {
+        let bytes = bytes::Bytes::from_static(&[1, 2, 3]);
+        let mut bytes_buffer = Vec::new();
+        assert_eq!(bytes.encode(&mut bytes_buffer), Ok(7));
+        assert_eq!(bytes_buffer, buffer[..7]);
+        assert_eq!(
+            bytes::Bytes::decode(&mut bytes_buffer.as_slice()).unwrap(),
+            bytes
+        );
+    }
+}
+
+#[test]
+fn little_endian_fields() {
+    #[derive(Encode)]
+    struct Timestamps {
+        big: u64,
+        #[codec(little_endian)]
+        little: u64,
+    }
+    let mut buffer = Vec::new();
+    let value = Timestamps {
+        big: 0x0102,
+        little: 0x0102,
+    };
+    assert_eq!(value.encode(&mut buffer), Ok(16));
+    assert_eq!(buffer, vec![0, 0, 0, 0, 0, 0, 1, 2, 2, 1, 0, 0, 0, 0, 0, 0]);
+
+    #[derive(Encode)]
+    enum Enum {
+        #[tag(1u8)]
+        Tuple(#[codec(little_endian)] i16, u16),
+    }
+    let mut buffer = Vec::new();
+    assert_eq!(Enum::Tuple(-2, 0x0102).encode(&mut buffer), Ok(5));
+    assert_eq!(buffer, vec![1, 0xfe, 0xff, 0x01, 0x02]);
+}
+
+#[test]
+fn option_discriminant_fields() {
+    #[derive(Encode)]
+    struct Optional {
+        #[codec(option_discriminant)]
+        first: Option<u16>,
+        #[codec(option_discriminant)]
+        second: Option<u8>,
+        last: u8,
+    }
+    let mut buffer = Vec::new();
+    let value = Optional {
+        first: None,
+        second: Some(7),
+        last: 9,
+    };
+    assert_eq!(value.encode(&mut buffer), Ok(4));
+    assert_eq!(buffer, vec![0, 1, 7, 9]);
+}
+
+#[test]
+fn sets() {
+    #[derive(Encode)]
+    struct Sets {
+        ordered: SizeWrapper<u8, BTreeSet<u16>>,
+        unordered: HashSet<u16>,
+    }
+    let mut buffer = Vec::new();
+    let value = Sets {
+        ordered: SizeWrapper::new(BTreeSet::from([2, 1])),
+        unordered: HashSet::from([3, 4]),
+    };
+    assert_eq!(value.encode(&mut buffer), Ok(9));
+    assert_eq!(buffer[..5], [4, 0, 1, 0, 2]);
+    let mut unordered = buffer[5..].to_vec();
+    unordered.sort_unstable();
+    assert_eq!(unordered, vec![0, 0, 3, 4]);
+    assert_eq!(
+        HashSet::<u16>::decode(&mut &buffer[5..]),
+        Ok(HashSet::from([3, 4]))
+    );
+}
+
+#[test]
+fn opaque() {
+    #[derive(Encode)]
+    struct WithOpaque {
+        data: Opaque<u8>,
+    }
+    let value = WithOpaque {
+        data: Opaque::from_slice(&[1, 2, 3]),
+    };
+    assert_eq!(value.data.as_bytes(), &[1, 2, 3]);
+    let mut buffer = Vec::new();
+    assert_eq!(value.encode(&mut buffer), Ok(4));
+    assert_eq!(buffer, vec![3, 1, 2, 3]);
+}
+
+#[test]
+fn opaque_built_incrementally() {
+    let mut data = Opaque::<u8>::with_capacity(4);
+    assert!(data.is_empty());
+    data.push(1);
+    data.extend_from_slice(&[2, 3]);
+    assert_eq!(data, Opaque::from_slice(&[1, 2, 3]));
+    let mut buffer = Vec::new();
+    assert_eq!(data.encode(&mut buffer), Ok(4));
+    assert_eq!(buffer, vec![3, 1, 2, 3]);
+}
+
+#[test]
+fn transparent() {
+    #[derive(Encode)]
+    #[codec(transparent)]
+    struct Key([u8; 2]);
+
+    #[derive(Encode)]
+    #[codec(transparent)]
+    struct Named {
+        inner: u16,
+    }
+
+    let mut buffer = Vec::new();
+    assert_eq!(Key([1, 2]).encode(&mut buffer), Ok(2));
+    assert_eq!(Named { inner: 0x0304 }.encode(&mut buffer), Ok(2));
+    assert_eq!(buffer, vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn dyn_encode() {
+    #[derive(Encode)]
+    struct Pair(u8, u16);
+
+    let values: Vec<Box<dyn DynEncode>> = vec![Box::new(1u8), Box::new(Pair(2, 0x0304))];
+    let mut buffer = Vec::new();
+    for value in &values {
+        value.encode_dyn(&mut buffer).unwrap();
+    }
+    assert_eq!(buffer, vec![1, 2, 3, 4]);
+
+    let mut array = [0; 2];
+    let mut slice = &mut array[..];
+    assert_eq!(values[0].encode_dyn(&mut slice).unwrap(), 1);
+    let error = values[1].encode_dyn(&mut slice).unwrap_err();
+    assert_eq!(error.downcast_ref(), Some(&BufferOverflow));
+}
+
+#[test]
+fn arrays() {
+    #[derive(Encode)]
+    struct Arrays {
+        words: [u32; 4],
+        halves: [u16; 3],
+    }
+    let mut buffer = Vec::new();
+    let value = Arrays {
+        words: [1, 2, 3, 4],
+        halves: [5, 6, 7],
+    };
+    assert_eq!(value.encode(&mut buffer), Ok(22));
+    assert_eq!(
+        buffer,
+        vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 5, 0, 6, 0, 7]
+    );
+}
+
+#[test]
+fn scratch_buffer() {
+    use codec::encode::scratch::{with_scratch_buffer, ScratchBuffer, SCRATCH_CAPACITY};
+
+    let mut buffer = ScratchBuffer::take();
+    assert!(buffer.is_empty());
+    assert!(buffer.capacity() >= SCRATCH_CAPACITY);
+    buffer.extend_from_slice(&[1; 2 * SCRATCH_CAPACITY]);
+    drop(buffer);
+
+    // The buffer is handed out empty again, keeping its grown allocation
+    with_scratch_buffer(|buffer| {
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 2 * SCRATCH_CAPACITY);
+        0x1234u16.encode(buffer).unwrap();
+
+        // A nested user gets a buffer of its own
+        with_scratch_buffer(|nested| assert!(nested.is_empty()));
+        assert_eq!(buffer, &[0x12, 0x34]);
+    });
+}
+
+#[test]
+fn ranges() {
+    let mut buffer = Vec::new();
+    assert_eq!((10u32..=65536).encode(&mut buffer), Ok(8));
+    assert_eq!((443u16..=443).encode(&mut buffer), Ok(4));
+    assert_eq!(
+        buffer,
+        vec![0, 0, 0, 10, 0, 1, 0, 0, 0x01, 0xbb, 0x01, 0xbb]
+    );
+}
+
+#[test]
+fn wrapping() {
+    #[derive(Debug, PartialEq, Encode, Decode)]
+    struct Counters {
+        sent: Wrapping<u32>,
+        received: Wrapping<u16>,
+    }
+    let counters = Counters {
+        sent: Wrapping(u32::MAX) + Wrapping(2),
+        received: Wrapping(0x0102),
+    };
+    let mut buffer = Vec::new();
+    assert_eq!(counters.encode(&mut buffer), Ok(6));
+    assert_eq!(buffer, vec![0, 0, 0, 1, 1, 2]);
+    assert_eq!(Counters::decode(&mut &buffer[..]), Ok(counters));
+}
+
+#[test]
+fn padded_structs() {
+    #[derive(Debug, PartialEq, Encode, Decode)]
+    #[codec(pad_to = 4)]
+    struct Padded {
+        kind: u8,
+        len: u16,
+        body: Vector<u8, u8>,
+    }
+
+    let padded = Padded {
+        kind: 1,
+        len: 2,
+        body: SizeWrapper::new(vec![3, 4]),
+    };
+    let mut buffer = Vec::new();
+    assert_eq!(padded.encode(&mut buffer), Ok(8));
+    assert_eq!(buffer, vec![1, 0, 2, 2, 3, 4, 0, 0]);
+    assert_eq!(Padded::decode(&mut &buffer[..]), Ok(padded));
+
+    // Already aligned, so not padded
+    let aligned = Padded {
+        kind: 1,
+        len: 2,
+        body: SizeWrapper::new(vec![]),
+    };
+    buffer.clear();
+    assert_eq!(aligned.encode(&mut buffer), Ok(4));
+    buffer.push(9);
+    let mut read_buffer = &buffer[..];
+    assert_eq!(Padded::decode(&mut read_buffer), Ok(aligned));
+    assert_eq!(read_buffer, &[9]);
+
+    // The padding is sized from the start of the struct, not of the whole buffer
+    let mut buffer: &[u8] = &[0xff, 7, 0, 3, 1, 0xaa, 0, 0, 0, 0xbb];
+    assert_eq!(u8::decode(&mut buffer), Ok(0xff));
+    let decoded = Padded::decode(&mut buffer).unwrap();
+    assert_eq!(decoded.body.into_inner(), vec![0xaa]);
+    assert_eq!(buffer, &[0xbb]);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "encoded size of the value in `SizeWrapper` differs")]
+fn size_wrapper_checks_returned_size() {
+    /// Writes two bytes but claims to have written one
+    struct Miscounted;
+
+    impl Encode for Miscounted {
+        fn encode<W: codec::encode::WriteBuffer>(
+            &self,
+            write_buffer: &mut W,
+        ) -> Result<usize, W::Error> {
+            write_buffer.fill_from(&[1, 2])?;
+            Ok(1)
+        }
+    }
+
+    let _ = SizeWrapper::<u8, _>::new(Miscounted).encode(&mut Vec::new());
+}
+
+#[test]
+fn enums_unknown_between_fields() {
+    #[derive(Debug, PartialEq, Encode, Decode)]
+    enum Middle {
+        #[tag(1u8)]
+        Known,
+        #[unknown]
+        Unknown {
+            before: u16,
+            #[unknown]
+            tag: u8,
+            after: u32,
+        },
+        #[tag(range = 0x80..=0x8F)]
+        Range(u8, #[unknown] u8, u16),
+        #[tag(range = 0x90..=0x9F)]
+        OnlyTag {
+            #[unknown]
+            tag: u8,
+        },
+    }
+
+    let values = [
+        Middle::Unknown {
+            before: 0x0102,
+            tag: 7,
+            after: 0x03040506,
+        },
+        Middle::Range(9, 0x85, 0x0a0b),
+        Middle::OnlyTag { tag: 0x91 },
+    ];
+    let mut buffer = Vec::new();
+    for value in &values {
+        value.encode(&mut buffer).unwrap();
+    }
+    assert_eq!(
+        buffer,
+        vec![7, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x85, 9, 0x0a, 0x0b, 0x91]
+    );
+
+    let mut read_buffer = &buffer[..];
+    for value in values {
+        assert_eq!(Middle::decode(&mut read_buffer), Ok(value));
+    }
+    assert!(read_buffer.is_empty());
+}
+
+#[test]
+fn encode_into_array() {
+    #[derive(Encode)]
+    struct Header {
+        ty: u8,
+        size: u32,
+    }
+    let header = Header {
+        ty: 6,
+        size: 0x0102,
+    };
+    assert_eq!(
+        header.encode_into_array::<8>(),
+        Ok(([6, 0, 0, 1, 2, 0, 0, 0], 5))
+    );
+    assert_eq!(header.encode_into_array::<5>(), Ok(([6, 0, 0, 1, 2], 5)));
+    assert_eq!(header.encode_into_array::<4>(), Err(BufferOverflow));
+}
+
+#[test]
+fn signed_bytes() {
+    let mut buffer = Vec::new();
+    for value in [i8::MIN, -1, 0, i8::MAX] {
+        assert_eq!(value.encode(&mut buffer), Ok(1));
+    }
+    assert_eq!(buffer, vec![0x80, 0xff, 0x00, 0x7f]);
+
+    let mut read_buffer = &buffer[..];
+    for value in [i8::MIN, -1, 0, i8::MAX] {
+        assert_eq!(i8::decode(&mut read_buffer), Ok(value));
+    }
+    assert!(read_buffer.is_empty());
+}
+
+#[test]
+fn encoded_size() {
+    #[derive(Encode, EncodedSize)]
+    struct Key([u8; 4]);
+
+    #[derive(Encode, EncodedSize)]
+    struct Header {
+        kind: u8,
+        #[codec(little_endian)]
+        size: u16,
+        key: Key,
+        framed: SizeWrapper<u16, (u8, u32)>,
+    }
+
+    #[derive(Encode, EncodedSize)]
+    #[codec(pad_to = 4)]
+    struct Padded {
+        kind: u8,
+        size: u16,
+    }
+
+    assert_eq!(Header::ENCODED_SIZE, 14);
+    let header = Header {
+        kind: 1,
+        size: 2,
+        key: Key([3; 4]),
+        framed: SizeWrapper::new((4, 5)),
+    };
+    let mut buffer = [0; Header::ENCODED_SIZE];
+    assert_eq!(
+        header.encode(&mut &mut buffer[..]),
+        Ok(Header::ENCODED_SIZE)
+    );
+
+    assert_eq!(Padded::ENCODED_SIZE, 4);
+    let mut buffer = Vec::new();
+    assert_eq!(Padded { kind: 1, size: 2 }.encode(&mut buffer), Ok(4));
+}
@@ -0,0 +1,49 @@
+#![cfg(feature = "io")]
+
+use bytes::BytesMut;
+use codec::io::{Decoder, FramedRead};
+use futures_util::StreamExt;
+use std::io;
+
+/// Splits the input into lines
+struct Lines;
+
+impl Decoder for Lines {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, io::Error> {
+        let Some(end) = src.iter().position(|b| *b == b'\n') else {
+            return Ok(None);
+        };
+        let line = src.split_to(end + 1);
+        String::from_utf8(line[..end].to_vec())
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[tokio::test]
+async fn frames_span_reads() {
+    let data: &[u8] = b"first\nsecond line\n\nlast";
+    let mut framed = FramedRead::with_read_size(data, Lines, 4);
+
+    assert_eq!(framed.next().await.unwrap().unwrap(), "first");
+    assert_eq!(framed.next().await.unwrap().unwrap(), "second line");
+    assert_eq!(framed.next().await.unwrap().unwrap(), "");
+    assert_eq!(framed.read_buffer().as_ref(), b"l");
+
+    let e = framed.next().await.unwrap().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(framed.next().await.is_none());
+}
+
+#[tokio::test]
+async fn stream_ends_with_reader() {
+    let data: &[u8] = b"only\n";
+    let frames: Vec<_> = FramedRead::new(data, Lines)
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    assert_eq!(frames, ["only"]);
+}
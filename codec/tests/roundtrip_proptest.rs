@@ -0,0 +1,78 @@
+use std::fmt::Debug;
+
+use codec::{Decode, Encode, Opaque, SizeWrapper};
+use proptest::arbitrary::Arbitrary;
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+fn roundtrip<T: Encode + Decode + PartialEq + Debug>(value: T) -> Result<(), TestCaseError> {
+    let mut buffer = Vec::new();
+    let size = value.encode(&mut buffer).unwrap();
+    prop_assert_eq!(size, buffer.len());
+    prop_assert_eq!(T::decode(&mut buffer.as_slice()), Ok(value));
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+struct Derived {
+    a: u8,
+    b: u32,
+    name: Opaque<u16>,
+    items: SizeWrapper<u8, Vec<u16>>,
+    tail: Vec<u8>,
+}
+
+impl Arbitrary for Derived {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (
+            any::<u8>(),
+            any::<u32>(),
+            vec(any::<u8>(), 0..1024),
+            // The encoded items must fit in the `u8` size
+            vec(any::<u16>(), 0..=127),
+            vec(any::<u8>(), 0..1024),
+        )
+            .prop_map(|(a, b, name, items, tail)| Derived {
+                a,
+                b,
+                name: name.into(),
+                items: SizeWrapper::new(items),
+                tail,
+            })
+            .boxed()
+    }
+}
+
+proptest! {
+    #[test]
+    fn integers(a: u8, b: u16, c: u32, d: u64) {
+        roundtrip(a)?;
+        roundtrip(b)?;
+        roundtrip(c)?;
+        roundtrip(d)?;
+    }
+
+    #[test]
+    fn vectors(bytes in vec(any::<u8>(), 0..1024), words in vec(any::<u16>(), 0..1024)) {
+        roundtrip(bytes)?;
+        roundtrip(words)?;
+    }
+
+    #[test]
+    fn size_wrapper(bytes in vec(any::<u8>(), 0..=255)) {
+        roundtrip(SizeWrapper::<u8, _>::new(bytes))?;
+    }
+
+    #[test]
+    fn opaque(bytes in vec(any::<u8>(), 0..4096)) {
+        roundtrip(Opaque::<u16>::from(bytes))?;
+    }
+
+    #[test]
+    fn derived(value: Derived) {
+        roundtrip(value)?;
+    }
+}
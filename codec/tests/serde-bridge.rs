@@ -0,0 +1,65 @@
+#![cfg(feature = "serde-bridge")]
+
+use codec::decode::DecodeError;
+use codec::serde_bridge::SerdeJson;
+use codec::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Payload {
+    version: u32,
+}
+
+#[test]
+fn json_fields() {
+    #[derive(Debug, PartialEq, Eq, Decode)]
+    struct Message {
+        tag: u8,
+        #[codec(with = "codec::serde_bridge::json")]
+        payload: Payload,
+    }
+
+    let mut buffer = vec![7, 0, 0, 0, 13];
+    buffer.extend_from_slice(br#"{"version":2}"#);
+    assert_eq!(
+        Message::decode(&mut buffer.as_slice()),
+        Ok(Message {
+            tag: 7,
+            payload: Payload { version: 2 },
+        })
+    );
+}
+
+#[test]
+fn serde_json_wrapper() {
+    #[derive(Debug, PartialEq, Eq, Decode, Encode)]
+    struct Message {
+        tag: u8,
+        payload: SerdeJson<Payload>,
+    }
+
+    let message = Message {
+        tag: 7,
+        payload: SerdeJson::new(Payload { version: 2 }).unwrap(),
+    };
+    let mut buffer = Vec::new();
+    assert_eq!(message.encode(&mut buffer), Ok(18));
+    assert_eq!(buffer[..5], [7, 0, 0, 0, 13]);
+    assert_eq!(&buffer[5..], br#"{"version":2}"#);
+    let decoded = Message::decode(&mut buffer.as_slice()).unwrap();
+    assert_eq!(decoded.payload.get(), &Payload { version: 2 });
+    assert_eq!(decoded, message);
+
+    let mut buffer: &[u8] = &[0, 0, 0, 2, b'{', b'}'];
+    assert_eq!(
+        SerdeJson::<Payload>::decode(&mut buffer),
+        Err(DecodeError::InvalidJson)
+    );
+}
+
+#[test]
+fn values_without_json_form_are_rejected() {
+    let map = BTreeMap::from([(vec![1_u8], 1_u8)]);
+    assert!(SerdeJson::new(map).is_err());
+}
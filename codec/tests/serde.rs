@@ -0,0 +1,44 @@
+#![cfg(feature = "serde")]
+
+use codec::{Ignore, Le, Opaque, OptionDiscriminant, Vector};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Config {
+    key: Opaque<u8>,
+    ports: Vector<u16, u16>,
+    version: Le<u32>,
+    name: OptionDiscriminant<String>,
+    extra: Ignore,
+}
+
+#[test]
+fn wrappers_are_transparent() {
+    let config = Config {
+        key: Opaque::from_slice(&[1, 2, 3]),
+        ports: Vector::new(vec![80, 443]),
+        version: Le(2),
+        name: OptionDiscriminant(None),
+        extra: Ignore,
+    };
+    let value = serde_json::to_value(&config).unwrap();
+    assert_eq!(
+        value,
+        json!({
+            "key": "AQID",
+            "ports": [80, 443],
+            "version": 2,
+            "name": null,
+            "extra": null,
+        })
+    );
+
+    let mut value = value;
+    value["extra"] = json!({ "anything": [1, 2] });
+    let decoded: Config = serde_json::from_value(value).unwrap();
+    assert_eq!(decoded.key, config.key);
+    assert_eq!(decoded.ports, config.ports);
+    assert_eq!(decoded.version, config.version);
+    assert_eq!(decoded.name, config.name);
+}
@@ -0,0 +1,104 @@
+use crate::crypto::PublicKey;
+use log::warn;
+use serde::Serialize;
+use serde_with::{serde_as, DurationSecondsWithFrac, TimestampSecondsWithFrac};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    spawn,
+    sync::mpsc::{channel, Sender},
+};
+
+/// Events written to the audit log, one JSON object per line
+#[serde_as]
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Connected {
+        pk: PublicKey,
+        addr: SocketAddr,
+        can_mesh: bool,
+        #[serde_as(as = "TimestampSecondsWithFrac<f64>")]
+        timestamp: SystemTime,
+    },
+    Disconnected {
+        pk: PublicKey,
+        #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+        duration: Duration,
+        bytes_sent: u64,
+        bytes_recv: u64,
+    },
+    HandshakeFailed {
+        addr: SocketAddr,
+        reason: String,
+    },
+}
+
+/// Handle to the audit log writer; recording events is a no-op when no log file was configured.
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    sender: Option<Sender<AuditEvent>>,
+}
+
+impl AuditLog {
+    /// Open `path` for appending and spawn the task writing events to it.
+    pub async fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let (sender, mut receiver) = channel::<AuditEvent>(64);
+        spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let mut line = match serde_json::to_vec(&event) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        warn!("Failed to serialize audit event {event:?}: {e}");
+                        continue;
+                    }
+                };
+                line.push(b'\n');
+                if let Err(e) = file.write_all(&line).await {
+                    warn!("Failed to write audit event {event:?}: {e}");
+                }
+            }
+        });
+        Ok(Self {
+            sender: Some(sender),
+        })
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        if let Some(sender) = &self.sender {
+            if let Err(e) = sender.try_send(event) {
+                warn!("Failed to record audit event: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_tagged_json() {
+        let event = AuditEvent::Disconnected {
+            pk: PublicKey::new([0; 32]),
+            duration: Duration::from_millis(1500),
+            bytes_sent: 10,
+            bytes_recv: 20,
+        };
+        let json: serde_json::Value = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "disconnected");
+        assert_eq!(json["duration"], 1.5);
+        assert_eq!(json["bytes_sent"], 10);
+        assert_eq!(json["bytes_recv"], 20);
+    }
+}
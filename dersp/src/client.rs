@@ -1,99 +1,281 @@
 use crate::{
+    audit::{AuditEvent, AuditLog},
+    compression::Compression,
+    connection::Connection,
     crypto::PublicKey,
     inout::DerpReader,
-    proto::data::{ForwardPacket, Frame, FrameType, PeerPresent, RecvPacket, SendPacket},
-    proto::{write_forward_packet, write_peer_present},
+    proto::data::{
+        ClosePeer, ControlMessage, ForwardPacket, FrameType, PeerGone, PeerPresent, RecvPacket,
+        SendPacket, DEFAULT_FORWARD_TTL,
+    },
+    proto::{
+        write_control_message, write_forward_packet, write_peer_gone, write_peer_present,
+        write_recv_packet,
+    },
+    queue::{BoundedMpsc, BoundedMpscReceiver},
+    reorder::ReorderBuffer,
     service::ServiceCommand,
 };
 use anyhow::{anyhow, Result};
-use codec::{Decode, Encode, SizeWrapper};
 use log::{debug, trace, warn};
-use std::net::SocketAddr;
-use tokio::{
-    io::AsyncWriteExt,
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
+use std::{
+    fmt,
+    future::pending,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
     },
-    spawn,
-    sync::mpsc::{channel, Receiver, Sender},
+    time::{Duration, Instant, SystemTime},
 };
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    select, spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{sleep, sleep_until},
+};
+
+/// Number of commands queued for a client before the oldest packets start being dropped
+pub const WRITE_QUEUE_CAPACITY: usize = 32;
+
+/// Packet payload bytes exchanged with a client
+#[derive(Debug, Default)]
+pub struct ClientStats {
+    pub bytes_sent: AtomicU64,
+    pub bytes_recv: AtomicU64,
+    /// Packets for the client dropped because its write queue was full
+    pub dropped_packets: AtomicU64,
+}
+
+/// Identifies an accepted TCP connection in logs, from accept until the client goes away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(pub u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "conn#{}", self.0)
+    }
+}
 
 pub struct Client {
-    _peer: SocketAddr,
-    r: OwnedReadHalf,
-    w: OwnedWriteHalf,
+    id: ConnectionId,
+    peer: SocketAddr,
+    r: Box<dyn AsyncRead + Send + Unpin>,
+    w: Box<dyn AsyncWrite + Send + Unpin>,
     pk: PublicKey,
     can_mesh: bool,
+    compression: Option<Compression>,
+    audit_log: AuditLog,
+    write_watchdog: Duration,
+    reorder_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    stats: Arc<ClientStats>,
 }
 
 impl Client {
-    pub fn new(socket: TcpStream, pk: PublicKey, can_mesh: bool) -> Result<Self> {
-        let _peer = socket.peer_addr()?;
-        let (r, w) = socket.into_split();
-        Ok(Self {
-            _peer,
-            r,
-            w,
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        connection: Connection,
+        id: ConnectionId,
+        pk: PublicKey,
+        can_mesh: bool,
+        compression: Option<Compression>,
+        audit_log: AuditLog,
+        write_watchdog: Duration,
+        reorder_timeout: Duration,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            id,
+            peer: connection.peer,
+            r: connection.reader,
+            w: connection.writer,
             pk,
             can_mesh,
-        })
+            compression,
+            audit_log,
+            write_watchdog,
+            reorder_timeout,
+            idle_timeout,
+            stats: Default::default(),
+        }
+    }
+
+    /// Bytes exchanged with the client, updated while it runs.
+    pub fn stats(&self) -> Arc<ClientStats> {
+        self.stats.clone()
     }
 
     pub async fn run(
         self,
         command_sender: Sender<ServiceCommand>,
-    ) -> Result<Sender<WriteLoopCommands>> {
+    ) -> Result<BoundedMpsc<WriteLoopCommands>> {
+        self.audit_log.record(AuditEvent::Connected {
+            pk: self.pk,
+            addr: self.peer,
+            can_mesh: self.can_mesh,
+            timestamp: SystemTime::now(),
+        });
+
+        let stats = self.stats;
         let w = self.w;
-        let sink = Self::start_write_loop(w, self.pk, self.can_mesh);
+        let (sink, write_loop_done) = Self::start_write_loop(
+            w,
+            self.id,
+            self.pk,
+            self.compression,
+            stats.clone(),
+            self.write_watchdog,
+            self.reorder_timeout,
+        );
         let r = self.r;
-        Self::start_read_loop(r, self.pk, command_sender, self.can_mesh, sink.clone());
+        Self::start_read_loop(
+            r,
+            self.id,
+            self.pk,
+            command_sender,
+            self.can_mesh,
+            self.compression,
+            sink.clone(),
+            stats,
+            self.audit_log,
+            self.idle_timeout,
+            write_loop_done,
+        );
 
         Ok(sink)
     }
 
+    /// Start the read loop, which disconnects the client once it sent nothing for longer than
+    /// `idle_timeout` or once `write_loop_done` reports that the write loop ended.
+    #[allow(clippy::too_many_arguments)]
     pub fn start_read_loop(
-        r: OwnedReadHalf,
+        r: Box<dyn AsyncRead + Send + Unpin>,
+        id: ConnectionId,
         pk: PublicKey,
         command_sender: Sender<ServiceCommand>,
         can_mesh: bool,
-        our_sink: Sender<WriteLoopCommands>,
+        compression: Option<Compression>,
+        our_sink: BoundedMpsc<WriteLoopCommands>,
+        stats: Arc<ClientStats>,
+        audit_log: AuditLog,
+        idle_timeout: Option<Duration>,
+        write_loop_done: oneshot::Receiver<()>,
     ) {
         spawn(async move {
-            if let Err(e) = Self::read_loop(r, pk, command_sender, can_mesh, our_sink).await {
-                warn!("[{pk:?}] Read loop failed: {e}");
-                // TODO: close whole client?
+            let connected_at = Instant::now();
+            let last_seen = LastSeen::new();
+            select! {
+                result = Self::read_loop(
+                    r,
+                    id,
+                    pk,
+                    command_sender.clone(),
+                    can_mesh,
+                    compression,
+                    our_sink.clone(),
+                    &stats,
+                    &last_seen,
+                ) => {
+                    if let Err(e) = result {
+                        warn!("[{id} {pk:?}] Read loop failed: {e}");
+                        // TODO: close whole client?
+                    }
+                }
+                idle_for = watch_idle(&last_seen, idle_timeout) => {
+                    warn!("[{id} {pk:?}] Nothing received for {idle_for:?}, closing the connection");
+                }
+                _ = write_loop_done => {
+                    debug!("[{id} {pk:?}] Write loop ended, closing the connection");
+                }
+            }
+            if let Err(e) = command_sender
+                .send(ServiceCommand::PeerGone(pk, our_sink))
+                .await
+            {
+                warn!("[{id} {pk:?}] Failed to report client as gone: {e}");
             }
+            audit_log.record(AuditEvent::Disconnected {
+                pk,
+                duration: connected_at.elapsed(),
+                bytes_sent: stats.bytes_sent.load(Ordering::Relaxed),
+                bytes_recv: stats.bytes_recv.load(Ordering::Relaxed),
+            });
         });
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn read_loop(
-        r: OwnedReadHalf,
+        r: Box<dyn AsyncRead + Send + Unpin>,
+        id: ConnectionId,
         pk: PublicKey,
         command_sender: Sender<ServiceCommand>,
         can_mesh: bool,
-        our_sink: Sender<WriteLoopCommands>,
+        compression: Option<Compression>,
+        our_sink: BoundedMpsc<WriteLoopCommands>,
+        stats: &ClientStats,
+        last_seen: &LastSeen,
     ) -> anyhow::Result<()> {
-        trace!("[{pk:?}] starting read loop");
+        trace!("[{id} {pk:?}] starting read loop");
         let mut derp_reader = DerpReader::new(r);
 
         loop {
             let message = derp_reader.get_next_message().await?;
-            trace!("[{pk:?}] next frame: {:?}", message.ty);
+            last_seen.touch();
+            trace!(
+                "[{id} {pk:?}] next frame: {:?} ({} bytes)",
+                message.ty,
+                message.size
+            );
 
             match message.ty {
                 FrameType::SendPacket => {
-                    let send_packet = Frame::<SendPacket>::decode(&mut message.buffer.as_slice())
-                        .map_err(|_| anyhow!("Decode error"))?
-                        .inner
-                        .into_inner();
+                    let send_packet = message
+                        .decode_body::<SendPacket>()
+                        .map_err(|_| anyhow!("Decode error"))?;
                     let is_forward = send_packet.target != pk;
-                    debug!("[{pk:?}] send_packet: {send_packet:?}, can mesh: {can_mesh}, is forward: {is_forward}");
+                    debug!("[{id} {pk:?}] send_packet: {send_packet:?}, can mesh: {can_mesh}, is forward: {is_forward}");
+                    stats
+                        .bytes_recv
+                        .fetch_add(send_packet.payload.len() as u64, Ordering::Relaxed);
                     command_sender
                         .send(ServiceCommand::SendPacket {
                             source: pk,
                             target: send_packet.target,
+                            ttl: DEFAULT_FORWARD_TTL,
+                            seq_no: None,
                             payload: send_packet.payload,
+                            queued_at: Instant::now(),
+                        })
+                        .await?;
+                }
+
+                FrameType::ForwardPacket if can_mesh => {
+                    let forward_packet = message
+                        .decode_body::<ForwardPacket>()
+                        .map_err(|_| anyhow!("Decode error"))?;
+                    let Some(ttl) = forward_packet.ttl.checked_sub(1) else {
+                        warn!(
+                            "[{id} {pk:?}] Dropping forward packet from {:?} to {:?}: ttl expired",
+                            forward_packet.source, forward_packet.target
+                        );
+                        continue;
+                    };
+                    let payload = match compression {
+                        Some(compression) => compression.decompress(&forward_packet.payload)?,
+                        None => forward_packet.payload,
+                    };
+                    stats
+                        .bytes_recv
+                        .fetch_add(payload.len() as u64, Ordering::Relaxed);
+                    command_sender
+                        .send(ServiceCommand::SendPacket {
+                            source: forward_packet.source,
+                            target: forward_packet.target,
+                            ttl,
+                            seq_no: forward_packet.seq_no,
+                            payload,
+                            queued_at: Instant::now(),
                         })
                         .await?;
                 }
@@ -112,16 +294,16 @@ impl Client {
                 }
 
                 FrameType::PeerPresent => {
-                    let peer_present = Frame::<PeerPresent>::decode(&mut message.buffer.as_slice())
-                        .map_err(|_| anyhow!("Decode error"))?
-                        .inner
-                        .into_inner();
+                    let peer_present = message
+                        .decode_body::<PeerPresent>()
+                        .map_err(|_| anyhow!("Decode error"))?;
                     debug!(
-                        "[{pk:?}] will handle messages for {:?} (can mesh: {can_mesh})",
+                        "[{id} {pk:?}] will handle messages for {:?} (can mesh: {can_mesh})",
                         peer_present.public_key,
                     );
                     command_sender
-                        .send(ServiceCommand::PeerPresent(
+                        .send(ServiceCommand::MeshPeerPresent(
+                            pk,
                             peer_present.public_key,
                             our_sink.clone(),
                         ))
@@ -129,80 +311,294 @@ impl Client {
                         .unwrap();
                 }
 
-                frame_type => todo!("frame type: {frame_type:?}"),
+                FrameType::PeerGone if can_mesh => {
+                    let peer_gone = message
+                        .decode_body::<PeerGone>()
+                        .map_err(|_| anyhow!("Decode error"))?;
+                    debug!(
+                        "[{id} {pk:?}] no longer handles messages for {:?}",
+                        peer_gone.public_key,
+                    );
+                    command_sender
+                        .send(ServiceCommand::PeerGone(
+                            peer_gone.public_key,
+                            our_sink.clone(),
+                        ))
+                        .await?;
+                }
+
+                FrameType::ClosePeer if can_mesh => {
+                    let close_peer = message
+                        .decode_body::<ClosePeer>()
+                        .map_err(|_| anyhow!("Decode error"))?;
+                    debug!("[{id} {pk:?}] asked to close {:?}", close_peer.public_key);
+                    command_sender
+                        .send(ServiceCommand::ClosePeer(close_peer.public_key))
+                        .await?;
+                }
+
+                // Only there to keep the connection from being idle, which receiving it already did
+                FrameType::KeepAlive => {}
+
+                // Mesh frames from a client that may not mesh, or frames only servers send
+                frame_type => warn!("[{id} {pk:?}] Ignoring unexpected {frame_type:?} frame"),
             }
         }
     }
 
+    /// Start the write loop, which is dropped together with the writer once a single write takes
+    /// longer than `write_watchdog`. Ordered packets after a gap are held for up to
+    /// `reorder_timeout`.
+    ///
+    /// The returned receiver completes once the write loop ended, for whatever reason.
     pub fn start_write_loop(
-        w: OwnedWriteHalf,
+        w: Box<dyn AsyncWrite + Send + Unpin>,
+        id: ConnectionId,
         pk: PublicKey,
-        can_mesh: bool,
-    ) -> Sender<WriteLoopCommands> {
-        let (s, r) = channel(1);
+        compression: Option<Compression>,
+        stats: Arc<ClientStats>,
+        write_watchdog: Duration,
+        reorder_timeout: Duration,
+    ) -> (BoundedMpsc<WriteLoopCommands>, oneshot::Receiver<()>) {
+        let (s, r) = BoundedMpsc::channel(WRITE_QUEUE_CAPACITY);
+        let (done, write_loop_done) = oneshot::channel();
 
-        spawn(Self::write_loop(r, w, pk, can_mesh));
+        spawn(async move {
+            // Dropped when the task ends, which completes `write_loop_done`
+            let _done = done;
+            let progress = WriteProgress::new();
+            let reorder = ReorderBuffer::new(reorder_timeout);
+            select! {
+                result = Self::write_loop(r, w, id, pk, compression, stats, &progress, reorder) => {
+                    if let Err(e) = result {
+                        warn!("[{id} {pk:?}] Write loop failed: {e}");
+                    }
+                }
+                stuck_for = watch_writes(&progress, write_watchdog) => {
+                    warn!("[{id} {pk:?}] Write stuck for {stuck_for:?}, closing the connection");
+                }
+            }
+        });
 
-        s
+        (s, write_loop_done)
     }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn write_loop(
-        mut r: Receiver<WriteLoopCommands>,
-        mut w: OwnedWriteHalf,
+        mut r: BoundedMpscReceiver<WriteLoopCommands>,
+        mut w: Box<dyn AsyncWrite + Send + Unpin>,
+        id: ConnectionId,
         pk: PublicKey,
-        can_mesh: bool,
+        compression: Option<Compression>,
+        stats: Arc<ClientStats>,
+        progress: &WriteProgress,
+        mut reorder: ReorderBuffer<RecvPacket>,
     ) -> anyhow::Result<()> {
         loop {
-            match r.recv().await {
-                Some(WriteLoopCommands::SendPacket {
-                    source,
-                    target,
-                    payload,
-                }) => match (can_mesh, target != pk) {
-                    (true, true) => {
-                        trace!("[{pk:?}] Will forward packet from {source:?} to {target:?}");
-                        let forward_packet = ForwardPacket::new(source, target, payload);
-                        write_forward_packet(&mut w, forward_packet).await?;
-                    }
-
-                    (_, false) => {
-                        let mut writing_buffer = Vec::new();
-                        trace!("[{pk:?}] Will send {} bytes to {target}", payload.len());
-                        let frame = Frame {
-                            frame_type: FrameType::RecvPacket,
-                            inner: SizeWrapper::new(RecvPacket { target, payload }),
-                        };
-                        frame.encode(&mut writing_buffer)?;
-                        w.write_all(&writing_buffer)
-                            .await
-                            .map_err(|e| anyhow!("{e}"))?;
+            progress.finish();
+            let command = match reorder.next_deadline() {
+                Some(deadline) => select! {
+                    command = r.recv() => command,
+                    _ = sleep_until(deadline.into()) => {
+                        progress.start();
+                        for recv_packet in reorder.expire(Instant::now()) {
+                            Self::write_recv_packet(&mut w, id, pk, &stats, recv_packet).await?;
+                        }
+                        continue;
                     }
-
-                    (false, true) => todo!(),
                 },
+                None => r.recv().await,
+            };
+            progress.start();
+            match command {
+                Some(WriteLoopCommands::RecvPacket(recv_packet)) => {
+                    Self::write_recv_packet(&mut w, id, pk, &stats, recv_packet).await?;
+                }
+                Some(WriteLoopCommands::OrderedRecvPacket { seq, packet }) => {
+                    for recv_packet in reorder.push(packet.source, seq, packet, Instant::now()) {
+                        Self::write_recv_packet(&mut w, id, pk, &stats, recv_packet).await?;
+                    }
+                }
+                Some(WriteLoopCommands::ForwardPacket(mut forward_packet)) => {
+                    trace!(
+                        "[{id} {pk:?}] Will forward {forward_packet} (ttl: {})",
+                        forward_packet.ttl
+                    );
+                    let payload_len = forward_packet.payload_len();
+                    if let Some(compression) = compression {
+                        forward_packet.payload = compression.compress(&forward_packet.payload);
+                    }
+                    if forward_packet.payload_len() > ForwardPacket::MAX_PAYLOAD_SIZE {
+                        warn!(
+                            "[{id} {pk:?}] Dropping forward packet of {} bytes: too big",
+                            forward_packet.payload_len()
+                        );
+                        continue;
+                    }
+                    write_forward_packet(&mut w, forward_packet).await?;
+                    stats
+                        .bytes_sent
+                        .fetch_add(payload_len as u64, Ordering::Relaxed);
+                }
                 Some(WriteLoopCommands::_Stop) => {
-                    debug!("[{pk:?}] write loop stopping");
+                    debug!("[{id} {pk:?}] write loop stopping");
                     return Ok(());
                 }
-                Some(WriteLoopCommands::PeerPresent(pk)) => {
-                    trace!("[{pk:?}] Sending peer present with {pk}");
-                    write_peer_present(&mut w, &pk).await?;
+                Some(WriteLoopCommands::PeerPresent(peer)) => {
+                    trace!("[{id} {pk:?}] Sending peer present with {peer}");
+                    write_peer_present(&mut w, &peer).await?;
+                }
+                Some(WriteLoopCommands::PeerGone(peer)) => {
+                    trace!("[{id} {pk:?}] Sending peer gone with {peer}");
+                    write_peer_gone(&mut w, &peer).await?;
+                }
+                Some(WriteLoopCommands::ControlMessage(control_message)) => {
+                    debug!("[{id} {pk:?}] Sending control message: {control_message:?}");
+                    write_control_message(&mut w, &control_message).await?;
+                    if let ControlMessage::Disconnect { .. } = control_message {
+                        debug!("[{id} {pk:?}] write loop stopping (disconnected)");
+                        return Ok(());
+                    }
                 }
                 None => {
-                    debug!("[{pk:?}] write loop stopping (no more commands)");
+                    debug!("[{id} {pk:?}] write loop stopping (no more commands)");
+                    for recv_packet in reorder.drain() {
+                        Self::write_recv_packet(&mut w, id, pk, &stats, recv_packet).await?;
+                    }
                     return Ok(());
                 }
             }
         }
     }
+
+    async fn write_recv_packet(
+        w: &mut Box<dyn AsyncWrite + Send + Unpin>,
+        id: ConnectionId,
+        pk: PublicKey,
+        stats: &ClientStats,
+        recv_packet: RecvPacket,
+    ) -> anyhow::Result<()> {
+        trace!(
+            "[{id} {pk:?}] Will send {} bytes from {}",
+            recv_packet.payload.len(),
+            recv_packet.source
+        );
+        let payload_len = recv_packet.payload.len();
+        write_recv_packet(w, recv_packet).await?;
+        stats
+            .bytes_sent
+            .fetch_add(payload_len as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Start of the write in progress of a write loop, watched by `watch_writes`
+#[derive(Debug)]
+pub struct WriteProgress {
+    epoch: Instant,
+    /// Milliseconds after `epoch` at which the current write started, `IDLE` between writes
+    write_started_at: AtomicU64,
+}
+
+impl WriteProgress {
+    const IDLE: u64 = u64::MAX;
+
+    fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            write_started_at: AtomicU64::new(Self::IDLE),
+        }
+    }
+
+    fn start(&self) {
+        let now = self.epoch.elapsed().as_millis() as u64;
+        self.write_started_at.store(now, Ordering::Relaxed);
+    }
+
+    fn finish(&self) {
+        self.write_started_at.store(Self::IDLE, Ordering::Relaxed);
+    }
+
+    /// How long the current write has been going on, `None` between writes
+    fn in_progress_for(&self) -> Option<Duration> {
+        match self.write_started_at.load(Ordering::Relaxed) {
+            Self::IDLE => None,
+            started_at => Some(
+                self.epoch
+                    .elapsed()
+                    .saturating_sub(Duration::from_millis(started_at)),
+            ),
+        }
+    }
+}
+
+/// Resolve once a single write has been in progress for longer than `limit`, e.g. because the
+/// peer stopped reading and the socket's send buffer is full.
+async fn watch_writes(progress: &WriteProgress, limit: Duration) -> Duration {
+    loop {
+        sleep(limit / 4).await;
+        if let Some(in_progress_for) = progress.in_progress_for() {
+            if in_progress_for > limit {
+                return in_progress_for;
+            }
+        }
+    }
+}
+
+/// When a read loop last received a frame, watched by `watch_idle`
+#[derive(Debug)]
+pub struct LastSeen {
+    epoch: Instant,
+    /// Milliseconds after `epoch` at which the last frame was received
+    last_seen_at: AtomicU64,
+}
+
+impl LastSeen {
+    fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            last_seen_at: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.epoch.elapsed().as_millis() as u64;
+        self.last_seen_at.store(now, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last_seen_at = Duration::from_millis(self.last_seen_at.load(Ordering::Relaxed));
+        self.epoch.elapsed().saturating_sub(last_seen_at)
+    }
+}
+
+/// Resolve once nothing was received for longer than `limit`, never if there is no limit.
+async fn watch_idle(last_seen: &LastSeen, limit: Option<Duration>) -> Duration {
+    let Some(limit) = limit else {
+        return pending().await;
+    };
+    loop {
+        sleep(limit / 4).await;
+        let idle_for = last_seen.idle_for();
+        if idle_for > limit {
+            return idle_for;
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum WriteLoopCommands {
-    SendPacket {
-        source: PublicKey,
-        target: PublicKey,
-        payload: Vec<u8>,
+    /// Deliver a packet to a client connected to this server
+    RecvPacket(RecvPacket),
+    /// Deliver a packet in the order of `seq`, the sequence number of its `(source, target)` pair
+    OrderedRecvPacket {
+        seq: u64,
+        packet: RecvPacket,
     },
+    /// Pass a packet on to the mesh peer the target is connected to
+    ForwardPacket(ForwardPacket),
     PeerPresent(PublicKey),
+    PeerGone(PublicKey),
+    ControlMessage(ControlMessage),
     _Stop,
 }
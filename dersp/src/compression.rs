@@ -0,0 +1,81 @@
+use crate::{inout::MAX_TCP_PACKET_SIZE, proto::data::ForwardPacket};
+use anyhow::{bail, ensure};
+
+const UNCOMPRESSED: u8 = 0x00;
+const LZ4: u8 = 0x01;
+
+/// LZ4 compression of `ForwardPacket` payloads on mesh links where both ends enabled it.
+///
+/// Every payload sent over such a link starts with a flag byte telling whether the rest of it
+/// is compressed. Only payloads longer than `threshold` are compressed, and those which would
+/// not fit into a `ForwardPacket` next to the flag byte otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct Compression {
+    threshold: usize,
+}
+
+impl Compression {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold: threshold.min(ForwardPacket::MAX_PAYLOAD_SIZE - 1),
+        }
+    }
+
+    pub fn compress(&self, payload: &[u8]) -> Vec<u8> {
+        if payload.len() > self.threshold {
+            let mut compressed = vec![LZ4];
+            compressed.extend(lz4_flex::compress_prepend_size(payload));
+            compressed
+        } else {
+            let mut uncompressed = Vec::with_capacity(payload.len() + 1);
+            uncompressed.push(UNCOMPRESSED);
+            uncompressed.extend_from_slice(payload);
+            uncompressed
+        }
+    }
+
+    pub fn decompress(&self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match payload.split_first() {
+            Some((&UNCOMPRESSED, payload)) => Ok(payload.to_vec()),
+            Some((&LZ4, payload)) => {
+                let (size, compressed) = lz4_flex::block::uncompressed_size(payload)?;
+                ensure!(
+                    size <= MAX_TCP_PACKET_SIZE,
+                    "Compressed payload too big: {size}"
+                );
+                Ok(lz4_flex::decompress(compressed, size)?)
+            }
+            Some((flag, _)) => bail!("Unknown payload compression flag: {flag}"),
+            None => bail!("Missing payload compression flag"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_only_above_threshold() {
+        let compression = Compression::new(16);
+        let small = vec![7; 16];
+        let big = vec![7; 1024];
+
+        let sent = compression.compress(&small);
+        assert_eq!(sent[0], UNCOMPRESSED);
+        assert_eq!(compression.decompress(&sent).unwrap(), small);
+
+        let sent = compression.compress(&big);
+        assert_eq!(sent[0], LZ4);
+        assert!(sent.len() < big.len());
+        assert_eq!(compression.decompress(&sent).unwrap(), big);
+
+        // Sent uncompressed, the flag would push it over the limit
+        let sent = Compression::new(usize::MAX).compress(&vec![7; ForwardPacket::MAX_PAYLOAD_SIZE]);
+        assert_eq!(sent[0], LZ4);
+        assert!(sent.len() <= ForwardPacket::MAX_PAYLOAD_SIZE);
+
+        assert!(compression.decompress(&[]).is_err());
+        assert!(compression.decompress(&[0x02, 1]).is_err());
+    }
+}
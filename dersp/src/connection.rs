@@ -0,0 +1,169 @@
+use crate::proto::{finalize_http_phase, Upgrade};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+/// The byte stream of DERP frames exchanged with a client, after its HTTP upgrade
+pub struct Connection {
+    pub peer: SocketAddr,
+    pub reader: Box<dyn AsyncRead + Send + Unpin>,
+    pub writer: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl Connection {
+    /// DERP frames sent directly over the socket.
+    pub fn tcp(socket: TcpStream) -> io::Result<Self> {
+        let peer = socket.peer_addr()?;
+        let (reader, writer) = socket.into_split();
+        Ok(Self {
+            peer,
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+        })
+    }
+
+    /// Upgrade the client's HTTP connection, unwrapping DERP frames from WebSocket messages if
+    /// the client did a WebSocket handshake.
+    pub async fn accept(mut socket: TcpStream) -> crate::proto::Result<Self> {
+        match finalize_http_phase(&mut socket).await? {
+            Upgrade::Derp => Ok(Self::tcp(socket)?),
+            #[cfg(feature = "websocket")]
+            Upgrade::WebSocket => {
+                let peer = socket.peer_addr()?;
+                let (reader, writer) = tokio::io::split(websocket::bridge(socket, peer));
+                Ok(Self {
+                    peer,
+                    reader: Box::new(reader),
+                    writer: Box::new(writer),
+                })
+            }
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "websocket")]
+mod websocket {
+    use crate::inout::MAX_TCP_PACKET_SIZE;
+    use futures_util::{SinkExt, StreamExt};
+    use log::{debug, warn};
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::TcpStream,
+        spawn,
+    };
+    use tokio_tungstenite::{
+        tungstenite::{protocol::Role, Message},
+        WebSocketStream,
+    };
+
+    /// Relay bytes between the returned stream and binary messages of the WebSocket.
+    pub fn bridge(socket: TcpStream, peer: SocketAddr) -> DuplexStream {
+        let (ours, theirs) = duplex(MAX_TCP_PACKET_SIZE);
+        spawn(async move {
+            let ws = WebSocketStream::from_raw_socket(socket, Role::Server, None).await;
+            match relay(ws, theirs).await {
+                Ok(()) => debug!("WebSocket of {peer} closed"),
+                Err(e) => warn!("WebSocket of {peer} failed: {e}"),
+            }
+        });
+        ours
+    }
+
+    async fn relay(mut ws: WebSocketStream<TcpStream>, stream: DuplexStream) -> anyhow::Result<()> {
+        let (mut r, mut w) = tokio::io::split(stream);
+        let mut buf = vec![0; MAX_TCP_PACKET_SIZE];
+        loop {
+            tokio::select! {
+                message = ws.next() => match message.transpose()? {
+                    Some(Message::Binary(data)) => w.write_all(&data).await?,
+                    Some(Message::Close(_)) | None => return Ok(()),
+                    // Pings are answered by tungstenite itself
+                    Some(_) => {}
+                },
+                n = r.read(&mut buf) => {
+                    let n = n?;
+                    if n == 0 {
+                        return Ok(ws.close(None).await?);
+                    }
+                    ws.send(Message::binary(buf[..n].to_vec())).await?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "websocket"))]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_tungstenite::{client_async, tungstenite::Message};
+
+    #[tokio::test]
+    async fn derp_frames_in_websocket_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let (accepted, connected) = tokio::join!(
+            Connection::accept(socket),
+            client_async("ws://127.0.0.1/derp", remote)
+        );
+        let mut connection = accepted.unwrap();
+        let (mut ws, _) = connected.unwrap();
+
+        connection.write_all(&[1, 2, 3]).await.unwrap();
+        let message = ws.next().await.unwrap().unwrap();
+        assert_eq!(message.into_data().to_vec(), vec![1, 2, 3]);
+
+        ws.send(Message::binary(vec![4, 5])).await.unwrap();
+        ws.send(Message::binary(vec![6])).await.unwrap();
+        let mut buf = [0; 3];
+        connection.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [4, 5, 6]);
+
+        ws.close(None).await.unwrap();
+        assert_eq!(connection.read(&mut buf).await.unwrap(), 0);
+    }
+}
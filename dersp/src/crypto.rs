@@ -25,7 +25,7 @@ use std::{convert::TryInto, fmt};
 use rand::prelude::*;
 use serde_with::{DeserializeFromStr, SerializeDisplay};
 
-use codec::{Decode, Encode};
+use codec::{Decode, Encode, EncodedSize};
 
 /// Secret, Public and Wireguard Preshared key size in bytes
 pub const KEY_SIZE: usize = 32;
@@ -41,6 +41,7 @@ pub struct SecretKey([u8; KEY_SIZE]);
     Default,
     Decode,
     Encode,
+    EncodedSize,
     PartialOrd,
     Ord,
     PartialEq,
@@ -51,6 +52,7 @@ pub struct SecretKey([u8; KEY_SIZE]);
     DeserializeFromStr,
     SerializeDisplay,
 )]
+#[codec(transparent)]
 pub struct PublicKey(pub [u8; KEY_SIZE]);
 
 /// Preshared key type
@@ -127,6 +129,15 @@ impl SecretKey {
         crypto_box::SecretKey::from(self.0).public_key().into()
     }
 
+    /// Create new key from a byte slice, which must be exactly `KEY_SIZE` bytes long
+    /// This ensures bytes are properly clamped
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, KeyDecodeError> {
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| KeyDecodeError::InvalidLength(bytes.len()))?;
+        Ok(Self::new(bytes))
+    }
+
     /// Return key represented as bytes
     pub fn as_bytes(&self) -> &[u8; 32] {
         &self.0
@@ -136,6 +147,11 @@ impl SecretKey {
     pub fn into_bytes(self) -> [u8; 32] {
         self.0
     }
+
+    /// Key as lowercase hex, same as formatting with `{:x}`
+    pub fn to_hex(self) -> String {
+        format!("{self:x}")
+    }
 }
 
 impl PublicKey {
@@ -143,6 +159,11 @@ impl PublicKey {
     pub const fn new(bytes: [u8; 32]) -> Self {
         Self(bytes)
     }
+
+    /// Key as lowercase hex, same as formatting with `{:x}`
+    pub fn to_hex(self) -> String {
+        format!("{self:x}")
+    }
 }
 
 impl PresharedKey {
@@ -359,4 +380,14 @@ mod tests {
         assert_eq!(SK, SK_HEX.parse().unwrap());
         assert_eq!(PK, PK_HEX.parse().unwrap());
     }
+
+    #[test]
+    fn secret_key_from_bytes() {
+        assert_eq!(SK, SecretKey::from_bytes(&[0xBA; 32]).unwrap());
+        assert_eq!(PK_HEX, SK.public().to_hex());
+        assert!(matches!(
+            SecretKey::from_bytes(&[0xBA; 31]),
+            Err(KeyDecodeError::InvalidLength(31))
+        ));
+    }
 }
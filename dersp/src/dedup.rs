@@ -0,0 +1,106 @@
+use crate::crypto::PublicKey;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How long a forwarded packet is remembered
+pub const DEDUP_WINDOW: Duration = Duration::from_secs(5);
+/// Max number of remembered packets
+pub const DEDUP_CAPACITY: usize = 64 * 1024;
+
+/// Remembers recently forwarded packets by `(source, seq_no)`, so that a packet reaching this
+/// server over more than one mesh path is delivered only once.
+#[derive(Debug)]
+pub struct DeduplicationCache {
+    seen: HashMap<(PublicKey, u32), Instant>,
+    /// Entries of `seen` oldest first, so the oldest can be evicted without a scan. An entry
+    /// inserted again leaves its outdated copy behind, which is skipped when evicted.
+    order: VecDeque<(PublicKey, u32, Instant)>,
+    window: Duration,
+    capacity: usize,
+}
+
+impl DeduplicationCache {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            window,
+            capacity,
+        }
+    }
+
+    /// Record the packet, returning `false` if it was already seen within the window.
+    pub fn insert(&mut self, source: PublicKey, seq_no: u32, now: Instant) -> bool {
+        if let Some(seen_at) = self.seen.get(&(source, seq_no)) {
+            if now.saturating_duration_since(*seen_at) < self.window {
+                return false;
+            }
+        }
+        if self.seen.len() >= self.capacity {
+            self.evict_expired(now);
+        }
+        while self.seen.len() >= self.capacity {
+            let Some((source, seq_no, seen_at)) = self.order.pop_front() else {
+                break;
+            };
+            self.forget(source, seq_no, seen_at);
+        }
+        self.seen.insert((source, seq_no), now);
+        self.order.push_back((source, seq_no, now));
+        true
+    }
+
+    /// Forget packets seen longer than the window ago.
+    pub fn evict_expired(&mut self, now: Instant) {
+        while let Some(&(source, seq_no, seen_at)) = self.order.front() {
+            if now.saturating_duration_since(seen_at) < self.window {
+                break;
+            }
+            self.order.pop_front();
+            self.forget(source, seq_no, seen_at);
+        }
+    }
+
+    /// Remove the packet unless it was seen again after `seen_at`.
+    fn forget(&mut self, source: PublicKey, seq_no: u32, seen_at: Instant) {
+        if self.seen.get(&(source, seq_no)) == Some(&seen_at) {
+            self.seen.remove(&(source, seq_no));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_packets_seen_within_window() {
+        let mut cache = DeduplicationCache::new(DEDUP_WINDOW, 2);
+        let (a, b) = (PublicKey::new([1; 32]), PublicKey::new([2; 32]));
+        let now = Instant::now();
+
+        assert!(cache.insert(a, 1, now));
+        assert!(!cache.insert(a, 1, now + Duration::from_secs(1)));
+        assert!(cache.insert(b, 1, now));
+        assert!(cache.insert(a, 1, now + DEDUP_WINDOW));
+
+        // Full cache first evicts expired packets, then the oldest one
+        let later = now + DEDUP_WINDOW;
+        assert!(cache.insert(a, 2, later + Duration::from_secs(1)));
+        assert!(cache.insert(b, 2, later + Duration::from_secs(2)));
+        assert!(cache.insert(a, 1, later + Duration::from_secs(3)));
+        assert!(!cache.insert(b, 2, later + Duration::from_secs(3)));
+
+        cache.evict_expired(now + DEDUP_WINDOW * 3);
+        assert!(cache.seen.is_empty());
+        assert!(cache.order.is_empty());
+
+        // Evicting the outdated copy of a packet seen again keeps the packet
+        assert!(cache.insert(a, 1, now));
+        assert!(cache.insert(a, 1, now + DEDUP_WINDOW));
+        cache.evict_expired(now + DEDUP_WINDOW);
+        assert!(!cache.insert(a, 1, now + DEDUP_WINDOW + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::{Duration, Instant};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Lower bound on how often SRV records are re-queried, so that a zero TTL does not turn the
+/// refresh into a busy loop.
+pub const MIN_SRV_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Mesh peers announced by a DNS SRV record.
+#[derive(Debug)]
+pub struct SrvPeers {
+    /// `host:port` addresses ordered by priority, then by descending weight
+    pub addrs: Vec<String>,
+    /// Time after which the record should be queried again
+    pub valid_until: Instant,
+}
+
+impl SrvPeers {
+    /// How long to wait before the next lookup of the record.
+    pub fn refresh_in(&self) -> Duration {
+        self.valid_until
+            .saturating_duration_since(Instant::now())
+            .max(MIN_SRV_REFRESH_INTERVAL)
+    }
+}
+
+pub async fn lookup_srv_peers(
+    resolver: &TokioAsyncResolver,
+    record: &str,
+) -> anyhow::Result<SrvPeers> {
+    let lookup = resolver.srv_lookup(record).await?;
+
+    let mut records: Vec<_> = lookup.iter().collect();
+    records.sort_by_key(|srv| (srv.priority(), std::cmp::Reverse(srv.weight())));
+    let addrs: Vec<String> = records
+        .into_iter()
+        .map(|srv| {
+            let target = srv.target().to_utf8();
+            format!("{}:{}", target.trim_end_matches('.'), srv.port())
+        })
+        .collect();
+
+    Ok(SrvPeers {
+        addrs,
+        valid_until: lookup.as_lookup().valid_until(),
+    })
+}
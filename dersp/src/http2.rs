@@ -0,0 +1,165 @@
+//! DERP over HTTP/2, without the HTTP upgrade.
+//!
+//! Every request stream on an accepted connection is a client session of its own: the request
+//! body carries the DERP frames of the client and the response body the frames sent to it. This
+//! lets clients reach the server through HTTP/2 proxies which support neither upgrades nor
+//! WebSockets.
+use crate::{
+    connection::Connection,
+    inout::MAX_TCP_PACKET_SIZE,
+    proto,
+    service::{handle_client, DerpService},
+};
+use bytes::Bytes;
+use futures_util::future::poll_fn;
+use h2::{server::SendResponse, RecvStream, SendStream};
+use http::{Request, Response};
+use log::{debug, warn};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::RwLock,
+};
+
+/// Accept HTTP/2 connections until the listener fails.
+pub async fn run(listener: TcpListener, service: Arc<RwLock<DerpService>>) {
+    while let Ok((socket, peer_addr)) = listener.accept().await {
+        let service = service.clone();
+        spawn(async move {
+            if let Err(e) = serve(socket, peer_addr, service).await {
+                warn!("HTTP/2 connection of {peer_addr:?} failed: {e}");
+            }
+        });
+    }
+}
+
+/// Run a DERP client session for every request stream of the connection.
+async fn serve(
+    socket: TcpStream,
+    peer_addr: SocketAddr,
+    service: Arc<RwLock<DerpService>>,
+) -> Result<(), h2::Error> {
+    let mut connection = h2::server::handshake(socket).await?;
+    // Accepting also drives the connection, the streams make progress only while it is polled
+    while let Some(stream) = connection.accept().await {
+        let (request, respond) = stream?;
+        let id = service.read().await.next_connection_id();
+        let service = service.clone();
+        spawn(async move {
+            let connection = accept(request, respond, peer_addr);
+            if let Err(e) = handle_client(connection, id, peer_addr, service).await {
+                warn!("[{id}] HTTP/2 client {peer_addr:?} failed: {e:?}");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn accept(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    peer: SocketAddr,
+) -> proto::Result<Connection> {
+    let send = respond.send_response(Response::new(()), false)?;
+    let (ours, theirs) = duplex(MAX_TCP_PACKET_SIZE);
+    spawn(async move {
+        match relay(request.into_body(), send, theirs).await {
+            Ok(()) => debug!("HTTP/2 stream of {peer} closed"),
+            Err(e) => warn!("HTTP/2 stream of {peer} failed: {e}"),
+        }
+    });
+    let (reader, writer) = tokio::io::split(ours);
+    Ok(Connection {
+        peer,
+        reader: Box::new(reader),
+        writer: Box::new(writer),
+    })
+}
+
+/// Relay bytes between the stream and the DATA frames of the request and response bodies.
+async fn relay(
+    mut recv: RecvStream,
+    mut send: SendStream<Bytes>,
+    stream: DuplexStream,
+) -> anyhow::Result<()> {
+    let (mut r, mut w) = tokio::io::split(stream);
+    let mut buf = vec![0; MAX_TCP_PACKET_SIZE];
+    loop {
+        tokio::select! {
+            data = recv.data() => match data.transpose()? {
+                Some(data) => {
+                    w.write_all(&data).await?;
+                    // Let the client send more only once the bytes were taken off our hands
+                    recv.flow_control().release_capacity(data.len())?;
+                }
+                None => return Ok(()),
+            },
+            n = r.read(&mut buf) => {
+                let n = n?;
+                if n == 0 {
+                    return Ok(send.send_data(Bytes::new(), true)?);
+                }
+                send_all(&mut send, Bytes::copy_from_slice(&buf[..n])).await?;
+            }
+        }
+    }
+}
+
+/// Send `data` as soon as the client's flow control window allows it.
+async fn send_all(send: &mut SendStream<Bytes>, mut data: Bytes) -> anyhow::Result<()> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let Some(capacity) = poll_fn(|cx| send.poll_capacity(cx)).await else {
+            anyhow::bail!("HTTP/2 stream reset by the client");
+        };
+        let chunk = data.split_to(capacity?.min(data.len()));
+        send.send_data(chunk, false)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn frames_in_request_and_response_bodies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connection_sender, mut connection_receiver) = channel(1);
+        spawn(async move {
+            let (socket, peer) = listener.accept().await.unwrap();
+            let mut connection = h2::server::handshake(socket).await.unwrap();
+            while let Some(Ok((request, respond))) = connection.accept().await {
+                let accepted = accept(request, respond, peer).await.unwrap();
+                connection_sender.send(accepted).await.unwrap();
+            }
+        });
+
+        let (client, h2_connection) =
+            h2::client::handshake(TcpStream::connect(addr).await.unwrap())
+                .await
+                .unwrap();
+        spawn(h2_connection);
+        let mut client = client.ready().await.unwrap();
+        let request = Request::post("http://dersp/derp").body(()).unwrap();
+        let (response, mut send) = client.send_request(request, false).unwrap();
+        send.send_data(Bytes::from_static(&[1, 2, 3]), false)
+            .unwrap();
+
+        let mut connection = connection_receiver.recv().await.unwrap();
+        let mut buf = [0; 3];
+        connection.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        connection.write_all(&[4, 5, 6]).await.unwrap();
+        let mut body = response.await.unwrap().into_body();
+        assert_eq!(&body.data().await.unwrap().unwrap()[..], [4, 5, 6]);
+
+        send.send_data(Bytes::new(), true).unwrap();
+        assert_eq!(connection.read(&mut buf).await.unwrap(), 0);
+    }
+}
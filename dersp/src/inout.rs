@@ -1,82 +1,343 @@
-use crate::proto::data::{FrameType, Header};
-use anyhow::anyhow;
-use codec::Decode;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::proto::{
+    data::{ExpectedFrameType, FrameType, Header},
+    Error, Result,
+};
+use bytes::BytesMut;
+use codec::{
+    decode::DecodeError,
+    io::{Decoder, FramedRead},
+    Decode, EncodedSize,
+};
+use futures_util::{Stream, StreamExt};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::AsyncRead;
 
-const HEADER_SIZE: usize = 5;
+pub const HEADER_SIZE: usize = Header::ENCODED_SIZE;
 /// Max TCP packet size is 65535
-const MAX_TCP_PACKET_SIZE: usize = u16::MAX as usize;
+pub const MAX_TCP_PACKET_SIZE: usize = u16::MAX as usize;
+/// Default limit of buffered bytes waiting for a frame to complete
+pub const DEFAULT_INPUT_BUFFER_LIMIT: usize = 4 * MAX_TCP_PACKET_SIZE;
 
-pub struct Message {
+/// A frame split into its decoded header and the payload bytes following it
+#[derive(Debug)]
+pub struct OwnedFrame {
     pub ty: FrameType,
-    pub buffer: Vec<u8>,
+    pub size: u32,
+    pub body: BytesMut,
 }
 
-enum PartMessage {
-    InsufficientData,
-    Message(Message),
+impl OwnedFrame {
+    /// Decode the whole body as the payload `T` of this frame's type.
+    pub fn decode_body<T: ExpectedFrameType + Decode>(&self) -> Result<T> {
+        if self.ty != T::FRAME_TYPE {
+            return Err(Error::UnexpectedFrameType {
+                expected: T::FRAME_TYPE,
+                got: self.ty,
+            });
+        }
+        let mut body = &self.body[..];
+        let payload = T::decode(&mut body)?;
+        if !body.is_empty() {
+            return Err(DecodeError::InvalidSize.into());
+        }
+        Ok(payload)
+    }
 }
 
-#[derive(Default)]
-pub struct InputBuffer {
-    data: Vec<u8>,
+/// Splits DERP frames off the bytes received from a client or mesh peer.
+///
+/// Frames are limited to `MAX_TCP_PACKET_SIZE` bytes after the header, like the handshake
+/// frames, and at most `max_bytes` may be buffered while waiting for a frame to complete.
+pub struct DerpFrameDecoder {
+    max_bytes: usize,
 }
 
-impl InputBuffer {
-    pub fn input_data(&mut self, data: &[u8]) {
-        self.data.extend(data);
+impl Default for DerpFrameDecoder {
+    fn default() -> Self {
+        Self::with_memory_limit(DEFAULT_INPUT_BUFFER_LIMIT)
     }
+}
 
-    fn next_message(&mut self) -> anyhow::Result<PartMessage> {
-        if self.data.len() < HEADER_SIZE {
-            return Ok(PartMessage::InsufficientData);
+impl DerpFrameDecoder {
+    /// Create a decoder failing once more than `max_bytes` of incomplete frames are buffered.
+    pub fn with_memory_limit(max_bytes: usize) -> Self {
+        Self { max_bytes }
+    }
+}
+
+impl Decoder for DerpFrameDecoder {
+    type Item = OwnedFrame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<OwnedFrame>> {
+        if src.len() < HEADER_SIZE {
+            return Ok(None);
         }
 
-        let mut header = [0; HEADER_SIZE];
-        header.copy_from_slice(&self.data[..HEADER_SIZE]);
-        let header = Header::decode(&mut header.as_slice()).map_err(|_| anyhow!("Decode error"))?;
-
-        let message_size = HEADER_SIZE + (header.size as usize);
-        if self.data.len() >= message_size {
-            // We can extract a message
-            let buffer = self.data.drain(..message_size).collect();
-            return Ok(PartMessage::Message(Message {
-                ty: header.frame_type,
-                buffer,
-            }));
-        } else {
-            // Insufficient data
-            return Ok(PartMessage::InsufficientData);
+        let header = Header::decode(&mut &src[..HEADER_SIZE])?;
+        let size = header.size as usize;
+        if size > MAX_TCP_PACKET_SIZE {
+            return Err(Error::FrameTooBig(size));
         }
+        let message_size = HEADER_SIZE + size;
+        if src.len() < message_size {
+            if src.len() > self.max_bytes {
+                return Err(Error::InputBufferFull(self.max_bytes));
+            }
+            return Ok(None);
+        }
+
+        let mut message = src.split_to(message_size);
+        Ok(Some(OwnedFrame {
+            ty: header.frame_type,
+            size: header.size,
+            body: message.split_off(HEADER_SIZE),
+        }))
     }
 }
 
+/// Splits the bytes of a reader into frames.
+///
+/// Every read fills as much of the read buffer as is available, so frames arriving together
+/// are taken with a single read.
 pub struct DerpReader<T: AsyncRead + Unpin> {
-    reader: T,
-    read_buffer: [u8; MAX_TCP_PACKET_SIZE],
-    input_buffer: InputBuffer,
+    framed: FramedRead<T, DerpFrameDecoder>,
 }
 
 impl<T: AsyncRead + Unpin> DerpReader<T> {
     pub fn new(reader: T) -> Self {
+        Self::with_buf_size(reader, MAX_TCP_PACKET_SIZE)
+    }
+
+    /// Read up to `cap` bytes at once, which is capped at `DEFAULT_INPUT_BUFFER_LIMIT`.
+    pub fn with_buf_size(reader: T, cap: usize) -> Self {
         DerpReader {
+            framed: FramedRead::with_read_size(
+                reader,
+                DerpFrameDecoder::default(),
+                cap.min(DEFAULT_INPUT_BUFFER_LIMIT),
+            ),
+        }
+    }
+
+    /// Read the next message, failing with `UnexpectedEof` once the reader is closed.
+    pub async fn get_next_message(&mut self) -> Result<OwnedFrame> {
+        match self.framed.next().await {
+            Some(message) => message,
+            None => Err(Error::Io(io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+}
+
+/// Blocking counterpart of `DerpReader`, for framing data from a `std::io::Read`.
+#[cfg(test)]
+pub struct SyncDerpReader<T: io::Read> {
+    reader: T,
+    read_buffer: [u8; MAX_TCP_PACKET_SIZE],
+    buffer: BytesMut,
+    decoder: DerpFrameDecoder,
+}
+
+#[cfg(test)]
+impl<T: io::Read> SyncDerpReader<T> {
+    pub fn new(reader: T) -> Self {
+        SyncDerpReader {
             reader,
             read_buffer: [0; MAX_TCP_PACKET_SIZE],
-            input_buffer: InputBuffer::default(),
+            buffer: BytesMut::new(),
+            decoder: DerpFrameDecoder::default(),
         }
     }
 
-    pub async fn get_next_message(&mut self) -> anyhow::Result<Message> {
+    /// Read the next message, failing with `UnexpectedEof` if the reader ends before it is
+    /// complete.
+    pub fn get_next_message(&mut self) -> Result<OwnedFrame> {
         loop {
-            let message = self.input_buffer.next_message()?;
-            match message {
-                PartMessage::InsufficientData => {
-                    let size = self.reader.read(&mut self.read_buffer).await?;
-                    self.input_buffer.input_data(&self.read_buffer[..size]);
-                }
-
-                PartMessage::Message(message) => return Ok(message),
+            if let Some(message) = self.decoder.decode(&mut self.buffer)? {
+                return Ok(message);
+            }
+            let size = self.reader.read(&mut self.read_buffer)?;
+            if size == 0 {
+                return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
             }
+            self.buffer.extend_from_slice(&self.read_buffer[..size]);
         }
     }
 }
+
+impl<T: AsyncRead + Unpin> Stream for DerpReader<T> {
+    type Item = Result<OwnedFrame>;
+
+    /// Yields consecutive messages, ending the stream once the underlying reader reaches EOF.
+    /// An incomplete frame left at that point is reported as an `UnexpectedEof` error first.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.framed).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::data::{PeerGone, PeerPresent};
+    use tokio::io::ReadBuf;
+
+    #[test]
+    fn sync_reader_splits_frames() {
+        let data: &[u8] = &[6, 0, 0, 0, 0, 7, 0, 0, 0, 1, 1, 6, 0, 0];
+        let mut reader = SyncDerpReader::new(data);
+
+        let message = reader.get_next_message().unwrap();
+        assert_eq!(message.ty, FrameType::KeepAlive);
+        assert_eq!(message.size, 0);
+        assert!(message.body.is_empty());
+
+        let message = reader.get_next_message().unwrap();
+        assert_eq!(message.ty, FrameType::NotePreferred);
+        assert_eq!(message.size, 1);
+        assert_eq!(message.body, vec![1]);
+
+        assert!(matches!(
+            reader.get_next_message(),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[tokio::test]
+    async fn reader_fails_on_eof_instead_of_spinning() {
+        let data: &[u8] = &[6, 0, 0, 0, 0, 7, 0, 0];
+        let mut reader = DerpReader::new(data);
+
+        let message = reader.get_next_message().await.unwrap();
+        assert_eq!(message.ty, FrameType::KeepAlive);
+        assert!(matches!(
+            reader.get_next_message().await,
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    /// Reader counting how often it is read from
+    struct CountingReader<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl AsyncRead for CountingReader<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            self.reads += 1;
+            Pin::new(&mut self.data).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_arriving_together_take_one_read() {
+        let data: &[u8] = &[6, 0, 0, 0, 0, 7, 0, 0, 0, 1, 1];
+
+        let mut reader = DerpReader::new(CountingReader { data, reads: 0 });
+        reader.get_next_message().await.unwrap();
+        reader.get_next_message().await.unwrap();
+        assert_eq!(reader.framed.get_ref().reads, 1);
+
+        let mut reader = DerpReader::with_buf_size(CountingReader { data, reads: 0 }, 4);
+        assert_eq!(
+            reader.get_next_message().await.unwrap().ty,
+            FrameType::KeepAlive
+        );
+        assert_eq!(reader.get_next_message().await.unwrap().body, vec![1]);
+        assert_eq!(reader.framed.get_ref().reads, 3);
+    }
+
+    /// Reader returning one chunk per read
+    struct ChunkedReader {
+        chunks: Vec<&'static [u8]>,
+        reads: usize,
+    }
+
+    impl AsyncRead for ChunkedReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(chunk) = self.chunks.get(self.reads).copied() {
+                buf.put_slice(chunk);
+                self.reads += 1;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_split_over_partial_reads() {
+        let chunks: Vec<&[u8]> = vec![&[7, 0], &[0, 0, 2, 1], &[2]];
+        let mut reader = DerpReader::new(ChunkedReader { chunks, reads: 0 });
+
+        let message = reader.get_next_message().await.unwrap();
+        assert_eq!(reader.framed.get_ref().reads, 3);
+        assert_eq!(message.ty, FrameType::NotePreferred);
+        assert_eq!(message.body, vec![1, 2]);
+    }
+
+    #[test]
+    fn frame_body_decodes_only_its_payload() {
+        let mut data = vec![9, 0, 0, 0, 32];
+        data.extend_from_slice(&[7; 32]);
+        let frame = SyncDerpReader::new(data.as_slice())
+            .get_next_message()
+            .unwrap();
+        assert_eq!(frame.size, 32);
+        assert_eq!(
+            frame.decode_body::<PeerPresent>().unwrap().public_key.0,
+            [7; 32]
+        );
+        assert!(matches!(
+            frame.decode_body::<PeerGone>(),
+            Err(Error::UnexpectedFrameType { .. })
+        ));
+
+        let mut data = vec![9, 0, 0, 0, 33];
+        data.extend_from_slice(&[7; 33]);
+        let frame = SyncDerpReader::new(data.as_slice())
+            .get_next_message()
+            .unwrap();
+        assert!(matches!(
+            frame.decode_body::<PeerPresent>(),
+            Err(Error::DecodeError(DecodeError::InvalidSize))
+        ));
+    }
+
+    #[test]
+    fn decoder_rejects_data_over_limits() {
+        let mut decoder = DerpFrameDecoder::with_memory_limit(8);
+        let mut data = BytesMut::from(&[4, 0, 0, 0, 3, 1, 2][..]);
+        assert!(decoder.decode(&mut data).unwrap().is_none());
+        data.extend_from_slice(&[3]);
+        assert_eq!(
+            decoder.decode(&mut data).unwrap().unwrap().body,
+            vec![1, 2, 3]
+        );
+        assert!(data.is_empty());
+
+        let mut data = BytesMut::from(&[4, 0, 0, 0, 32, 1, 2, 3, 4][..]);
+        assert!(matches!(
+            decoder.decode(&mut data),
+            Err(Error::InputBufferFull(8))
+        ));
+
+        let mut decoder = DerpFrameDecoder::default();
+        let mut data = BytesMut::from(&[4, 0, 0, 0xff, 0xff][..]);
+        assert!(decoder.decode(&mut data).unwrap().is_none());
+        let mut data = BytesMut::from(&[4, 0, 1, 0, 0][..]);
+        assert!(matches!(
+            decoder.decode(&mut data),
+            Err(Error::FrameTooBig(0x10000))
+        ));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::crypto::PublicKey;
+use serde::Serialize;
+use serde_with::{serde_as, TimestampSecondsWithFrac};
+use std::{collections::VecDeque, time::SystemTime};
+
+/// Number of forwarded packets remembered by the journal, older ones are forgotten
+pub const JOURNAL_CAPACITY: usize = 1000;
+/// Number of the most recent entries logged with the periodic stats
+pub const JOURNAL_REPORT_ENTRIES: usize = 100;
+
+/// How a packet left this server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Via {
+    /// Delivered to a client connected here
+    Local,
+    /// Forwarded to the mesh peer the target is connected to
+    Mesh,
+}
+
+/// A forwarded packet, without its payload
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct JournalEntry {
+    #[serde_as(as = "TimestampSecondsWithFrac<f64>")]
+    pub timestamp: SystemTime,
+    pub source_pk: PublicKey,
+    pub target_pk: PublicKey,
+    pub size_bytes: usize,
+    pub via: Via,
+}
+
+/// The last `JOURNAL_CAPACITY` forwarded packets, for debugging routing problems without a
+/// packet capture.
+#[derive(Debug, Default)]
+pub struct PacketJournal {
+    entries: VecDeque<JournalEntry>,
+}
+
+impl PacketJournal {
+    pub fn record(&mut self, entry: JournalEntry) {
+        if self.entries.len() == JOURNAL_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Up to `count` most recent entries, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<JournalEntry> {
+        let skip = self.entries.len().saturating_sub(count);
+        self.entries.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entry(size_bytes: usize) -> JournalEntry {
+        JournalEntry {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1500),
+            source_pk: PublicKey::new([1; 32]),
+            target_pk: PublicKey::new([2; 32]),
+            size_bytes,
+            via: Via::Mesh,
+        }
+    }
+
+    #[test]
+    fn keeps_most_recent_entries() {
+        let mut journal = PacketJournal::default();
+        for size_bytes in 0..JOURNAL_CAPACITY + 10 {
+            journal.record(entry(size_bytes));
+        }
+        let recent = journal.recent(3);
+        let sizes: Vec<_> = recent.iter().map(|entry| entry.size_bytes).collect();
+        assert_eq!(
+            sizes,
+            [
+                JOURNAL_CAPACITY + 7,
+                JOURNAL_CAPACITY + 8,
+                JOURNAL_CAPACITY + 9
+            ]
+        );
+        assert_eq!(journal.recent(usize::MAX).len(), JOURNAL_CAPACITY);
+
+        let json = serde_json::to_value(entry(42)).unwrap();
+        assert_eq!(json["timestamp"], 1.5);
+        assert_eq!(json["size_bytes"], 42);
+        assert_eq!(json["via"], "mesh");
+    }
+}
@@ -1,42 +1,345 @@
+mod audit;
 mod client;
+mod compression;
+mod connection;
 mod crypto;
+mod dedup;
+mod discovery;
+#[cfg(feature = "h2-transport")]
+mod http2;
 mod inout;
+mod journal;
 mod mesh_client;
 mod proto;
+mod queue;
+#[cfg(feature = "quic-transport")]
+mod quic;
+mod ratelimit;
+mod reorder;
 mod service;
+mod systemd;
+#[cfg(test)]
+mod testing;
+mod verify;
 
-use crate::service::{DerpService, Service};
-use clap::Parser;
-use log::info;
-use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::RwLock;
+use crate::{
+    compression::Compression,
+    crypto::SecretKey,
+    mesh_client::MeshPeerSettings,
+    service::{BackpressurePolicy, DerpService, Service, ServiceCommand},
+};
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use listenfd::ListenFd;
+use log::{info, warn};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::{lookup_host, TcpListener, TcpSocket};
+use tokio::sync::{mpsc::Sender, RwLock};
 
 #[derive(Parser, Debug)]
 #[command(version)]
 pub struct Config {
-    /// Path to the mesh key used to authenticate with other derp servers
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Mesh key used to authenticate with other derp servers. Deprecated as it is visible in
+    /// the process list, prefer the `DERP_MESHKEY` environment variable or `--meshkey-file`
     #[arg(long)]
     meshkey: Option<String>,
 
+    /// File containing the mesh key used to authenticate with other derp servers
+    #[arg(long)]
+    meshkey_file: Option<PathBuf>,
+
     /// List of other derp servers with which we should create a mesh
     #[arg(long)]
     mesh_peers: Vec<String>,
 
+    /// Local IP address used as the source of connections to mesh peers
+    #[arg(long)]
+    mesh_bind_addr: Option<String>,
+
+    /// DNS SRV record (e.g. `_derp._tcp.example.com`) listing derp servers to mesh with,
+    /// re-queried whenever its TTL expires
+    #[arg(long)]
+    mesh_srv_record: Option<String>,
+
+    /// Maximum number of mesh peers allowed to connect to us and watch our clients. Outgoing
+    /// connections to `mesh_peers` do not count towards it
+    #[arg(long)]
+    max_mesh_peers: Option<usize>,
+
+    /// Consecutive failed connections to a mesh peer after which reconnecting is paused
+    #[arg(long, default_value_t = 5)]
+    mesh_failure_threshold: u32,
+
+    /// Seconds for which reconnecting to a failing mesh peer is paused
+    #[arg(long, default_value_t = 60)]
+    circuit_reset_secs: u64,
+
+    /// User-Agent sent to mesh peers in the HTTP upgrade request, `dersp/<version> <os>` by
+    /// default
+    #[arg(long)]
+    user_agent: Option<String>,
+
+    /// Seconds allowed for connecting to a mesh peer and completing the handshake with it
+    #[arg(long, default_value_t = 10)]
+    mesh_handshake_timeout_secs: u64,
+
+    /// Seconds allowed for a client to upgrade its connection and complete the DERP handshake
+    #[arg(long, default_value_t = 10)]
+    handshake_timeout_secs: u64,
+
+    /// Seconds a single write to a client may take before its connection is closed
+    #[arg(long, default_value_t = 30)]
+    write_watchdog_secs: u64,
+
+    /// Seconds a client may send nothing, not even a keepalive, before its connection is closed
+    #[arg(long)]
+    client_idle_timeout_secs: Option<u64>,
+
+    /// Number the packets sent between every pair of peers and deliver them to the target in order
+    #[arg(long)]
+    enable_ordered_delivery: bool,
+
+    /// Milliseconds a packet is held back waiting for the packets before it, with
+    /// `--enable-ordered-delivery`
+    #[arg(long, default_value_t = 50)]
+    reorder_timeout_ms: u64,
+
+    /// Seconds between logged summaries of connected peers, dropped packets and failed
+    /// handshakes, 0 disables them
+    #[arg(long, default_value_t = 60)]
+    stats_interval_secs: u64,
+
+    /// Remember the source, target and size of the last forwarded packets, for debugging routing.
+    /// The most recent ones are logged at debug level with the stats
+    #[arg(long)]
+    enable_packet_journal: bool,
+
+    /// What happens to a packet for a peer whose write queue is full
+    #[arg(long, value_enum, default_value_t = BackpressurePolicy::DropOldest)]
+    write_backpressure: BackpressurePolicy,
+
+    /// Milliseconds a packet may wait for the service to route it, older packets are dropped
+    #[arg(long, default_value_t = 1000)]
+    max_command_age_ms: u64,
+
+    /// Seconds given to clients to receive their queued packets after Ctrl-C
+    #[arg(long, default_value_t = 5)]
+    drain_timeout_secs: u64,
+
+    /// Maximum bytes per second relayed from one peer to another, packets over it are dropped
+    #[arg(long)]
+    max_bytes_per_sec_per_pair: Option<u64>,
+
+    /// Compress payloads forwarded to mesh peers when longer than this many bytes. Used only
+    /// with mesh peers that enabled it too
+    #[arg(long)]
+    compress_threshold: Option<usize>,
+
+    /// File to which connect, disconnect and failed handshake events are appended as JSON lines
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Address to listen on, not needed when a socket is passed by systemd socket activation
     #[arg(long, short)]
-    listen_on: String,
+    listen_on: Option<String>,
+
+    /// Connections waiting to be accepted on `--listen-on` before new ones are refused,
+    /// 1024 by default
+    #[arg(long)]
+    tcp_accept_backlog: Option<u32>,
+
+    /// Address to also accept DERP over QUIC on
+    #[cfg(feature = "quic-transport")]
+    #[arg(long)]
+    quic_listen_on: Option<String>,
+
+    /// Address to also accept DERP over HTTP/2 on, one client per request stream
+    #[cfg(feature = "h2-transport")]
+    #[arg(long)]
+    h2_listen_on: Option<String>,
+
+    /// Send systemd watchdog keepalives, by default only when systemd sets `WATCHDOG_USEC`
+    #[arg(long)]
+    systemd_watchdog: Option<bool>,
+
+    /// Log filter in `env_logger` syntax (e.g. `dersp=debug,codec=warn`), overrides `RUST_LOG`
+    #[arg(long)]
+    log_filter: Option<String>,
+}
+
+// Key management tools, the server runs when no subcommand is given
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a new random secret key as hex to stdout and its public key to stderr
+    Keygen,
+    /// Print the public key of a secret key as hex
+    Pubkey {
+        /// Secret key as hex
+        #[arg(long)]
+        secret: String,
+    },
+    /// Check the options and try a handshake with every mesh peer, without starting the server.
+    /// Exits with 1 if some mesh peers are unreachable and with 2 if the options are invalid
+    Verify,
+}
+
+impl Command {
+    fn run(&self) -> anyhow::Result<()> {
+        match self {
+            Command::Keygen => {
+                let secret_key = SecretKey::gen();
+                println!("{}", secret_key.to_hex());
+                eprintln!("{}", secret_key.public().to_hex());
+            }
+            Command::Pubkey { secret } => {
+                let bytes = hex::decode(secret.trim()).context("Secret key is not valid hex")?;
+                println!("{}", SecretKey::from_bytes(&bytes)?.public().to_hex());
+            }
+            Command::Verify => unreachable!("verify needs the whole config"),
+        }
+        Ok(())
+    }
+}
+
+/// Environment variable with the mesh key, the preferred way to pass it in production
+const MESHKEY_ENV: &str = "DERP_MESHKEY";
+
+/// Listen backlog used without `--tcp-accept-backlog`, large enough for many clients reconnecting
+/// at once, e.g. after a network partition heals
+const DEFAULT_ACCEPT_BACKLOG: u32 = 1024;
+
+impl Config {
+    /// Mesh key from `--meshkey-file`, `DERP_MESHKEY` or `--meshkey`, in this order.
+    pub fn resolve_meshkey(&self) -> anyhow::Result<Option<String>> {
+        if let Some(path) = &self.meshkey_file {
+            let meshkey = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read mesh key from {}", path.display()))?;
+            return Ok(Some(meshkey.trim().to_owned()));
+        }
+        if let Ok(meshkey) = std::env::var(MESHKEY_ENV) {
+            return Ok(Some(meshkey));
+        }
+        if self.meshkey.is_some() {
+            warn!("--meshkey is deprecated, use {MESHKEY_ENV} or --meshkey-file instead");
+        }
+        Ok(self.meshkey.clone())
+    }
+
+    /// Everything needed to connect to mesh peers, `None` without a mesh key.
+    pub fn mesh_peer_settings(
+        &self,
+        secret_key: SecretKey,
+        command_sender: Sender<ServiceCommand>,
+    ) -> anyhow::Result<Option<MeshPeerSettings>> {
+        let bind_addr = self
+            .mesh_bind_addr
+            .as_deref()
+            .map(str::parse::<IpAddr>)
+            .transpose()
+            .context("Invalid --mesh-bind-addr")?;
+        let Some(meshkey) = self.resolve_meshkey()? else {
+            return Ok(None);
+        };
+        Ok(Some(MeshPeerSettings {
+            secret_key,
+            meshkey,
+            bind_addr,
+            command_sender,
+            failure_threshold: self.mesh_failure_threshold,
+            circuit_reset: Duration::from_secs(self.circuit_reset_secs),
+            compression: self.compress_threshold.map(Compression::new),
+            handshake_timeout: Duration::from_secs(self.mesh_handshake_timeout_secs),
+            user_agent: self.user_agent.clone().unwrap_or_else(|| {
+                format!(
+                    "dersp/{} {}",
+                    env!("CARGO_PKG_VERSION"),
+                    std::env::consts::OS
+                )
+            }),
+        }))
+    }
 }
 
 #[tokio::main]
 pub async fn main() -> anyhow::Result<()> {
-    env_logger::init();
     let config = Config::parse();
+    match &config.command {
+        Some(Command::Verify) => {}
+        Some(command) => return command.run(),
+        None => {}
+    }
+    match &config.log_filter {
+        Some(filter) => env_logger::Builder::new().parse_filters(filter).init(),
+        None => env_logger::init(),
+    }
+    if let Some(Command::Verify) = config.command {
+        std::process::exit(verify::run(&config).await);
+    }
     info!("Config: {config:?}");
 
-    let listener = TcpListener::bind(&config.listen_on).await?;
+    let listener = match ListenFd::from_env().take_tcp_listener(0)? {
+        Some(listener) => {
+            info!("Using socket passed by systemd");
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => {
+            let Some(listen_on) = &config.listen_on else {
+                bail!("--listen-on is required without systemd socket activation");
+            };
+            let Some(addr) = lookup_host(listen_on).await?.next() else {
+                bail!("{listen_on} does not resolve to any address");
+            };
+            let backlog = config.tcp_accept_backlog.unwrap_or(DEFAULT_ACCEPT_BACKLOG);
+            bind_listener(addr, backlog)?
+        }
+    };
+    #[cfg(feature = "quic-transport")]
+    let quic_endpoint = match &config.quic_listen_on {
+        Some(quic_listen_on) => Some(quic::bind(quic_listen_on.parse()?)?),
+        None => None,
+    };
+    #[cfg(feature = "h2-transport")]
+    let h2_listener = match &config.h2_listen_on {
+        Some(h2_listen_on) => Some(TcpListener::bind(h2_listen_on.as_str()).await?),
+        None => None,
+    };
+    let systemd_watchdog = config.systemd_watchdog;
     let service: Arc<RwLock<DerpService>> = DerpService::new(config).await?;
 
     info!("Listening on: {:?}", listener.local_addr());
+    #[cfg(feature = "quic-transport")]
+    if let Some(endpoint) = quic_endpoint {
+        info!("Listening for QUIC on: {:?}", endpoint.local_addr());
+        tokio::spawn(quic::run(endpoint, service.clone()));
+    }
+    #[cfg(feature = "h2-transport")]
+    if let Some(h2_listener) = h2_listener {
+        info!("Listening for HTTP/2 on: {:?}", h2_listener.local_addr());
+        tokio::spawn(http2::run(h2_listener, service.clone()));
+    }
+    systemd::notify_ready();
+    systemd::start_watchdog(systemd_watchdog);
 
     service.run(listener).await
 }
+
+/// Like `TcpListener::bind`, but with the given listen backlog instead of the OS default.
+fn bind_listener(addr: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
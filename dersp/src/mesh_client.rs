@@ -1,94 +1,309 @@
-use std::{io::Cursor, net::SocketAddr};
+use std::{
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, bail};
-use codec::Decode;
+use anyhow::{anyhow, bail, ensure};
+use futures_util::StreamExt;
 use httparse::Status;
 use log::debug;
-use log::{trace, warn};
+use log::{info, trace, warn};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{lookup_host, tcp::OwnedWriteHalf, TcpStream},
-    spawn,
-    sync::mpsc::{channel, Receiver, Sender},
+    net::{lookup_host, tcp::OwnedWriteHalf, TcpSocket, TcpStream},
+    select, spawn,
+    sync::mpsc::Sender,
+    task::JoinHandle,
+    time::{sleep, timeout},
 };
 
 use crate::{
-    client::WriteLoopCommands,
+    client::{WriteLoopCommands, WRITE_QUEUE_CAPACITY},
+    compression::Compression,
     crypto::{PublicKey, SecretKey},
     inout::DerpReader,
-    proto::data::{ForwardPacket, Frame, FrameType, PeerPresent},
-    proto::{exchange_keys, read_server_info, write_peer_present, write_watch_conns},
+    proto::data::{
+        ControlMessage, ForwardPacket, FrameType, PeerGone, PeerPresent, RawControlMessage,
+        ServerCapabilities,
+    },
+    proto::{
+        exchange_keys, read_server_info, write_forward_packet, write_peer_gone, write_peer_present,
+        write_watch_conns,
+    },
+    queue::{BoundedMpsc, BoundedMpscReceiver},
     service::ServiceCommand,
 };
 
 /// Max TCP packet size is 65535
 const MAX_TCP_PACKET_SIZE: usize = u16::MAX as usize;
 
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Everything needed to (re)connect to a mesh peer
+#[derive(Clone)]
+pub struct MeshPeerSettings {
+    pub secret_key: SecretKey,
+    pub meshkey: String,
+    pub bind_addr: Option<IpAddr>,
+    pub command_sender: Sender<ServiceCommand>,
+    /// Consecutive connection failures after which reconnecting is paused
+    pub failure_threshold: u32,
+    /// How long reconnecting stays paused before a single probe is made
+    pub circuit_reset: Duration,
+    pub compression: Option<Compression>,
+    /// Time allowed for connecting and completing the DERP handshake
+    pub handshake_timeout: Duration,
+    /// User-Agent header of the HTTP upgrade request
+    pub user_agent: String,
+}
+
+/// Stops reconnect attempts after `failure_threshold` consecutive failures (open circuit) and
+/// allows a single probe once `reset_after` has passed since the last failure.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    reset_after: Duration,
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, reset_after: Duration) -> Self {
+        Self {
+            failure_threshold,
+            reset_after,
+            failures: 0,
+            opened_at: None,
+        }
+    }
+
+    /// Time left until the next attempt is allowed, `None` if it can be made right away.
+    pub fn retry_in(&self) -> Option<Duration> {
+        let elapsed = self.opened_at?.elapsed();
+        (elapsed < self.reset_after).then(|| self.reset_after - elapsed)
+    }
+
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.opened_at = None;
+    }
+
+    /// Returns `true` if this failure opened the circuit.
+    pub fn record_failure(&mut self) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures < self.failure_threshold {
+            return false;
+        }
+        self.opened_at.replace(Instant::now()).is_none()
+    }
+}
+
+/// Keep a connection to the mesh peer at `addr` alive, reconnecting with exponential backoff and
+/// reporting it to the service with `MeshPeerUp`/`MeshPeerDown`.
+pub async fn maintain_mesh_peer(addr: String, settings: MeshPeerSettings) {
+    let mut breaker = CircuitBreaker::new(settings.failure_threshold, settings.circuit_reset);
+    let mut backoff = INITIAL_RECONNECT_BACKOFF;
+    loop {
+        if let Some(wait) = breaker.retry_in() {
+            sleep(wait).await;
+            info!("Probing mesh peer {addr}");
+        }
+
+        let connection = async { MeshClient::new(&addr, &settings).await?.start().await };
+        match connection.await {
+            Ok((sender, mesh_peer_pk, connection)) => {
+                breaker.record_success();
+                backoff = INITIAL_RECONNECT_BACKOFF;
+                if settings
+                    .command_sender
+                    .send(ServiceCommand::MeshPeerUp(mesh_peer_pk, sender))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                match connection.await {
+                    Ok(Ok(())) => info!("Mesh peer {addr} closed the connection"),
+                    Ok(Err(e)) => warn!("Connection to mesh peer {addr} failed: {e}"),
+                    Err(e) => warn!("Connection to mesh peer {addr} panicked: {e}"),
+                }
+                if settings
+                    .command_sender
+                    .send(ServiceCommand::MeshPeerDown(mesh_peer_pk))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to connect to mesh peer {addr}: {e}");
+                if breaker.record_failure() {
+                    warn!(
+                        "Giving up on mesh peer {addr} for {:?} after {} consecutive failures",
+                        settings.circuit_reset, settings.failure_threshold
+                    );
+                }
+                if breaker.retry_in().is_some() {
+                    continue;
+                }
+            }
+        }
+
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+}
+
 pub struct MeshClient {
     addr: SocketAddr,
     secret_key: SecretKey,
     meshkey: String,
+    bind_addr: Option<IpAddr>,
+    compression: Option<Compression>,
+    handshake_timeout: Duration,
+    user_agent: String,
     command_sender: Sender<ServiceCommand>,
 }
 
 impl MeshClient {
-    pub async fn new(
-        addr_or_host: &str,
-        secret_key: SecretKey,
-        meshkey: String,
-        command_sender: Sender<ServiceCommand>,
-    ) -> anyhow::Result<Self> {
+    pub async fn new(addr_or_host: &str, settings: &MeshPeerSettings) -> anyhow::Result<Self> {
         if let Some(addr) = lookup_host(addr_or_host).await?.next() {
             debug!("mesh peer {addr_or_host} is in fact: {addr}");
             Ok(Self {
                 addr,
-                secret_key,
-                meshkey,
-                command_sender,
+                secret_key: settings.secret_key,
+                meshkey: settings.meshkey.clone(),
+                bind_addr: settings.bind_addr,
+                compression: settings.compression,
+                handshake_timeout: settings.handshake_timeout,
+                user_agent: settings.user_agent.clone(),
+                command_sender: settings.command_sender.clone(),
             })
         } else {
             bail!("Failed to resolve {addr_or_host}");
         }
     }
 
-    pub async fn start(self) -> anyhow::Result<(Sender<WriteLoopCommands>, PublicKey)> {
-        let stream = TcpStream::connect(self.addr).await?;
-        let (sender, receiver) = channel(1);
+    /// Connect to the mesh peer and return its sink, public key and the task running the
+    /// connection, which finishes once the connection is closed.
+    pub async fn start(
+        self,
+    ) -> anyhow::Result<(
+        BoundedMpsc<WriteLoopCommands>,
+        PublicKey,
+        JoinHandle<anyhow::Result<()>>,
+    )> {
+        let stream = self.connect().await?;
+        let (sender, receiver) = BoundedMpsc::channel(WRITE_QUEUE_CAPACITY);
         let (mesh_peer_pk_sender, mesh_peer_pk_receiver) = tokio::sync::oneshot::channel();
-        spawn(self.run(stream, sender.clone(), receiver, mesh_peer_pk_sender));
+        let connection = spawn(self.run(stream, sender.clone(), receiver, mesh_peer_pk_sender));
         let mesh_peer_pk = mesh_peer_pk_receiver.await?;
-        Ok((sender, mesh_peer_pk))
+        Ok((sender, mesh_peer_pk, connection))
+    }
+
+    /// Connect to the mesh peer and complete the handshake, without watching its clients or
+    /// telling the service about it. Returns the public key of the mesh peer.
+    pub async fn verify(self) -> anyhow::Result<PublicKey> {
+        let stream = self.connect().await?;
+        let server_addr = stream.peer_addr()?;
+        let (_, _, mesh_peer_pk, capabilities) = self.handshake(stream).await?;
+        ensure!(
+            capabilities.contains(ServerCapabilities::MESH),
+            "Mesh peer {server_addr} does not support meshing"
+        );
+        Ok(mesh_peer_pk)
+    }
+
+    async fn connect(&self) -> anyhow::Result<TcpStream> {
+        let socket = if self.addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(bind_addr) = self.bind_addr {
+            socket.bind(SocketAddr::new(bind_addr, 0))?;
+        }
+        let stream = timeout(self.handshake_timeout, socket.connect(self.addr))
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "Connecting to mesh peer {} timed out after {:?}",
+                    self.addr,
+                    self.handshake_timeout
+                )
+            })??;
+        Ok(stream)
+    }
+
+    /// Upgrade the connection and exchange keys within the handshake timeout, returning the
+    /// reader of the following frames, the writer, and the key and capabilities of the mesh
+    /// peer.
+    async fn handshake(
+        &self,
+        stream: TcpStream,
+    ) -> anyhow::Result<(
+        DerpReader<impl AsyncRead + Unpin>,
+        OwnedWriteHalf,
+        PublicKey,
+        ServerCapabilities,
+    )> {
+        let server_addr = stream.peer_addr()?;
+        let (mut r, mut w) = stream.into_split();
+
+        let handshake = async {
+            let leftovers = connect_http(&mut r, &mut w, &self.user_agent).await?;
+            let reader = Cursor::new(leftovers).chain(r);
+            let mut derp_reader = DerpReader::new(reader);
+
+            let mesh_peer_pk = exchange_keys(
+                &mut derp_reader,
+                &mut w,
+                self.secret_key,
+                Some(&self.meshkey),
+                self.compression.is_some(),
+            )
+            .await?;
+
+            let capabilities = read_server_info(&mut derp_reader).await?;
+            anyhow::Ok((derp_reader, mesh_peer_pk, capabilities))
+        };
+        let (derp_reader, mesh_peer_pk, capabilities) = timeout(self.handshake_timeout, handshake)
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "Handshake with mesh peer {server_addr} timed out after {:?}",
+                    self.handshake_timeout
+                )
+            })??;
+        Ok((derp_reader, w, mesh_peer_pk, capabilities))
     }
 
     pub async fn run(
         self,
         stream: TcpStream,
-        sender: Sender<WriteLoopCommands>,
-        receiver: Receiver<WriteLoopCommands>,
+        sender: BoundedMpsc<WriteLoopCommands>,
+        receiver: BoundedMpscReceiver<WriteLoopCommands>,
         mesh_peer_pk_sender: tokio::sync::oneshot::Sender<PublicKey>,
     ) -> anyhow::Result<()> {
         // TODO: handle closing of the mesh_peer_pk_sender when there is some error?
         // Maybe this is already handled by the receiver returning result?
         let server_addr = stream.peer_addr()?;
-        let (mut r, mut w) = stream.into_split();
-
-        let leftovers = connect_http(&mut r, &mut w).await?;
-        let reader = Cursor::new(leftovers).chain(r);
-        let mut derp_reader = DerpReader::new(reader);
-
-        let mesh_peer_pk = exchange_keys(
-            &mut derp_reader,
-            &mut w,
-            self.secret_key,
-            Some(&self.meshkey),
-        )
-        .await?;
+        let (derp_reader, mut w, mesh_peer_pk, capabilities) = self.handshake(stream).await?;
 
         mesh_peer_pk_sender
             .send(mesh_peer_pk)
             .map_err(|e| anyhow!("{e}"))?;
 
-        read_server_info(&mut derp_reader).await?;
+        ensure!(
+            capabilities.contains(ServerCapabilities::MESH),
+            "Mesh peer {server_addr} does not support meshing"
+        );
+        let compression = self
+            .compression
+            .filter(|_| capabilities.contains(ServerCapabilities::COMPRESSION));
 
         write_watch_conns(&mut w).await?;
 
@@ -98,11 +313,21 @@ impl MeshClient {
             server_addr
         );
 
-        spawn(write_loop(receiver, w));
-
-        if let Err(e) = self.read_loop(derp_reader, sender).await {
-            warn!("[{mesh_peer_pk:?}] read loop failed: {e}");
-            return Err(e);
+        // Whichever loop ends first closes the whole connection, so `MeshPeerDown` is reported
+        select! {
+            result = self.read_loop(derp_reader, mesh_peer_pk, sender, compression) => {
+                if let Err(e) = result {
+                    warn!("[{mesh_peer_pk:?}] read loop failed: {e}");
+                    return Err(e);
+                }
+            }
+            result = write_loop(receiver, w, compression) => {
+                if let Err(e) = result {
+                    warn!("[{mesh_peer_pk:?}] write loop failed: {e}");
+                    return Err(e);
+                }
+                debug!("[{mesh_peer_pk:?}] write loop stopped");
+            }
         }
 
         Ok(())
@@ -111,22 +336,24 @@ impl MeshClient {
     async fn read_loop<T: AsyncRead + Unpin>(
         self,
         mut reader: DerpReader<T>,
-        sender: Sender<WriteLoopCommands>,
+        mesh_peer_pk: PublicKey,
+        sender: BoundedMpsc<WriteLoopCommands>,
+        compression: Option<Compression>,
     ) -> anyhow::Result<()> {
-        loop {
-            let message = reader.get_next_message().await?;
+        while let Some(message) = reader.next().await {
+            let message = message?;
 
             trace!("next frame: {:?}", message.ty);
 
             match message.ty {
                 FrameType::PeerPresent => {
-                    let peer_present = Frame::<PeerPresent>::decode(&mut message.buffer.as_slice())
-                        .map_err(|_| anyhow!("Decode error"))?
-                        .inner
-                        .into_inner();
+                    let peer_present = message
+                        .decode_body::<PeerPresent>()
+                        .map_err(|_| anyhow!("Decode error"))?;
                     trace!("Got peer present for {}", peer_present.public_key);
                     self.command_sender
-                        .send(ServiceCommand::PeerPresent(
+                        .send(ServiceCommand::MeshPeerPresent(
+                            mesh_peer_pk,
                             peer_present.public_key,
                             sender.clone(),
                         ))
@@ -134,35 +361,100 @@ impl MeshClient {
                         .unwrap();
                 }
 
+                FrameType::PeerGone => {
+                    let peer_gone = message
+                        .decode_body::<PeerGone>()
+                        .map_err(|_| anyhow!("Decode error"))?;
+                    trace!("Got peer gone for {}", peer_gone.public_key);
+                    self.command_sender
+                        .send(ServiceCommand::PeerGone(
+                            peer_gone.public_key,
+                            sender.clone(),
+                        ))
+                        .await?;
+                }
+
                 FrameType::ForwardPacket => {
-                    let forward_packet =
-                        Frame::<ForwardPacket>::decode(&mut message.buffer.as_slice())
-                            .map_err(|_| anyhow!("Decode error"))?
-                            .inner
-                            .into_inner();
+                    let forward_packet = message
+                        .decode_body::<ForwardPacket>()
+                        .map_err(|_| anyhow!("Decode error"))?;
+                    let Some(ttl) = forward_packet.ttl.checked_sub(1) else {
+                        warn!(
+                            "Dropping forward packet from {:?} to {:?}: ttl expired",
+                            forward_packet.source, forward_packet.target
+                        );
+                        continue;
+                    };
+                    let payload = match compression {
+                        Some(compression) => compression.decompress(&forward_packet.payload)?,
+                        None => forward_packet.payload,
+                    };
                     self.command_sender
                         .send(ServiceCommand::SendPacket {
                             source: forward_packet.source,
                             target: forward_packet.target,
-                            payload: forward_packet.payload,
+                            ttl,
+                            seq_no: forward_packet.seq_no,
+                            payload,
+                            queued_at: Instant::now(),
                         })
                         .await?;
                 }
 
-                _ => todo!(),
+                FrameType::ControlMessage => {
+                    let control_message = message
+                        .decode_body::<RawControlMessage>()
+                        .map_err(|_| anyhow!("Decode error"))?
+                        .parse()?;
+                    match control_message {
+                        ControlMessage::Redirect { addr } => {
+                            info!("Mesh peer asked us to reconnect to {addr}")
+                        }
+                        ControlMessage::Disconnect { reason } => {
+                            bail!("Mesh peer disconnected us: {reason}")
+                        }
+                    }
+                }
+
+                frame_type => warn!("Ignoring unexpected {frame_type:?} frame from mesh peer"),
             }
         }
+
+        debug!("mesh peer closed the connection");
+        Ok(())
     }
 }
 
-async fn write_loop(mut r: Receiver<WriteLoopCommands>, mut writer: OwnedWriteHalf) {
+/// Write the commands of the service to the mesh peer until the service drops the link or a
+/// write fails.
+async fn write_loop<W: AsyncWrite + Unpin>(
+    mut r: BoundedMpscReceiver<WriteLoopCommands>,
+    mut writer: W,
+    compression: Option<Compression>,
+) -> anyhow::Result<()> {
     loop {
         match r.recv().await {
             Some(WriteLoopCommands::PeerPresent(pk)) => {
-                write_peer_present(&mut writer, &pk).await.unwrap();
+                write_peer_present(&mut writer, &pk).await?;
+            }
+            Some(WriteLoopCommands::PeerGone(pk)) => {
+                write_peer_gone(&mut writer, &pk).await?;
+            }
+            Some(WriteLoopCommands::ForwardPacket(mut forward_packet)) => {
+                if let Some(compression) = compression {
+                    forward_packet.payload = compression.compress(&forward_packet.payload);
+                }
+                if forward_packet.payload_len() > ForwardPacket::MAX_PAYLOAD_SIZE {
+                    warn!(
+                        "Dropping forward packet of {} bytes: too big",
+                        forward_packet.payload_len()
+                    );
+                    continue;
+                }
+                write_forward_packet(&mut writer, forward_packet).await?;
             }
-            Some(x) => todo!("{x:?}"),
-            None => todo!(),
+            Some(WriteLoopCommands::_Stop) | None => return Ok(()),
+            Some(command) => warn!("Ignoring {command:?}, it is not meant for mesh peers"),
         }
     }
 }
@@ -170,6 +462,7 @@ async fn write_loop(mut r: Receiver<WriteLoopCommands>, mut writer: OwnedWriteHa
 async fn connect_http<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
     reader: &mut R,
     writer: &mut W,
+    user_agent: &str,
     // server_keepalives: &DerpKeepaliveConfig,
     // host: &str,
 ) -> anyhow::Result<Vec<u8>> {
@@ -180,9 +473,7 @@ async fn connect_http<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
                 "GET /derp HTTP/1.1\r\n\
                 Connection: Upgrade\r\n\
                 Upgrade: WebSocket\r\n\
-                User-Agent: telio/{} {}\r\n\r\n",
-                env!("CARGO_PKG_VERSION"),
-                std::env::consts::OS,
+                User-Agent: {user_agent}\r\n\r\n",
                 // TODO: server_keepalives.tcp_keepalive,
                 // TODO: server_keepalives.derp_keepalive,
             )
@@ -192,6 +483,11 @@ async fn connect_http<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
 
     let mut data = [0_u8; MAX_TCP_PACKET_SIZE];
     let data_len = reader.read(&mut data).await?;
+    // Fail fast on peers that are not speaking HTTP at all
+    ensure!(
+        data[..data_len].starts_with(b"HTTP/"),
+        "Mesh peer did not answer with an HTTP response"
+    );
 
     let mut headers = [httparse::EMPTY_HEADER; 16];
     let mut res = httparse::Response::new(&mut headers);
@@ -206,3 +502,160 @@ async fn connect_http<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
         .ok_or_else(|| anyhow!("Out of bounds index for data buffer"))?
         .to_vec())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_breaker_opens_after_threshold_and_resets_on_success() {
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        assert!(!breaker.record_failure());
+        assert_eq!(breaker.retry_in(), None);
+        assert!(breaker.record_failure());
+        assert!(breaker.retry_in().is_some());
+        // A failed probe keeps the circuit open without reporting it again
+        assert!(!breaker.record_failure());
+        breaker.record_success();
+        assert_eq!(breaker.retry_in(), None);
+
+        let mut breaker = CircuitBreaker::new(1, Duration::ZERO);
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.retry_in(), None);
+    }
+
+    #[tokio::test]
+    async fn upgrade_request_has_user_agent() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        server
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\n\r\nleftover")
+            .await
+            .unwrap();
+
+        let (mut r, mut w) = tokio::io::split(&mut client);
+        let leftovers = connect_http(&mut r, &mut w, "dersp/test linux")
+            .await
+            .unwrap();
+        assert_eq!(leftovers, b"leftover");
+
+        let mut request = vec![0; 1024];
+        let len = server.read(&mut request).await.unwrap();
+        let request = String::from_utf8_lossy(&request[..len]);
+        assert!(request.contains("\r\nUser-Agent: dersp/test linux\r\n"));
+    }
+
+    #[tokio::test]
+    async fn write_loop_ends_when_link_is_dropped() {
+        let (sink, receiver) = BoundedMpsc::channel(4);
+        let (writer, remote) = tokio::io::duplex(1024);
+        let write_loop = spawn(write_loop(receiver, writer, None));
+
+        sink.send(WriteLoopCommands::PeerGone(PublicKey::new([1; 32])))
+            .await
+            .unwrap();
+        drop(sink);
+        timeout(Duration::from_secs(1), write_loop)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        let mut reader = DerpReader::new(remote);
+        let message = reader.get_next_message().await.unwrap();
+        assert_eq!(message.ty, FrameType::PeerGone);
+        assert!(reader.get_next_message().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn start_times_out_when_peer_never_answers() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (command_sender, _command_receiver) = tokio::sync::mpsc::channel(1);
+        let settings = MeshPeerSettings {
+            secret_key: SecretKey::gen(),
+            meshkey: "mesh".to_owned(),
+            bind_addr: None,
+            command_sender,
+            failure_threshold: 1,
+            circuit_reset: Duration::ZERO,
+            compression: None,
+            handshake_timeout: Duration::from_millis(100),
+            user_agent: "dersp/test".to_owned(),
+        };
+
+        let start = MeshClient::new(&addr, &settings).await.unwrap().start();
+        let result = timeout(Duration::from_secs(5), start).await.unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_loop_ends_on_disconnect() {
+        let (command_sender, _commands) = tokio::sync::mpsc::channel(4);
+        let mesh_client = MeshClient {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            secret_key: SecretKey::gen(),
+            meshkey: "meshkey".to_owned(),
+            bind_addr: None,
+            compression: None,
+            handshake_timeout: Duration::from_secs(1),
+            user_agent: "dersp/test".to_owned(),
+            command_sender,
+        };
+        let (sink, _receiver) = BoundedMpsc::channel(4);
+        let (reader, mut remote) = tokio::io::duplex(1024);
+        crate::proto::write_control_message(
+            &mut remote,
+            &ControlMessage::Disconnect {
+                reason: "too many mesh peers".to_owned(),
+            },
+        )
+        .await
+        .unwrap();
+
+        // The remote stays open, the disconnect alone ends the loop
+        let result = timeout(
+            Duration::from_secs(1),
+            mesh_client.read_loop(DerpReader::new(reader), PublicKey::new([1; 32]), sink, None),
+        )
+        .await
+        .unwrap();
+        assert!(result.is_err());
+        drop(remote);
+    }
+
+    #[tokio::test]
+    async fn read_loop_ignores_unexpected_frames() {
+        let (command_sender, _commands) = tokio::sync::mpsc::channel(4);
+        let mesh_client = MeshClient {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            secret_key: SecretKey::gen(),
+            meshkey: "meshkey".to_owned(),
+            bind_addr: None,
+            compression: None,
+            handshake_timeout: Duration::from_secs(1),
+            user_agent: "dersp/test".to_owned(),
+            command_sender,
+        };
+        let (sink, _receiver) = BoundedMpsc::channel(4);
+        let (reader, mut remote) = tokio::io::duplex(1024);
+        crate::proto::write_watch_conns(&mut remote).await.unwrap();
+        crate::proto::write_control_message(
+            &mut remote,
+            &ControlMessage::Disconnect {
+                reason: "shutting down".to_owned(),
+            },
+        )
+        .await
+        .unwrap();
+
+        // Servers never send WatchConns, the loop must skip it and still see the disconnect
+        let result = timeout(
+            Duration::from_secs(1),
+            mesh_client.read_loop(DerpReader::new(reader), PublicKey::new([1; 32]), sink, None),
+        )
+        .await
+        .unwrap();
+        assert!(result.unwrap_err().to_string().contains("shutting down"));
+        drop(remote);
+    }
+}
@@ -1,5 +1,9 @@
-use anyhow::Context;
-use codec::{Decode, Encode, SizeWrapper};
+use codec::{
+    decode::{DecodeError, PeekBuffer, ReadBuffer},
+    CodecDebug, CodecDisplay, Decode, Encode, EncodedSize, SizeWrapper,
+};
+use log::warn;
+use std::{net::SocketAddr, ops::BitOr};
 
 use crypto_box::{
     aead::{Aead, AeadCore},
@@ -7,12 +11,25 @@ use crypto_box::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::crypto::{PublicKey, SecretKey};
+use super::Error;
+use crate::{
+    crypto::{PublicKey, SecretKey, KEY_SIZE},
+    inout::MAX_TCP_PACKET_SIZE,
+};
 
 /// 8 bytes of magic message prefix: `DERP🔑`
 const MAGIC: [u8; 8] = [0x44, 0x45, 0x52, 0x50, 0xF0, 0x9F, 0x94, 0x91];
 
-#[derive(Debug, Decode, Encode, PartialEq)]
+/// Number of mesh hops a packet is allowed to take before it is dropped
+pub const DEFAULT_FORWARD_TTL: u8 = 8;
+
+/// Protocol version announced by our clients in `ClientInfoPayload`
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Protocol versions accepted from connecting clients
+const SUPPORTED_VERSIONS: &[u32] = &[PROTOCOL_VERSION];
+
+#[derive(Clone, Copy, Debug, Decode, Encode, PartialEq)]
 pub enum FrameType {
     /// 8B magic + 32B public key + (0+ bytes future use)
     #[tag(0x01u8)]
@@ -20,7 +37,7 @@ pub enum FrameType {
     /// 32B pub key + 24B nonce + naclbox(json)
     #[tag(0x02)]
     ClientInfo,
-    /// 24B nonce + naclbox(json)
+    /// 4B `ServerCapabilities` bitmask + (0+ bytes future use)
     #[tag(0x03)]
     ServerInfo,
     /// 32B dest pub key + packet bytes
@@ -48,7 +65,8 @@ pub enum FrameType {
     /// 32B pub key of peer that's connected
     #[tag(0x09)]
     PeerPresent,
-    /// 32B src pub key + 32B dst pub key + packet bytes
+    /// 32B src pub key + 32B dst pub key + 1B ttl + 1B or 5B optional seq no + packet bytes,
+    /// at most `ForwardPacket::MAX_PAYLOAD_SIZE` of them
     #[tag(0x0A)]
     ForwardPacket,
     /// WatchConns is how one DERP node in a regional mesh
@@ -91,13 +109,70 @@ impl FrameType {
     }
 }
 
-#[derive(Decode, Encode)]
+/// Every tag is a single byte
+impl EncodedSize for FrameType {
+    const ENCODED_SIZE: usize = 1;
+}
+
+#[derive(Encode, EncodedSize)]
 pub struct Frame<T> {
     pub frame_type: FrameType,
     pub inner: SizeWrapper<u32, T>,
 }
 
-#[derive(Clone, Default, Decode, Encode)]
+/// Payload of a frame of a single `FrameType`.
+pub trait ExpectedFrameType {
+    const FRAME_TYPE: FrameType;
+}
+
+impl<T: ExpectedFrameType> Frame<T> {
+    /// Frame of the type `T` is the payload of.
+    pub fn new(inner: T) -> Self {
+        Frame {
+            frame_type: T::FRAME_TYPE,
+            inner: SizeWrapper::new(inner),
+        }
+    }
+}
+
+/// Fails with `DecodeError::UnexpectedVariant` if the frame is not of `T::FRAME_TYPE`.
+impl<T: ExpectedFrameType + Decode> Decode for Frame<T> {
+    fn decode<R: ReadBuffer>(read_buffer: &mut R) -> Result<Self, R::Error> {
+        let tag = u8::decode(read_buffer)?;
+        let frame_type = FrameType::decode(&mut [tag].as_slice())?;
+        if frame_type != T::FRAME_TYPE {
+            return Err(DecodeError::UnexpectedVariant(tag.into()).into());
+        }
+        Ok(Frame {
+            frame_type,
+            inner: SizeWrapper::decode(read_buffer)?,
+        })
+    }
+}
+
+macro_rules! expected_frame_types {
+    ($($ty:ty => $frame_type:ident),* $(,)?) => {$(
+        impl ExpectedFrameType for $ty {
+            const FRAME_TYPE: FrameType = FrameType::$frame_type;
+        }
+    )*};
+}
+
+expected_frame_types!(
+    ServerKey => ServerKey,
+    ClientInfo => ClientInfo,
+    ServerInfo => ServerInfo,
+    SendPacket => SendPacket,
+    RecvPacket => RecvPacket,
+    PeerGone => PeerGone,
+    PeerPresent => PeerPresent,
+    ClosePeer => ClosePeer,
+    ForwardPacket => ForwardPacket,
+    WatchConns => WatchConns,
+    RawControlMessage => ControlMessage,
+);
+
+#[derive(Clone, Default, Decode, Encode, EncodedSize)]
 pub struct ServerKey {
     pub magic: [u8; 8],
     pub public_key: PublicKey,
@@ -113,15 +188,17 @@ impl ServerKey {
 
     /// This consume self
     pub fn frame(self) -> Frame<ServerKey> {
-        Frame {
-            frame_type: FrameType::ServerKey,
-            inner: SizeWrapper::new(self),
-        }
+        Frame::new(self)
     }
 
-    pub fn validate_magic(&self) -> anyhow::Result<()> {
-        anyhow::ensure!(self.magic == MAGIC, "Invalid magic {:?}", self.magic);
-        Ok(())
+    /// Decode a server key, rejecting data without the DERP magic before decoding the rest.
+    pub fn decode_validated<R: PeekBuffer<Error = DecodeError>>(
+        read_buffer: &mut R,
+    ) -> Result<Self, Error> {
+        if !read_buffer.starts_with(&MAGIC) {
+            return Err(Error::InvalidMagic(codec::decode::take(read_buffer)?));
+        }
+        Ok(Self::decode(read_buffer)?)
     }
 }
 
@@ -130,12 +207,17 @@ pub struct ClientInfoPayload {
     pub version: u32,
     #[serde(rename = "meshKey")]
     pub meshkey: String,
+    /// Client accepts LZ4 compressed `ForwardPacket` payloads
+    #[serde(default)]
+    pub compression: bool,
 }
 
-#[derive(Clone, Decode, Encode)]
+#[derive(Clone, CodecDebug, Decode, Encode)]
 pub struct ClientInfo {
     pub public_key: PublicKey,
+    #[codec(hex_debug)]
     pub nonce: [u8; 24],
+    #[codec(hex_debug)]
     pub cipher_text: Vec<u8>,
 }
 
@@ -144,31 +226,39 @@ impl ClientInfo {
         secret_key: SecretKey,
         server_key: PublicKey,
         meshkey: Option<&str>,
-    ) -> anyhow::Result<Self> {
+        compression: bool,
+    ) -> Result<Self, Error> {
+        let payload = ClientInfoPayload {
+            version: PROTOCOL_VERSION,
+            meshkey: meshkey.unwrap_or_default().to_owned(),
+            compression,
+        };
+        Self::with_payload(secret_key, server_key, &payload)
+    }
+
+    fn with_payload(
+        secret_key: SecretKey,
+        server_key: PublicKey,
+        payload: &ClientInfoPayload,
+    ) -> Result<Self, Error> {
         let secret_key = secret_key.into();
         let public_key = BoxPublicKey::from(&secret_key);
         let server_key = server_key.into();
 
         let mut rng = rand_core::OsRng;
         let nonce = SalsaBox::generate_nonce(&mut rng);
-        let plain_text: Vec<u8> = if let Some(meshkey) = meshkey {
-            format!("{{\"version\": 2, \"meshKey\": \"{meshkey}\"}}")
-                .as_bytes()
-                .to_vec()
-        } else {
-            b"{\"version\": 2, \"meshKey\": \"\"}".to_vec()
-        };
+        let plain_text = serde_json::to_vec(payload)?;
 
         let b = SalsaBox::new(&server_key, &secret_key);
 
         let cipher_text = b
             .encrypt(&nonce, &plain_text[..])
-            .map_err(|e| anyhow::anyhow!("{e}"))?;
+            .map_err(|e| Error::CryptoError(e.to_string()))?;
 
         let nonce: [u8; 24] = nonce
             .to_vec()
             .try_into()
-            .map_err(|e| anyhow::anyhow!("{e:?}"))?;
+            .map_err(|e| Error::CryptoError(format!("{e:?}")))?;
 
         Ok(ClientInfo {
             public_key: public_key.into(),
@@ -177,14 +267,20 @@ impl ClientInfo {
         })
     }
 
-    pub fn complete(&self, sk: &SecretKey) -> anyhow::Result<CompleteClientInfo> {
+    pub fn complete(&self, sk: &SecretKey) -> Result<CompleteClientInfo, Error> {
         let b = SalsaBox::new(&self.public_key.into(), &sk.into());
-        let plain_text = b.decrypt(
-            self.nonce.clone().as_ref().try_into()?,
-            self.cipher_text.as_slice(),
-        )?;
-        let payload: ClientInfoPayload =
-            serde_json::from_slice(&plain_text).with_context(|| "Client info parsing")?;
+        let plain_text = b
+            .decrypt(&self.nonce.into(), self.cipher_text.as_slice())
+            .map_err(|e| Error::CryptoError(e.to_string()))?;
+        let payload: ClientInfoPayload = serde_json::from_slice(&plain_text)?;
+
+        if !SUPPORTED_VERSIONS.contains(&payload.version) {
+            warn!(
+                "Client {:?} uses unsupported protocol version {}",
+                self.public_key, payload.version
+            );
+            return Err(Error::InvalidVersion(payload.version));
+        }
 
         Ok(CompleteClientInfo {
             public_key: self.public_key,
@@ -194,10 +290,7 @@ impl ClientInfo {
     }
 
     pub fn frame(self) -> Frame<ClientInfo> {
-        Frame {
-            frame_type: FrameType::ClientInfo,
-            inner: SizeWrapper::new(self),
-        }
+        Frame::new(self)
     }
 }
 
@@ -207,18 +300,48 @@ pub struct CompleteClientInfo {
     pub payload: ClientInfoPayload,
 }
 
+/// Bitmask of optional protocol features supported by the server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Decode, Encode)]
+#[codec(transparent)]
+pub struct ServerCapabilities(pub u32);
+
+impl ServerCapabilities {
+    // Bit 0 is reserved for answering pings, which is not implemented yet
+    pub const MESH: Self = Self(1 << 1);
+    pub const FORWARD_TTL: Self = Self(1 << 2);
+    pub const COMPRESSION: Self = Self(1 << 3);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for ServerCapabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// 4B capabilities bitmask + (0+ bytes future use)
 #[derive(Decode, Encode, Default)]
 pub struct ServerInfo {
+    pub capabilities: ServerCapabilities,
     data: Vec<u8>,
 }
 
 impl ServerInfo {
+    pub fn new(capabilities: ServerCapabilities) -> Self {
+        ServerInfo {
+            capabilities,
+            data: Vec::new(),
+        }
+    }
+
     // This consume self
     pub fn frame(self) -> Frame<ServerInfo> {
-        Frame {
-            frame_type: FrameType::ServerInfo,
-            inner: SizeWrapper::new(self),
-        }
+        Frame::new(self)
     }
 }
 
@@ -230,45 +353,118 @@ pub struct SendPacket {
 
 #[derive(Debug, Decode, Encode)]
 pub struct RecvPacket {
-    pub target: PublicKey,
+    /// The peer that sent this packet
+    pub source: PublicKey,
     pub payload: Vec<u8>,
 }
 
-#[derive(Decode, Encode)]
+impl RecvPacket {
+    pub fn frame(self) -> Frame<RecvPacket> {
+        Frame::new(self)
+    }
+}
+
+#[derive(CodecDebug, CodecDisplay, Decode, Encode)]
+#[codec(display = "ForwardPacket({source} -> {target}, {} bytes)", source, target, payload.len())]
 pub struct ForwardPacket {
     pub source: PublicKey,
     pub target: PublicKey,
+    /// Remaining mesh hops, decremented by every node that receives this packet
+    pub ttl: u8,
+    /// Assigned by the server the source is connected to, used to drop duplicates
+    #[codec(option_discriminant)]
+    pub seq_no: Option<u32>,
+    #[codec(hex_debug)]
     pub payload: Vec<u8>,
 }
 
 impl ForwardPacket {
-    pub fn new(source: PublicKey, target: PublicKey, payload: Vec<u8>) -> Self {
+    /// Largest payload that still fits into a frame, with both keys, the ttl and a sequence
+    /// number
+    pub const MAX_PAYLOAD_SIZE: usize = MAX_TCP_PACKET_SIZE - 2 * KEY_SIZE - 1 - 5;
+
+    pub fn new(
+        source: PublicKey,
+        target: PublicKey,
+        ttl: u8,
+        seq_no: Option<u32>,
+        payload: Vec<u8>,
+    ) -> Self {
         ForwardPacket {
             source,
             target,
+            ttl,
+            seq_no,
             payload,
         }
     }
 
+    pub fn payload_len(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// Panics if the payload is longer than `MAX_PAYLOAD_SIZE`, the frame could not be read back.
     pub fn frame(self) -> Frame<ForwardPacket> {
-        Frame {
-            frame_type: FrameType::ForwardPacket,
-            inner: SizeWrapper::new(self),
-        }
+        assert!(
+            self.payload_len() <= Self::MAX_PAYLOAD_SIZE,
+            "Forward packet payload of {} bytes is over the limit of {}",
+            self.payload_len(),
+            Self::MAX_PAYLOAD_SIZE
+        );
+        Frame::new(self)
     }
 }
 
-#[derive(Debug, Decode, Encode)]
+/// Commands sent from the server to its clients, carried as JSON in a `ControlMessage` frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    /// Client should reconnect to a different DERP node
+    Redirect { addr: SocketAddr },
+    /// Server is about to close the connection
+    Disconnect { reason: String },
+}
+
+impl ControlMessage {
+    pub fn frame(&self) -> Result<Frame<RawControlMessage>, Error> {
+        Ok(Frame::new(RawControlMessage {
+            json: serde_json::to_vec(self)?,
+        }))
+    }
+}
+
+#[derive(Decode, Encode)]
+pub struct RawControlMessage {
+    pub json: Vec<u8>,
+}
+
+impl RawControlMessage {
+    pub fn parse(&self) -> Result<ControlMessage, Error> {
+        Ok(serde_json::from_slice(&self.json)?)
+    }
+}
+
+#[derive(Debug, Decode, Encode, EncodedSize)]
 pub struct PeerPresent {
     pub public_key: PublicKey,
 }
 
+#[derive(Debug, Decode, Encode, EncodedSize)]
+pub struct PeerGone {
+    pub public_key: PublicKey,
+}
+
+#[derive(Debug, Decode, Encode, EncodedSize)]
+pub struct ClosePeer {
+    pub public_key: PublicKey,
+}
+
 #[derive(Default, Decode, Encode)]
 pub struct WatchConns {
     pub data: Vec<u8>,
 }
 
-#[derive(Decode)]
+#[derive(Decode, EncodedSize)]
 pub struct Header {
     pub frame_type: FrameType,
     pub size: u32,
@@ -288,6 +484,7 @@ mod tests {
         let mut encoded_buf = Vec::new();
         server_key.clone().frame().encode(&mut encoded_buf).unwrap();
         assert_eq!(&encoded_buf, data);
+        assert_eq!(Frame::<ServerKey>::ENCODED_SIZE, data.len());
 
         let decoded_server_key = Frame::<ServerKey>::decode(&mut &data[..])
             .unwrap()
@@ -297,6 +494,38 @@ mod tests {
         assert_eq!(decoded_server_key.public_key, server_key.public_key);
     }
 
+    #[test]
+    fn server_key_magic_is_checked_first() {
+        let mut body = MAGIC.to_vec();
+        body.extend_from_slice(&[7; 32]);
+        let server_key = ServerKey::decode_validated(&mut body.as_slice()).unwrap();
+        assert_eq!(server_key.public_key, PublicKey::new([7; 32]));
+
+        body[0] = b'H';
+        assert!(matches!(
+            ServerKey::decode_validated(&mut body.as_slice()),
+            Err(Error::InvalidMagic(magic)) if magic[0] == b'H'
+        ));
+        // Without the magic the missing key is not even looked for
+        assert!(matches!(
+            ServerKey::decode_validated(&mut &body[..8]),
+            Err(Error::InvalidMagic(_))
+        ));
+    }
+
+    #[test]
+    fn test_frame_of_other_type() {
+        let mut data = vec![2];
+        data.extend_from_slice(&[0, 0, 0, 40]);
+        data.extend_from_slice(&MAGIC);
+        data.extend_from_slice(&[0; 32]);
+
+        assert!(matches!(
+            Frame::<ServerKey>::decode(&mut data.as_slice()),
+            Err(DecodeError::UnexpectedVariant(2))
+        ));
+    }
+
     #[test]
     fn test_client_info() {
         let data = &[
@@ -311,10 +540,7 @@ mod tests {
         };
 
         let mut encoded_buf = Vec::new();
-        let frame = Frame {
-            frame_type: FrameType::ClientInfo,
-            inner: SizeWrapper::new(client_info.clone()),
-        };
+        let frame = Frame::new(client_info.clone());
         frame.encode(&mut encoded_buf).unwrap();
         assert_eq!(&encoded_buf, data);
 
@@ -326,4 +552,162 @@ mod tests {
         assert_eq!(decoded_client_info.nonce, client_info.nonce);
         assert_eq!(decoded_client_info.cipher_text, client_info.cipher_text);
     }
+
+    #[test]
+    fn test_client_info_version() {
+        let server_sk = SecretKey::gen();
+        let client_sk = SecretKey::gen();
+
+        let client_info =
+            ClientInfo::new(client_sk, server_sk.public(), Some("mesh"), true).unwrap();
+        let complete_info = client_info.complete(&server_sk).unwrap();
+        assert_eq!(complete_info.public_key, client_sk.public());
+        assert_eq!(
+            complete_info.payload,
+            ClientInfoPayload {
+                version: PROTOCOL_VERSION,
+                meshkey: "mesh".to_owned(),
+                compression: true,
+            }
+        );
+
+        let payload = ClientInfoPayload {
+            version: 99,
+            meshkey: String::new(),
+            compression: false,
+        };
+        let client_info =
+            ClientInfo::with_payload(client_sk, server_sk.public(), &payload).unwrap();
+        assert!(matches!(
+            client_info.complete(&server_sk),
+            Err(Error::InvalidVersion(99))
+        ));
+    }
+
+    #[test]
+    fn test_server_info_capabilities() {
+        let data = &[3, 0, 0, 0, 4, 0, 0, 0, 6];
+        let capabilities = ServerCapabilities::MESH | ServerCapabilities::FORWARD_TTL;
+
+        let mut encoded_buf = Vec::new();
+        ServerInfo::new(capabilities)
+            .frame()
+            .encode(&mut encoded_buf)
+            .unwrap();
+        assert_eq!(&encoded_buf, data);
+
+        let decoded_capabilities = Frame::<ServerInfo>::decode(&mut &data[..])
+            .unwrap()
+            .inner
+            .into_inner()
+            .capabilities;
+        assert_eq!(decoded_capabilities, capabilities);
+        assert!(decoded_capabilities.contains(ServerCapabilities::MESH));
+        assert!(!decoded_capabilities.contains(ServerCapabilities::COMPRESSION));
+    }
+
+    #[test]
+    fn test_recv_packet_frame() {
+        let data = &[
+            5, 0, 0, 0, 35, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+            1, 1, 1, 1, 1, 1, 1, 1, 7, 8, 9,
+        ];
+        let recv_packet = RecvPacket {
+            source: PublicKey::new([1; 32]),
+            payload: vec![7, 8, 9],
+        };
+
+        let mut encoded_buf = Vec::new();
+        recv_packet.frame().encode(&mut encoded_buf).unwrap();
+        assert_eq!(&encoded_buf, data);
+
+        let decoded_recv_packet = Frame::<RecvPacket>::decode(&mut &data[..])
+            .unwrap()
+            .inner
+            .into_inner();
+        assert_eq!(decoded_recv_packet.source, PublicKey::new([1; 32]));
+        assert_eq!(decoded_recv_packet.payload, vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn test_peer_gone_frame() {
+        let peer_gone = Frame::new(PeerGone {
+            public_key: PublicKey::new([3; 32]),
+        });
+
+        let mut encoded_buf = Vec::new();
+        peer_gone.encode(&mut encoded_buf).unwrap();
+        assert_eq!(encoded_buf[..5], [8, 0, 0, 0, 32]);
+        assert_eq!(encoded_buf[5..], [3; 32]);
+
+        let decoded_peer_gone = Frame::<PeerGone>::decode(&mut &encoded_buf[..])
+            .unwrap()
+            .inner
+            .into_inner();
+        assert_eq!(decoded_peer_gone.public_key, PublicKey::new([3; 32]));
+    }
+
+    #[test]
+    fn test_control_message() {
+        let message = ControlMessage::Redirect {
+            addr: "10.0.0.1:8765".parse().unwrap(),
+        };
+
+        let mut encoded_buf = Vec::new();
+        message.frame().unwrap().encode(&mut encoded_buf).unwrap();
+        assert_eq!(encoded_buf[0], 0x14);
+        assert_eq!(
+            &encoded_buf[5..],
+            br#"{"type":"redirect","addr":"10.0.0.1:8765"}"#
+        );
+
+        let decoded_message = Frame::<RawControlMessage>::decode(&mut &encoded_buf[..])
+            .unwrap()
+            .inner
+            .into_inner()
+            .parse()
+            .unwrap();
+        assert_eq!(decoded_message, message);
+    }
+
+    #[test]
+    #[should_panic(expected = "Forward packet payload of 65466 bytes is over the limit of 65465")]
+    fn test_forward_packet_too_big() {
+        let payload = vec![0; ForwardPacket::MAX_PAYLOAD_SIZE + 1];
+        ForwardPacket::new(
+            PublicKey::new([1; 32]),
+            PublicKey::new([2; 32]),
+            DEFAULT_FORWARD_TTL,
+            Some(0x0102),
+            payload,
+        )
+        .frame();
+    }
+
+    #[test]
+    fn test_forward_packet_ttl() {
+        let forward_packet = ForwardPacket::new(
+            PublicKey::new([1; 32]),
+            PublicKey::new([2; 32]),
+            DEFAULT_FORWARD_TTL,
+            Some(0x0102),
+            vec![0xA, 0xB],
+        );
+
+        let mut encoded_buf = Vec::new();
+        forward_packet.frame().encode(&mut encoded_buf).unwrap();
+        assert_eq!(encoded_buf[..5], [0x0A, 0, 0, 0, 72]);
+        assert_eq!(
+            encoded_buf[69..],
+            [DEFAULT_FORWARD_TTL, 1, 0, 0, 1, 2, 0xA, 0xB]
+        );
+
+        let decoded_forward_packet = Frame::<ForwardPacket>::decode(&mut &encoded_buf[..])
+            .unwrap()
+            .inner
+            .into_inner();
+        assert_eq!(decoded_forward_packet.ttl, DEFAULT_FORWARD_TTL);
+        assert_eq!(decoded_forward_packet.seq_no, Some(0x0102));
+        assert_eq!(decoded_forward_packet.payload, vec![0xA, 0xB]);
+    }
 }
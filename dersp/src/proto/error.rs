@@ -0,0 +1,53 @@
+use std::convert::Infallible;
+
+use codec::decode::DecodeError;
+
+use super::data::FrameType;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors returned while reading, writing or validating DERP frames.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Unexpected frame type: expected {expected:?}, got {got:?}")]
+    UnexpectedFrameType { expected: FrameType, got: FrameType },
+    #[error("Decode error: {0:?}")]
+    DecodeError(DecodeError),
+    #[error("Invalid magic {0:?}")]
+    InvalidMagic([u8; 8]),
+    #[error("Unsupported client protocol version {0}")]
+    InvalidVersion(u32),
+    #[error("Crypto error: {0}")]
+    CryptoError(String),
+    #[error("Frame too big: {0}")]
+    FrameTooBig(usize),
+    #[error("Input buffer full, more than {0} bytes of incomplete frames")]
+    InputBufferFull(usize),
+    #[error("Invalid HTTP upgrade request: {0}")]
+    InvalidUpgrade(String),
+    #[error(transparent)]
+    Http(#[from] httparse::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "quic-transport")]
+    #[error(transparent)]
+    Quic(#[from] quinn::ConnectionError),
+    #[cfg(feature = "h2-transport")]
+    #[error(transparent)]
+    H2(#[from] h2::Error),
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::DecodeError(e)
+    }
+}
+
+impl From<Infallible> for Error {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
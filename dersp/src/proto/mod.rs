@@ -1,192 +1,325 @@
 use self::data::{
-    ClientInfo, ForwardPacket, Frame, FrameType, PeerPresent, ServerInfo, ServerKey, WatchConns,
+    ClientInfo, ControlMessage, ForwardPacket, Frame, FrameType, Header, PeerGone, PeerPresent,
+    RecvPacket, ServerCapabilities, ServerInfo, ServerKey, WatchConns,
 };
 
 use crate::{
     crypto::{PublicKey, SecretKey},
-    inout::DerpReader,
+    inout::{DerpReader, HEADER_SIZE, MAX_TCP_PACKET_SIZE},
 };
-use anyhow::{anyhow, ensure};
-use codec::{Decode, Encode, SizeWrapper};
+use codec::{encode::scratch::ScratchBuffer, Decode, Encode, EncodedSize};
 use log::debug;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub mod data;
+mod error;
+
+pub use error::{Error, Result};
+
+impl<T: Encode> Frame<T> {
+    /// Encode the frame into the scratch buffer and write it to `writer` in one go.
+    pub async fn write_all<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        let mut buf = ScratchBuffer::take();
+        self.encode(&mut *buf)?;
+        Ok(writer.write_all(&buf).await?)
+    }
+}
+
 const UPGRADE_MSG_SIZE: usize = 4096;
 
+/// What the server learns about a client during the handshake
+#[derive(Debug)]
+pub struct ClientHandshake {
+    pub public_key: PublicKey,
+    pub meshkey: Option<String>,
+    /// Client accepts LZ4 compressed `ForwardPacket` payloads
+    pub compression: bool,
+}
+
+/// How the client asked to upgrade its HTTP connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upgrade {
+    /// DERP frames follow the HTTP response directly
+    Derp,
+    /// A real WebSocket handshake, DERP frames are carried in binary WebSocket messages
+    #[cfg(feature = "websocket")]
+    WebSocket,
+}
+
+/// Do the DERP handshake with a client, once its HTTP connection was upgraded.
 pub async fn handle_handshake<RW: AsyncWrite + AsyncRead + Unpin>(
     mut rw: &mut RW,
     sk: &SecretKey,
-) -> anyhow::Result<(PublicKey, Option<String>)> {
-    finalize_http_phase(&mut rw).await?;
-
+    capabilities: ServerCapabilities,
+) -> Result<ClientHandshake> {
     write_server_key(&mut rw, &sk).await?;
 
-    let (pk, meshkey) = read_client_info(&mut rw, &sk).await?;
+    let client = read_client_info(&mut rw, &sk).await?;
 
-    write_server_info(&mut rw).await?;
+    write_server_info(&mut rw, capabilities).await?;
 
-    Ok((pk, meshkey))
+    Ok(client)
 }
 
-async fn finalize_http_phase<RW: AsyncWrite + AsyncRead + Unpin>(
+/// Read the client's HTTP upgrade request and answer it.
+///
+/// Clients asking for `Upgrade: websocket` without a `Sec-WebSocket-Key` still get raw DERP
+/// frames, as do all clients when the `websocket` feature is disabled.
+pub async fn finalize_http_phase<RW: AsyncWrite + AsyncRead + Unpin>(
     rw: &mut RW,
-) -> anyhow::Result<()> {
+) -> Result<Upgrade> {
     let mut buf = [0u8; UPGRADE_MSG_SIZE];
-    let n = rw.read(&mut buf).await?; // TODO: timeout
-    ensure!(n > 0, "empty initiall message");
-    ensure!(n < UPGRADE_MSG_SIZE, "initial message too big");
+    let n = rw.read(&mut buf).await?;
+    if n == 0 {
+        return Err(Error::InvalidUpgrade("empty initiall message".to_owned()));
+    }
+    if n >= UPGRADE_MSG_SIZE {
+        return Err(Error::InvalidUpgrade("initial message too big".to_owned()));
+    }
 
     let mut headers = [httparse::EMPTY_HEADER; 16];
     let mut req = httparse::Request::new(&mut headers);
     let body_start = req.parse(&buf)?; // TODO: add context
-    ensure!(body_start.is_complete());
-    validate_headers(&headers)?;
+    if body_start.is_partial() {
+        return Err(Error::InvalidUpgrade("incomplete request".to_owned()));
+    }
+    let websocket_key = validate_headers(&headers)?;
     let body_start = body_start.unwrap();
     let _body = &buf[body_start..];
     // TODO: do something with body?
-    rw.write(b"HTTP/1.1 200 OK\r\n\r\n").await?;
 
-    Ok(())
+    #[cfg(feature = "websocket")]
+    if let Some(key) = websocket_key {
+        let accept = tokio_tungstenite::tungstenite::handshake::derive_accept_key(key.as_bytes());
+        rw.write_all(
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Accept: {accept}\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await?;
+        return Ok(Upgrade::WebSocket);
+    }
+    #[cfg(not(feature = "websocket"))]
+    let _ = websocket_key;
+
+    rw.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await?;
+
+    Ok(Upgrade::Derp)
 }
 
-fn validate_headers(headers: &[httparse::Header]) -> anyhow::Result<()> {
+/// Validate the upgrade headers, returning the `Sec-WebSocket-Key` of a WebSocket upgrade.
+fn validate_headers(headers: &[httparse::Header]) -> Result<Option<String>> {
+    let mut websocket = false;
+    let mut websocket_key = None;
     for h in headers {
         if h.name == "Upgrade" {
-            let value = std::str::from_utf8(h.value)?.to_ascii_lowercase();
-            ensure!(
-                value == "websocket" || value == "derp",
-                "Unexpected Upgrade value {value}"
-            );
+            let value = header_value(h)?;
+            if value != "websocket" && value != "derp" {
+                return Err(Error::InvalidUpgrade(format!(
+                    "Unexpected Upgrade value {value}"
+                )));
+            }
+            websocket = value == "websocket";
         }
 
         if h.name == "Connection" {
-            let value = std::str::from_utf8(h.value)?.to_ascii_lowercase();
-            ensure!(value == "upgrade", "Unexpected Connection value {value}");
+            let value = header_value(h)?;
+            // Browsers may send e.g. `keep-alive, Upgrade`
+            if !value.split(',').any(|token| token.trim() == "upgrade") {
+                return Err(Error::InvalidUpgrade(format!(
+                    "Unexpected Connection value {value}"
+                )));
+            }
+        }
+
+        if h.name.eq_ignore_ascii_case("Sec-WebSocket-Key") {
+            // The key is case sensitive, unlike the values compared above
+            websocket_key = Some(
+                std::str::from_utf8(h.value)
+                    .map_err(|e| Error::InvalidUpgrade(format!("{} header: {e}", h.name)))?
+                    .to_owned(),
+            );
         }
     }
 
-    Ok(())
+    Ok(websocket_key.filter(|_| websocket))
+}
+
+fn header_value(header: &httparse::Header) -> Result<String> {
+    std::str::from_utf8(header.value)
+        .map(str::to_ascii_lowercase)
+        .map_err(|e| Error::InvalidUpgrade(format!("{} header: {e}", header.name)))
 }
 
 async fn write_server_key<W: AsyncWrite + Unpin>(
     writer: &mut W,
     secret_key: &SecretKey,
-) -> anyhow::Result<()> {
-    let server_key = ServerKey::new(secret_key.public());
-    let mut buf = Vec::new();
-    server_key.frame().encode(&mut buf)?;
-    writer.write_all(&buf).await.map_err(|e| anyhow!("{}", e))
+) -> Result<()> {
+    ServerKey::new(secret_key.public())
+        .frame()
+        .write_all(writer)
+        .await
 }
 
-async fn read_server_key<R: AsyncRead + Unpin>(
-    reader: &mut DerpReader<R>,
-) -> anyhow::Result<PublicKey> {
+async fn read_server_key<R: AsyncRead + Unpin>(reader: &mut DerpReader<R>) -> Result<PublicKey> {
     let message = reader.get_next_message().await?;
 
+    // The frame may carry bytes for future use after the public key, so only the known prefix
+    // of the body is decoded.
     let server_key = match message.ty {
-        FrameType::ServerKey => Frame::<ServerKey>::decode(&mut message.buffer.as_slice())
-            .map_err(|_| anyhow!("Decode error"))?
-            .inner
-            .into_inner(),
-        ty => anyhow::bail!("Unexpected message: {ty:?}"),
+        FrameType::ServerKey => ServerKey::decode_validated(&mut &message.body[..])?,
+        got => {
+            return Err(Error::UnexpectedFrameType {
+                expected: FrameType::ServerKey,
+                got,
+            })
+        }
     };
 
-    server_key.validate_magic()?;
-
     Ok(server_key.public_key)
 }
 
+/// Reads exactly one frame, header included, without consuming any bytes that follow it.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut buf = vec![0; HEADER_SIZE];
+    reader.read_exact(&mut buf).await?;
+    let header = Header::decode(&mut buf.as_slice())?;
+
+    let size = header.size as usize;
+    if size > MAX_TCP_PACKET_SIZE {
+        return Err(Error::FrameTooBig(size));
+    }
+    buf.resize(HEADER_SIZE + size, 0);
+    reader.read_exact(&mut buf[HEADER_SIZE..]).await?;
+
+    Ok(buf)
+}
+
 async fn read_client_info<R: AsyncRead + Unpin>(
     reader: &mut R,
     sk: &SecretKey,
-) -> anyhow::Result<(PublicKey, Option<String>)> {
-    // TODO use only one prealocated buffer for read / write
-    let mut buf = [0; 1024];
-    reader.read(&mut buf).await?;
+) -> Result<ClientHandshake> {
+    let buf = read_frame(reader).await?;
 
     let client_info = match FrameType::get_frame_type(&buf) {
-        FrameType::ClientInfo => {
-            Frame::<ClientInfo>::decode(&mut buf.as_slice()).map_err(|_| anyhow!("Decode error"))
+        FrameType::ClientInfo => Frame::<ClientInfo>::decode(&mut buf.as_slice())?,
+        got => {
+            return Err(Error::UnexpectedFrameType {
+                expected: FrameType::ClientInfo,
+                got,
+            })
         }
-        ty => anyhow::bail!("Unexpected message: {ty:?}"),
-    }?;
-    let client_info = client_info.inner.into_inner();
-    debug!("Client public key: {:?}", client_info.public_key);
-
-    let complete_info = client_info.complete(sk)?;
+    };
+    let complete_info = client_info
+        .inner
+        .try_map(|client_info| {
+            debug!("Client public key: {:?}", client_info.public_key);
+            client_info.complete(sk)
+        })?
+        .into_inner();
 
     debug!("client info: {:?}", complete_info.payload);
 
-    Ok((
-        complete_info.public_key,
-        if complete_info.payload.meshkey.is_empty() {
+    Ok(ClientHandshake {
+        public_key: complete_info.public_key,
+        meshkey: if complete_info.payload.meshkey.is_empty() {
             None
         } else {
             Some(complete_info.payload.meshkey)
         },
-    ))
+        compression: complete_info.payload.compression,
+    })
 }
 
 async fn write_client_info<W: AsyncWrite + Unpin>(
     writer: &mut W,
     client_info: ClientInfo,
-) -> anyhow::Result<()> {
-    let mut buf = Vec::new();
-    client_info.frame().encode(&mut buf)?;
-    writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
+) -> Result<()> {
+    client_info.frame().write_all(writer).await
 }
 
-async fn write_server_info<W: AsyncWrite + Unpin>(writer: &mut W) -> anyhow::Result<()> {
-    let mut buf = Vec::new();
-    ServerInfo::default().frame().encode(&mut buf)?;
-    writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
+async fn write_server_info<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    capabilities: ServerCapabilities,
+) -> Result<()> {
+    ServerInfo::new(capabilities)
+        .frame()
+        .write_all(writer)
+        .await
 }
 
 pub async fn read_server_info<R: AsyncRead + Unpin>(
     derp_reader: &mut DerpReader<R>,
-) -> anyhow::Result<()> {
+) -> Result<ServerCapabilities> {
     let message = derp_reader.get_next_message().await?;
 
-    if message.ty != FrameType::ServerInfo {
-        Err(anyhow::anyhow!("Invalid frame type {:?}", message.ty))
-    } else {
-        Ok(())
-    }
+    let server_info = match message.ty {
+        FrameType::ServerInfo => message.decode_body::<ServerInfo>()?,
+        got => {
+            return Err(Error::UnexpectedFrameType {
+                expected: FrameType::ServerInfo,
+                got,
+            })
+        }
+    };
+
+    Ok(server_info.capabilities)
 }
 
 pub async fn write_peer_present<W: AsyncWrite + Unpin>(
     writer: &mut W,
     public_key: &PublicKey,
-) -> anyhow::Result<()> {
-    let mut buf = Vec::new();
-    let peer_present = Frame {
-        frame_type: data::FrameType::PeerPresent,
-        inner: SizeWrapper::new(PeerPresent {
-            public_key: *public_key,
-        }),
-    };
-    peer_present.encode(&mut buf)?;
-    writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
+) -> Result<()> {
+    let (buf, len) = Frame::new(PeerPresent {
+        public_key: *public_key,
+    })
+    .encode_into_array::<{ Frame::<PeerPresent>::ENCODED_SIZE }>()
+    .expect("peer present frames have a fixed size");
+    Ok(writer.write_all(&buf[..len]).await?)
+}
+
+pub async fn write_peer_gone<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    public_key: &PublicKey,
+) -> Result<()> {
+    let (buf, len) = Frame::new(PeerGone {
+        public_key: *public_key,
+    })
+    .encode_into_array::<{ Frame::<PeerGone>::ENCODED_SIZE }>()
+    .expect("peer gone frames have a fixed size");
+    Ok(writer.write_all(&buf[..len]).await?)
+}
+
+pub async fn write_recv_packet<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    recv_packet: RecvPacket,
+) -> Result<()> {
+    recv_packet.frame().write_all(writer).await
 }
 
 pub async fn write_forward_packet<W: AsyncWrite + Unpin>(
     writer: &mut W,
     forward_packet: ForwardPacket,
-) -> anyhow::Result<()> {
-    let mut buf = Vec::new();
-    forward_packet.frame().encode(&mut buf)?;
-    writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
+) -> Result<()> {
+    forward_packet.frame().write_all(writer).await
 }
 
-pub async fn write_watch_conns<W: AsyncWrite + Unpin>(writer: &mut W) -> anyhow::Result<()> {
-    let mut buf = Vec::new();
-    let frame = Frame {
-        frame_type: FrameType::WatchConns,
-        inner: SizeWrapper::new(WatchConns::default()),
-    };
-    frame.encode(&mut buf)?;
-    writer.write_all(&buf).await.map_err(|e| anyhow!("{e}"))
+pub async fn write_control_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    control_message: &ControlMessage,
+) -> Result<()> {
+    control_message.frame()?.write_all(writer).await
+}
+
+pub async fn write_watch_conns<W: AsyncWrite + Unpin>(writer: &mut W) -> Result<()> {
+    let (buf, len) = Frame::new(WatchConns::default())
+        .encode_into_array::<HEADER_SIZE>()
+        .expect("watch conns frames have no payload");
+    Ok(writer.write_all(&buf[..len]).await?)
 }
 
 /// Reads the server key and sends the initiation message via a writer to the DERP server
@@ -199,10 +332,56 @@ pub async fn exchange_keys<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
     mut writer: W,
     secret_key: SecretKey,
     meshkey: Option<&str>,
-) -> anyhow::Result<PublicKey> {
+    compression: bool,
+) -> Result<PublicKey> {
     let server_key = read_server_key(reader).await?;
     debug!("server key: {server_key}");
-    let client_info = ClientInfo::new(secret_key, server_key, meshkey)?;
+    let client_info = ClientInfo::new(secret_key, server_key, meshkey, compression)?;
     write_client_info(&mut writer, client_info).await?;
     Ok(server_key)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_server_key_with_future_use_bytes() {
+        let server_pk = SecretKey::gen().public();
+
+        let mut buf = Vec::new();
+        ServerKey::new(server_pk).frame().encode(&mut buf).unwrap();
+        buf[4] += 3;
+        buf.extend_from_slice(&[1, 2, 3]);
+        write_watch_conns(&mut buf).await.unwrap();
+
+        let mut reader = DerpReader::new(buf.as_slice());
+        assert_eq!(read_server_key(&mut reader).await.unwrap(), server_pk);
+        assert_eq!(
+            reader.get_next_message().await.unwrap().ty,
+            FrameType::WatchConns
+        );
+    }
+
+    #[tokio::test]
+    async fn read_client_info_bigger_than_1024_bytes() {
+        let server_sk = SecretKey::gen();
+        let client_sk = SecretKey::gen();
+        let meshkey = "k".repeat(2000);
+
+        let mut buf = Vec::new();
+        ClientInfo::new(client_sk, server_sk.public(), Some(&meshkey), false)
+            .unwrap()
+            .frame()
+            .encode(&mut buf)
+            .unwrap();
+        assert!(buf.len() > 1024);
+        buf.extend_from_slice(b"next frame");
+
+        let mut reader = buf.as_slice();
+        let client = read_client_info(&mut reader, &server_sk).await.unwrap();
+        assert_eq!(client.public_key, client_sk.public());
+        assert_eq!(client.meshkey, Some(meshkey));
+        assert_eq!(reader, b"next frame");
+    }
+}
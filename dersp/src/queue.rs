@@ -0,0 +1,120 @@
+use std::sync::{Arc, Weak};
+use tokio::sync::{
+    mpsc::{
+        channel,
+        error::{SendError, TrySendError},
+        Receiver, Sender,
+    },
+    Mutex,
+};
+
+/// Sending half of a bounded FIFO queue that can make room for new entries by dropping the
+/// oldest ones, so that a slow consumer never blocks its producers.
+#[derive(Debug)]
+pub struct BoundedMpsc<T> {
+    sender: Sender<T>,
+    // Weak, so that dropping the `BoundedMpscReceiver` still closes the channel
+    receiver: Weak<Mutex<Receiver<T>>>,
+}
+
+#[derive(Debug)]
+pub struct BoundedMpscReceiver<T> {
+    receiver: Arc<Mutex<Receiver<T>>>,
+}
+
+impl<T> BoundedMpsc<T> {
+    pub fn channel(capacity: usize) -> (Self, BoundedMpscReceiver<T>) {
+        let (sender, receiver) = channel(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        (
+            Self {
+                sender,
+                receiver: Arc::downgrade(&receiver),
+            },
+            BoundedMpscReceiver { receiver },
+        )
+    }
+
+    /// Wait for free space in the queue and enqueue `value`.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.sender.send(value).await
+    }
+
+    /// Enqueue `value` if there is free space in the queue.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.sender.try_send(value)
+    }
+
+    /// Enqueue `value` without waiting, dropping the oldest entries if the queue is full.
+    /// Returns the number of entries dropped.
+    pub async fn force_send_dropping_oldest(&self, mut value: T) -> Result<u64, SendError<T>> {
+        let mut dropped = 0;
+        loop {
+            match self.sender.try_send(value) {
+                Ok(()) => return Ok(dropped),
+                Err(TrySendError::Closed(v)) => return Err(SendError(v)),
+                Err(TrySendError::Full(v)) => {
+                    value = v;
+                    let Some(receiver) = self.receiver.upgrade() else {
+                        return Err(SendError(value));
+                    };
+                    if receiver.lock().await.try_recv().is_ok() {
+                        dropped += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether all queued entries were already received.
+    pub fn is_empty(&self) -> bool {
+        self.sender.capacity() == self.sender.max_capacity()
+    }
+
+    /// Whether both handles send to the same queue.
+    pub fn same_channel(&self, other: &Self) -> bool {
+        self.sender.same_channel(&other.sender)
+    }
+}
+
+impl<T> Clone for BoundedMpsc<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            receiver: self.receiver.clone(),
+        }
+    }
+}
+
+impl<T> BoundedMpscReceiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        self.receiver.lock().await.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn force_send_drops_oldest() {
+        let (s, mut r) = BoundedMpsc::channel(2);
+        for i in 0..2 {
+            assert_eq!(s.force_send_dropping_oldest(i).await.unwrap(), 0);
+        }
+        for i in 2..5 {
+            assert_eq!(s.force_send_dropping_oldest(i).await.unwrap(), 1);
+        }
+        assert_eq!(r.recv().await, Some(3));
+        assert_eq!(r.recv().await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn force_send_fails_without_receiver() {
+        let (s, r) = BoundedMpsc::channel(1);
+        s.force_send_dropping_oldest(1).await.unwrap();
+        drop(r);
+        assert!(s.force_send_dropping_oldest(2).await.is_err());
+        assert!(s.send(3).await.is_err());
+    }
+}
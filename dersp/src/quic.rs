@@ -0,0 +1,99 @@
+//! DERP over QUIC, without the HTTP upgrade.
+//!
+//! The server opens one bidirectional stream on every accepted connection and does the usual
+//! DERP handshake and frame exchange on it.
+use crate::{
+    connection::Connection,
+    proto,
+    service::{handle_client, DerpService},
+};
+use log::warn;
+use quinn::{Connecting, Endpoint, ServerConfig};
+use rustls::{Certificate, PrivateKey};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{spawn, sync::RwLock};
+
+/// Server name in the self-signed certificate of the endpoint
+pub const SERVER_NAME: &str = "dersp";
+
+/// Bind a QUIC endpoint with a freshly generated self-signed certificate.
+///
+/// Like with DERP over TCP, clients learn the server key in the DERP handshake, the certificate
+/// carries no identity worth verifying.
+pub fn bind(addr: SocketAddr) -> anyhow::Result<Endpoint> {
+    let (config, _) = server_config()?;
+    Ok(Endpoint::server(config, addr)?)
+}
+
+fn server_config() -> anyhow::Result<(ServerConfig, Certificate)> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])?;
+    let der = Certificate(cert.serialize_der()?);
+    let key = PrivateKey(cert.serialize_private_key_der());
+    Ok((ServerConfig::with_single_cert(vec![der.clone()], key)?, der))
+}
+
+/// Accept QUIC connections until the endpoint is closed.
+pub async fn run(endpoint: Endpoint, service: Arc<RwLock<DerpService>>) {
+    while let Some(connecting) = endpoint.accept().await {
+        let peer_addr = connecting.remote_address();
+        let id = service.read().await.next_connection_id();
+        let service = service.clone();
+        spawn(async move {
+            if let Err(e) = handle_client(accept(connecting), id, peer_addr, service).await {
+                warn!("[{id}] QUIC client {peer_addr:?} failed: {e:?}");
+            }
+        });
+    }
+}
+
+async fn accept(connecting: Connecting) -> proto::Result<Connection> {
+    let connection = connecting.await?;
+    // Opening the stream is only announced to the client with the first bytes sent on it,
+    // which the DERP server sends first anyway
+    let (send, recv) = connection.open_bi().await?;
+    Ok(Connection {
+        peer: connection.remote_address(),
+        reader: Box::new(recv),
+        writer: Box::new(send),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::ClientConfig;
+    use rustls::RootCertStore;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn frames_on_server_opened_stream() {
+        let (config, cert) = server_config().unwrap();
+        let server = Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&cert).unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+        let connecting = client
+            .connect(server.local_addr().unwrap(), SERVER_NAME)
+            .unwrap();
+
+        let (accepted, connected) = tokio::join!(
+            async { accept(server.accept().await.unwrap()).await },
+            connecting
+        );
+        let mut connection = accepted.unwrap();
+        let connected = connected.unwrap();
+        assert_eq!(connection.peer, client.local_addr().unwrap());
+
+        connection.write_all(&[1, 2, 3]).await.unwrap();
+        let (mut send, mut recv) = connected.accept_bi().await.unwrap();
+        let mut buf = [0; 3];
+        recv.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        send.write_all(&[4, 5, 6]).await.unwrap();
+        connection.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [4, 5, 6]);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{crypto::PublicKey, inout::MAX_TCP_PACKET_SIZE};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Token bucket holding up to one second worth of bytes, but always enough for the largest
+/// packet.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_used: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, now: Instant) -> Self {
+        Self {
+            tokens: capacity,
+            last_used: now,
+        }
+    }
+
+    fn try_consume(
+        &mut self,
+        bytes: usize,
+        bytes_per_sec: u64,
+        capacity: f64,
+        now: Instant,
+    ) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_used).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * bytes_per_sec as f64).min(capacity);
+        self.last_used = now;
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+}
+
+/// Limits the bytes relayed between every `(source, target)` pair of peers.
+#[derive(Debug)]
+pub struct PairRateLimiter {
+    bytes_per_sec: u64,
+    /// Bytes a bucket holds when full, a packet bigger than that could never pass
+    capacity: f64,
+    buckets: HashMap<(PublicKey, PublicKey), TokenBucket>,
+}
+
+impl PairRateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            capacity: bytes_per_sec.max(MAX_TCP_PACKET_SIZE as u64) as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take `bytes` from the pair's bucket, returning `false` if the packet should be dropped.
+    pub fn allow(
+        &mut self,
+        source: PublicKey,
+        target: PublicKey,
+        bytes: usize,
+        now: Instant,
+    ) -> bool {
+        let (bytes_per_sec, capacity) = (self.bytes_per_sec, self.capacity);
+        self.buckets
+            .entry((source, target))
+            .or_insert_with(|| TokenBucket::new(capacity, now))
+            .try_consume(bytes, bytes_per_sec, capacity, now)
+    }
+
+    /// Forget buckets unused for long enough to be full again.
+    pub fn evict_idle(&mut self, now: Instant) {
+        let refill = Duration::try_from_secs_f64(self.capacity / self.bytes_per_sec as f64)
+            .unwrap_or(Duration::MAX);
+        self.buckets
+            .retain(|_, bucket| now.saturating_duration_since(bucket.last_used) < refill);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_each_pair_separately() {
+        let mut limiter = PairRateLimiter::new(100_000);
+        let (a, b, c) = (
+            PublicKey::new([1; 32]),
+            PublicKey::new([2; 32]),
+            PublicKey::new([3; 32]),
+        );
+        let now = Instant::now();
+
+        assert!(limiter.allow(a, b, 60_000, now));
+        assert!(!limiter.allow(a, b, 60_000, now));
+        assert!(limiter.allow(a, c, 60_000, now));
+        assert!(limiter.allow(b, a, 60_000, now));
+        assert!(limiter.allow(a, b, 50_000, now + Duration::from_millis(100)));
+
+        limiter.evict_idle(now + Duration::from_millis(1050));
+        assert_eq!(limiter.buckets.len(), 1);
+        limiter.evict_idle(now + Duration::from_secs(2));
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn largest_packet_passes_low_limits() {
+        let mut limiter = PairRateLimiter::new(1000);
+        let (a, b) = (PublicKey::new([1; 32]), PublicKey::new([2; 32]));
+        let now = Instant::now();
+
+        assert!(limiter.allow(a, b, MAX_TCP_PACKET_SIZE, now));
+        assert!(!limiter.allow(a, b, 1000, now + Duration::from_millis(900)));
+        assert!(limiter.allow(a, b, 1000, now + Duration::from_secs(1)));
+
+        // Refilling the bucket takes longer than a second
+        limiter.evict_idle(now + Duration::from_secs(60));
+        assert_eq!(limiter.buckets.len(), 1);
+        limiter.evict_idle(now + Duration::from_secs(67));
+        assert!(limiter.buckets.is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::crypto::PublicKey;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Max number of packets held back for a single source, more of them skip the gap right away
+pub const MAX_HELD_PER_SOURCE: usize = 64;
+
+/// Packets held back for one source, waiting for the ones before them.
+#[derive(Debug)]
+struct SourceQueue<T> {
+    /// Sequence number of the next packet to deliver
+    next: u64,
+    held: BTreeMap<u64, (Instant, T)>,
+}
+
+impl<T> SourceQueue<T> {
+    /// Move the contiguous run of held packets starting at `next` to `ready`.
+    fn release(&mut self, ready: &mut Vec<T>) {
+        while let Some((_, packet)) = self.held.remove(&self.next) {
+            ready.push(packet);
+            self.next += 1;
+        }
+    }
+
+    /// Give up on the gap before the oldest held packet.
+    fn skip_gap(&mut self, ready: &mut Vec<T>) {
+        if let Some(&first) = self.held.keys().next() {
+            self.next = first;
+            self.release(ready);
+        }
+    }
+
+    fn oldest_arrival(&self) -> Option<Instant> {
+        self.held.values().map(|(arrived_at, _)| *arrived_at).min()
+    }
+}
+
+/// Restores the order of packets that carry a per `(source, target)` sequence number, for a
+/// single target.
+///
+/// Packets after a gap are held for up to `timeout`, then the gap is skipped. Packets arriving
+/// after their gap was skipped are delivered right away instead of being dropped.
+#[derive(Debug)]
+pub struct ReorderBuffer<T> {
+    timeout: Duration,
+    sources: HashMap<PublicKey, SourceQueue<T>>,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Accept a packet, returning the packets which are now ready for delivery, in order.
+    pub fn push(&mut self, source: PublicKey, seq: u64, packet: T, now: Instant) -> Vec<T> {
+        // The first packet seen from a source sets where its sequence starts
+        let queue = self.sources.entry(source).or_insert_with(|| SourceQueue {
+            next: seq,
+            held: BTreeMap::new(),
+        });
+        if seq < queue.next {
+            return vec![packet];
+        }
+        queue.held.insert(seq, (now, packet));
+        let mut ready = Vec::new();
+        queue.release(&mut ready);
+        if queue.held.len() > MAX_HELD_PER_SOURCE {
+            queue.skip_gap(&mut ready);
+        }
+        ready
+    }
+
+    /// Skip the gaps waited on for longer than the timeout, returning the released packets.
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let mut ready = Vec::new();
+        for queue in self.sources.values_mut() {
+            while let Some(arrived_at) = queue.oldest_arrival() {
+                if now.saturating_duration_since(arrived_at) < self.timeout {
+                    break;
+                }
+                queue.skip_gap(&mut ready);
+            }
+        }
+        ready
+    }
+
+    /// When the next held packet times out, `None` if nothing is held.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.sources
+            .values()
+            .filter_map(SourceQueue::oldest_arrival)
+            .min()
+            .map(|arrived_at| arrived_at + self.timeout)
+    }
+
+    /// Release every held packet, e.g. before the connection closes.
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut ready = Vec::new();
+        for queue in self.sources.values_mut() {
+            while !queue.held.is_empty() {
+                queue.skip_gap(&mut ready);
+            }
+        }
+        self.sources.clear();
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_packets_after_a_gap() {
+        let mut buffer = ReorderBuffer::new(Duration::from_millis(50));
+        let (a, b) = (PublicKey::new([1; 32]), PublicKey::new([2; 32]));
+        let now = Instant::now();
+
+        assert_eq!(buffer.push(a, 10, "a10", now), vec!["a10"]);
+        assert!(buffer.push(a, 12, "a12", now).is_empty());
+        assert!(buffer.push(a, 13, "a13", now).is_empty());
+        assert_eq!(buffer.push(b, 0, "b0", now), vec!["b0"]);
+        assert_eq!(
+            buffer.next_deadline(),
+            Some(now + Duration::from_millis(50))
+        );
+
+        assert_eq!(buffer.push(a, 11, "a11", now), vec!["a11", "a12", "a13"]);
+        assert_eq!(buffer.next_deadline(), None);
+    }
+
+    #[test]
+    fn skips_gaps_after_timeout() {
+        let mut buffer = ReorderBuffer::new(Duration::from_millis(50));
+        let a = PublicKey::new([1; 32]);
+        let now = Instant::now();
+
+        buffer.push(a, 0, 0, now);
+        assert!(buffer.push(a, 2, 2, now).is_empty());
+        assert!(buffer
+            .push(a, 5, 5, now + Duration::from_millis(20))
+            .is_empty());
+
+        assert!(buffer.expire(now + Duration::from_millis(49)).is_empty());
+        assert_eq!(buffer.expire(now + Duration::from_millis(50)), vec![2]);
+        assert_eq!(
+            buffer.next_deadline(),
+            Some(now + Duration::from_millis(70))
+        );
+        assert_eq!(buffer.push(a, 1, 1, now), vec![1]);
+        assert_eq!(buffer.expire(now + Duration::from_millis(70)), vec![5]);
+        assert_eq!(buffer.push(a, 6, 6, now), vec![6]);
+    }
+
+    #[test]
+    fn bounds_held_packets() {
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(60));
+        let a = PublicKey::new([1; 32]);
+        let now = Instant::now();
+
+        buffer.push(a, 0, 0, now);
+        for seq in 2..=MAX_HELD_PER_SOURCE as u64 + 1 {
+            assert!(buffer.push(a, seq, seq, now).is_empty());
+        }
+        let ready = buffer.push(a, MAX_HELD_PER_SOURCE as u64 + 2, 0, now);
+        assert_eq!(ready.len(), MAX_HELD_PER_SOURCE + 1);
+        assert!(buffer.drain().is_empty());
+    }
+}
@@ -1,21 +1,56 @@
 use crate::{
-    client::{Client, WriteLoopCommands},
+    audit::{AuditEvent, AuditLog},
+    client::{Client, ClientStats, ConnectionId, WriteLoopCommands},
+    compression::Compression,
+    connection::Connection,
     crypto::{PublicKey, SecretKey},
-    mesh_client::MeshClient,
-    proto::handle_handshake,
+    dedup::{DeduplicationCache, DEDUP_CAPACITY, DEDUP_WINDOW},
+    discovery::{lookup_srv_peers, MIN_SRV_REFRESH_INTERVAL},
+    journal::{JournalEntry, PacketJournal, Via, JOURNAL_REPORT_ENTRIES},
+    mesh_client::{maintain_mesh_peer, MeshPeerSettings},
+    proto,
+    proto::{
+        data::{ControlMessage, ForwardPacket, RecvPacket, ServerCapabilities},
+        handle_handshake, ClientHandshake,
+    },
+    queue::BoundedMpsc,
+    ratelimit::PairRateLimiter,
     Config,
 };
 use anyhow::{bail, ensure};
+use clap::ValueEnum;
 use log::{debug, info, trace, warn};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 use tokio::{
-    net::{TcpListener, TcpStream},
+    net::TcpListener,
+    select,
+    signal::ctrl_c,
     spawn,
     sync::{
-        mpsc::{channel, Receiver, Sender},
+        mpsc::{
+            channel,
+            error::{SendError, TrySendError},
+            Receiver, Sender,
+        },
         RwLock,
     },
+    time::{interval, sleep, timeout},
 };
+use trust_dns_resolver::TokioAsyncResolver;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Longest the service waits for room in a full write queue with `BackpressurePolicy::BlockSender`
+const BLOCK_SENDER_TIMEOUT: Duration = Duration::from_millis(100);
 
 pub trait Service {
     async fn run(&self, listener: TcpListener) -> anyhow::Result<()>;
@@ -23,74 +58,185 @@ pub trait Service {
 
 #[derive(Debug)]
 pub struct DerpService {
-    peers_sinks: HashMap<PublicKey, Sender<WriteLoopCommands>>,
-    mesh: HashMap<PublicKey, Sender<WriteLoopCommands>>,
+    peers_sinks: HashMap<PublicKey, PeerRoute>,
+    mesh: HashMap<PublicKey, BoundedMpsc<WriteLoopCommands>>,
+    /// Mesh peers that connected to us and subscribed for peer changes
+    mesh_watchers: HashSet<PublicKey>,
+    max_mesh_peers: Option<usize>,
+    /// Set once shutdown started, no new clients are accepted then
+    draining: bool,
+    drain_timeout: Duration,
+    /// Longest a client may take to upgrade its connection and complete the handshake
+    handshake_timeout: Duration,
+    /// Clients disconnected because they did not complete the handshake in time
+    handshake_timeouts: AtomicU64,
+    /// Longest a single write to a client may take
+    write_watchdog: Duration,
+    /// Longest a client holds back a packet waiting for the packets before it
+    reorder_timeout: Duration,
+    /// How long a client may send nothing before it is disconnected
+    idle_timeout: Option<Duration>,
+    /// Sequence number of the next packet of every `(source, target)` pair, with ordered
+    /// delivery enabled
+    delivery_seqs: Option<Mutex<HashMap<(PublicKey, PublicKey), u64>>>,
     command_sender: Sender<ServiceCommand>,
     meshkey: Option<String>,
+    compression: Option<Compression>,
+    audit_log: AuditLog,
+    /// Source of `ConnectionId`s for accepted connections
+    connection_ids: AtomicU64,
+    /// Source of sequence numbers for packets forwarded to the mesh
+    seq_nos: AtomicU32,
+    dedup_cache: Mutex<DeduplicationCache>,
+    /// Local clients every peer sent packets to, which are told when that peer is gone
+    sent_to: Mutex<HashMap<PublicKey, HashSet<PublicKey>>>,
+    rate_limiter: Option<Mutex<PairRateLimiter>>,
+    /// Packets dropped because their `(source, target)` pair went over its rate limit
+    rate_limited_packets: AtomicU64,
+    /// Longest a packet may wait in the command queue before it is routed
+    max_command_age: Duration,
+    /// Packets dropped because they waited in the command queue for longer than
+    /// `max_command_age`
+    stale_commands: AtomicU64,
+    /// Packets dropped because their target is neither connected here nor through a mesh peer
+    unknown_target_packets: AtomicU64,
+    /// What happens to packets for peers whose write queue is full, with the packets dropped
+    backpressure: Arc<Backpressure>,
+    /// Last forwarded packets, with `--enable-packet-journal`
+    packet_journal: Option<Mutex<PacketJournal>>,
 }
 
 impl DerpService {
     pub async fn add_new_client(
         &mut self,
-        socket: TcpStream,
-        client_pk: PublicKey,
-        meshkey: Option<String>,
+        connection: Connection,
+        id: ConnectionId,
+        handshake: ClientHandshake,
     ) -> anyhow::Result<()> {
-        let can_mesh = match (&self.meshkey, &meshkey) {
+        let client_pk = handshake.public_key;
+        ensure!(
+            !self.draining,
+            "[{id}] Client {client_pk:?} rejected, the server is shutting down"
+        );
+        let can_mesh = match (&self.meshkey, &handshake.meshkey) {
             (None, None) => false,
             (None, Some(_)) => {
                 bail!(
-                    "Client {client_pk:?} ({:?}) tried to mesh with a server that can't mesh",
-                    socket.peer_addr()
+                    "[{id}] Client {client_pk:?} ({:?}) tried to mesh with a server that can't mesh",
+                    connection.peer
                 )
             }
             (Some(_), None) => false,
             (Some(server_meshkey), Some(client_meshkey)) => {
                 ensure!(
                     server_meshkey == client_meshkey,
-                    "Client {client_pk:?} ({:?}) tried to mesh with a wrong key",
-                    socket.peer_addr()
+                    "[{id}] Client {client_pk:?} ({:?}) tried to mesh with a wrong key",
+                    connection.peer
                 );
                 true
             }
         };
-        let client = Client::new(socket, client_pk, can_mesh)?;
+        // Only mesh peers exchange `ForwardPacket`s, so only they can use compression
+        let compression = self
+            .compression
+            .filter(|_| can_mesh && handshake.compression);
+        let client = Client::new(
+            connection,
+            id,
+            client_pk,
+            can_mesh,
+            compression,
+            self.audit_log.clone(),
+            self.write_watchdog,
+            self.reorder_timeout,
+            self.idle_timeout,
+        );
+        let record = ClientRecord {
+            connected_at: Instant::now(),
+            can_mesh,
+            stats: client.stats(),
+        };
         let sink = client.run(self.command_sender.clone()).await?;
 
-        info!("will insert {client_pk:?} to peers (can mesh: {can_mesh})");
-        if let Some(old) = self.peers_sinks.insert(client_pk, sink) {
-            warn!("Newer client with {client_pk:?}: {old:?}");
+        info!("[{id}] will insert {client_pk:?} to peers (can mesh: {can_mesh})");
+        if let Some(old) = self
+            .peers_sinks
+            .insert(client_pk, PeerRoute::Local(sink, record))
+        {
+            warn!("[{id}] Newer client with {client_pk:?}: {old:?}");
         }
 
-        self.notify_all_mesh_peers(client_pk).await;
+        self.notify_all_mesh_peers(client_pk, WriteLoopCommands::PeerPresent)
+            .await;
 
         Ok(())
     }
 
     pub async fn new(config: Config) -> anyhow::Result<Arc<RwLock<Self>>> {
-        let meshkey = config.meshkey;
-
         let (s, r) = channel(1);
         let service_sk = SecretKey::gen();
+        let mesh_settings = config.mesh_peer_settings(service_sk, s.clone())?;
+        let meshkey = mesh_settings
+            .as_ref()
+            .map(|settings| settings.meshkey.clone());
+        let audit_log = match &config.audit_log {
+            Some(path) => AuditLog::open(path).await?,
+            None => AuditLog::default(),
+        };
+
+        let compression = config.compress_threshold.map(Compression::new);
+
         info!("Service public key: {}", service_sk.public());
 
         let ret = Arc::new(RwLock::new(Self {
             peers_sinks: Default::default(),
             mesh: Default::default(),
-            command_sender: s.clone(),
-            meshkey: meshkey.clone(),
+            mesh_watchers: Default::default(),
+            max_mesh_peers: config.max_mesh_peers,
+            draining: false,
+            drain_timeout: Duration::from_secs(config.drain_timeout_secs),
+            handshake_timeout: Duration::from_secs(config.handshake_timeout_secs),
+            handshake_timeouts: AtomicU64::new(0),
+            write_watchdog: Duration::from_secs(config.write_watchdog_secs),
+            reorder_timeout: Duration::from_millis(config.reorder_timeout_ms),
+            idle_timeout: config.client_idle_timeout_secs.map(Duration::from_secs),
+            delivery_seqs: config
+                .enable_ordered_delivery
+                .then(|| Mutex::new(HashMap::new())),
+            command_sender: s,
+            meshkey,
+            compression,
+            audit_log,
+            connection_ids: AtomicU64::new(0),
+            seq_nos: AtomicU32::new(0),
+            dedup_cache: Mutex::new(DeduplicationCache::new(DEDUP_WINDOW, DEDUP_CAPACITY)),
+            sent_to: Default::default(),
+            rate_limiter: config
+                .max_bytes_per_sec_per_pair
+                .map(|rate| Mutex::new(PairRateLimiter::new(rate))),
+            rate_limited_packets: AtomicU64::new(0),
+            max_command_age: Duration::from_millis(config.max_command_age_ms),
+            stale_commands: AtomicU64::new(0),
+            unknown_target_packets: AtomicU64::new(0),
+            backpressure: Arc::new(Backpressure::new(config.write_backpressure)),
+            packet_journal: config
+                .enable_packet_journal
+                .then(|| Mutex::new(PacketJournal::default())),
         }));
         spawn(command_loop(r, ret.clone()));
-        if let Some(meshkey) = meshkey {
+        spawn(evict_stale_state(ret.clone()));
+        if config.stats_interval_secs > 0 {
+            spawn(report_stats(
+                ret.clone(),
+                Duration::from_secs(config.stats_interval_secs),
+            ));
+        }
+        if let Some(settings) = mesh_settings {
             for addr in config.mesh_peers {
-                let mesh_client =
-                    MeshClient::new(&addr, service_sk, meshkey.clone(), s.clone()).await?;
-                match mesh_client.start().await {
-                    Ok((sender, mesh_peer_pk)) => {
-                        ret.write().await.mesh.insert(mesh_peer_pk, sender);
-                    }
-                    Err(e) => warn!("Failed to start peer client for {addr}: {e}"),
-                }
+                spawn(maintain_mesh_peer(addr, settings.clone()));
+            }
+            if let Some(record) = config.mesh_srv_record {
+                spawn(discover_mesh_peers(record, settings));
             }
         } else {
             warn!(
@@ -101,20 +247,240 @@ impl DerpService {
         Ok(ret)
     }
 
-    async fn notify_all_mesh_peers(&self, client_pk: PublicKey) {
-        trace!("Will notify all mesh about new client: {client_pk:?}");
+    pub fn next_connection_id(&self) -> ConnectionId {
+        ConnectionId(self.connection_ids.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Sequence number for a packet from `source` entering the mesh through this server. It is
+    /// remembered right away, so the packet is dropped if the mesh echoes it back to us.
+    fn next_seq_no(&self, source: PublicKey) -> u32 {
+        let seq_no = self.seq_nos.fetch_add(1, Ordering::Relaxed);
+        self.dedup_cache
+            .lock()
+            .unwrap()
+            .insert(source, seq_no, Instant::now());
+        seq_no
+    }
+
+    /// Number of clients disconnected so far for not completing the handshake in time
+    pub fn handshake_timeouts(&self) -> u64 {
+        self.handshake_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped so far by the per pair rate limit
+    pub fn rate_limited_packets(&self) -> u64 {
+        self.rate_limited_packets.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped so far for waiting too long in the command queue
+    pub fn stale_commands(&self) -> u64 {
+        self.stale_commands.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped so far because their target was not a known peer
+    pub fn unknown_target_packets(&self) -> u64 {
+        self.unknown_target_packets.load(Ordering::Relaxed)
+    }
+
+    /// Number of new packets dropped so far for full write queues, with
+    /// `BackpressurePolicy::DropNewest`
+    pub fn dropped_newest_packets(&self) -> u64 {
+        self.backpressure.dropped_newest.load(Ordering::Relaxed)
+    }
+
+    /// Number of queued packets dropped so far to make room for new ones, with
+    /// `BackpressurePolicy::DropOldest`
+    pub fn dropped_oldest_packets(&self) -> u64 {
+        self.backpressure.dropped_oldest.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped so far after waiting too long for room in a write queue, with
+    /// `BackpressurePolicy::BlockSender`
+    pub fn send_timeout_packets(&self) -> u64 {
+        self.backpressure.send_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Up to `count` most recently forwarded packets, oldest first. Empty when the packet
+    /// journal is not enabled
+    pub fn recent_packets(&self, count: usize) -> Vec<JournalEntry> {
+        match &self.packet_journal {
+            Some(journal) => journal.lock().unwrap().recent(count),
+            None => Vec::new(),
+        }
+    }
+
+    /// Number of clients connected directly to this server, including mesh peers
+    pub fn client_count(&self) -> usize {
+        self.peers_sinks
+            .values()
+            .filter(|route| matches!(route, PeerRoute::Local(..)))
+            .count()
+    }
+
+    /// Number of mesh peers linked to this server, both the ones it connected to and the ones
+    /// that connected to it and watch its clients
+    pub fn mesh_peer_count(&self) -> usize {
+        self.mesh.len()
+    }
+
+    /// Snapshot of the clients connected directly to this server, including mesh peers. Peers
+    /// only reachable through the mesh are not listed.
+    pub fn peer_list(&self) -> Vec<PeerInfo> {
+        self.peers_sinks
+            .iter()
+            .filter_map(|(pk, route)| match route {
+                PeerRoute::Local(_, record) => Some(PeerInfo {
+                    pk: *pk,
+                    connected_at: record.connected_at,
+                    can_mesh: record.can_mesh,
+                    bytes_sent: record.stats.bytes_sent.load(Ordering::Relaxed),
+                    bytes_recv: record.stats.bytes_recv.load(Ordering::Relaxed),
+                    dropped_packets: record.stats.dropped_packets.load(Ordering::Relaxed),
+                }),
+                PeerRoute::Mesh { .. } => None,
+            })
+            .collect()
+    }
+
+    pub fn capabilities(&self) -> ServerCapabilities {
+        let mut capabilities = ServerCapabilities::FORWARD_TTL;
+        if self.meshkey.is_some() {
+            capabilities = capabilities | ServerCapabilities::MESH;
+        }
+        if self.compression.is_some() {
+            capabilities = capabilities | ServerCapabilities::COMPRESSION;
+        }
+        capabilities
+    }
+
+    /// Disconnect the client `pk` connected to this server when a mesh peer sends `ClosePeer`,
+    /// returning `false` if there is none. Stopping its write loop also ends its read loop,
+    /// closing the whole connection.
+    ///
+    /// Clients reachable through a mesh peer are not disconnected, that is up to the mesh peer.
+    pub async fn disconnect_client(&mut self, pk: PublicKey) -> bool {
+        let Some(PeerRoute::Local(sink, _)) = self.peers_sinks.get(&pk).cloned() else {
+            return false;
+        };
+        self.peers_sinks.remove(&pk);
+        info!("Disconnecting client {pk:?}");
+        // A full queue must not stall routing while the service is locked
+        spawn(async move {
+            if let Err(e) = sink.send(WriteLoopCommands::_Stop).await {
+                warn!("Failed to stop the write loop of {pk:?}: {e}");
+            }
+        });
+        self.forget_local_client(pk).await;
+        true
+    }
+
+    /// Remove the route to `pk` through `sink`, after the connection behind it went away.
+    async fn remove_route(&mut self, pk: PublicKey, sink: &BoundedMpsc<WriteLoopCommands>) {
+        let Some(route) = self.peers_sinks.get(&pk) else {
+            return;
+        };
+        // A newer connection of the same peer may have replaced this one already
+        if !route.sink().same_channel(sink) {
+            return;
+        }
+        if let Some(PeerRoute::Local(_, record)) = self.peers_sinks.remove(&pk) {
+            info!(
+                "{} {pk:?} is gone after {:?}, sent {} and received {} bytes",
+                if record.can_mesh {
+                    "Mesh peer"
+                } else {
+                    "Client"
+                },
+                record.connected_at.elapsed(),
+                record.stats.bytes_sent.load(Ordering::Relaxed),
+                record.stats.bytes_recv.load(Ordering::Relaxed),
+            );
+            self.forget_local_client(pk).await;
+            // It may have been a mesh peer, relaying its clients over this connection
+            self.forget_mesh_routes(pk, sink);
+        } else {
+            info!("Client {pk:?} is gone (via peer gone)");
+            self.notify_recipients_of_peer_gone(pk);
+        }
+    }
+
+    /// Clean up after the local client `pk` was removed from the peers, and tell the mesh peers
+    /// and the clients it sent packets to that it is gone.
+    async fn forget_local_client(&mut self, pk: PublicKey) {
+        // Its reorder buffer is gone with it, a new connection starts over
+        if let Some(delivery_seqs) = self.delivery_seqs.as_mut() {
+            delivery_seqs
+                .get_mut()
+                .unwrap()
+                .retain(|(_, target), _| *target != pk);
+        }
+        self.notify_all_mesh_peers(pk, WriteLoopCommands::PeerGone)
+            .await;
+        self.notify_recipients_of_peer_gone(pk);
+    }
+
+    /// Send `command(client_pk)` to all mesh peers, e.g. to tell them about a new client
+    async fn notify_all_mesh_peers(
+        &self,
+        client_pk: PublicKey,
+        command: fn(PublicKey) -> WriteLoopCommands,
+    ) {
+        trace!("Will notify all mesh about client: {client_pk:?}");
         let mesh = self.mesh.clone();
         spawn(async move {
             for (peer, sink) in mesh {
-                if let Err(e) = sink
-                    .send(WriteLoopCommands::PeerPresent(client_pk.clone()))
-                    .await
-                {
+                if let Err(e) = sink.send(command(client_pk)).await {
                     warn!("Failed to notify mesh peer {peer} about client {client_pk:?}: {e}");
                 }
             }
         });
     }
+
+    /// Drop the peers the mesh peer `via` relayed through `sink`, telling the local clients they
+    /// sent packets to that they are gone. Returns the number of dropped peers.
+    fn forget_mesh_routes(
+        &mut self,
+        via: PublicKey,
+        sink: &BoundedMpsc<WriteLoopCommands>,
+    ) -> usize {
+        let gone: Vec<_> = self
+            .peers_sinks
+            .iter()
+            .filter(|(_, route)| match route {
+                PeerRoute::Mesh { via: v, sink: s } => *v == via && s.same_channel(sink),
+                PeerRoute::Local(..) => false,
+            })
+            .map(|(pk, _)| *pk)
+            .collect();
+        for pk in &gone {
+            self.peers_sinks.remove(pk);
+            self.notify_recipients_of_peer_gone(*pk);
+        }
+        gone.len()
+    }
+
+    /// Tell the local clients that `peer_pk` sent packets to that it is gone
+    fn notify_recipients_of_peer_gone(&mut self, peer_pk: PublicKey) {
+        let sent_to = self.sent_to.get_mut().unwrap();
+        let recipients = sent_to.remove(&peer_pk).unwrap_or_default();
+        for recipients in sent_to.values_mut() {
+            recipients.remove(&peer_pk);
+        }
+        let sinks: Vec<_> = recipients
+            .into_iter()
+            .filter_map(|pk| match self.peers_sinks.get(&pk) {
+                Some(PeerRoute::Local(sink, _)) => Some((pk, sink.clone())),
+                _ => None,
+            })
+            .collect();
+        spawn(async move {
+            for (pk, sink) in sinks {
+                if let Err(e) = sink.send(WriteLoopCommands::PeerGone(peer_pk)).await {
+                    warn!("Failed to tell client {pk:?} that {peer_pk:?} is gone: {e}");
+                }
+            }
+        });
+    }
 }
 
 // TODO: should this be RWLock instead of Mutex?
@@ -122,31 +488,113 @@ impl Service for Arc<RwLock<DerpService>> {
     async fn run(&self, listener: TcpListener) -> anyhow::Result<()> {
         loop {
             // TODO: handle panic!
-            if let Ok((socket, peer_addr)) = listener.accept().await {
-                let service = self.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(socket, peer_addr, service).await {
-                        warn!("Client {peer_addr:?} failed: {e:?}");
+            select! {
+                accepted = listener.accept() => {
+                    if let Ok((socket, peer_addr)) = accepted {
+                        let id = self.read().await.next_connection_id();
+                        let service = self.clone();
+                        tokio::spawn(async move {
+                            let connect = Connection::accept(socket);
+                            if let Err(e) = handle_client(connect, id, peer_addr, service).await {
+                                warn!("[{id}] Client {peer_addr:?} failed: {e:?}");
+                            }
+                        });
                     }
-                });
+                }
+                signal = ctrl_c() => {
+                    signal?;
+                    break;
+                }
             }
         }
+
+        drop(listener);
+        let drain_timeout = {
+            let mut service = self.write().await;
+            service.draining = true;
+            service.drain_timeout
+        };
+        info!("Draining client queues for up to {drain_timeout:?}");
+        if timeout(drain_timeout, wait_for_empty_queues(self))
+            .await
+            .is_err()
+        {
+            warn!("Drain timeout expired, dropping queued packets");
+        }
+        Ok(())
     }
 }
 
-async fn handle_client(
-    mut socket: TcpStream,
+/// Wait until the write queues of all clients are empty.
+async fn wait_for_empty_queues(service: &Arc<RwLock<DerpService>>) {
+    loop {
+        let all_empty = service
+            .read()
+            .await
+            .peers_sinks
+            .values()
+            .all(|route| route.sink().is_empty());
+        if all_empty {
+            return;
+        }
+        sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Complete the handshake with a client once `connect` upgraded its connection, and start
+/// serving it.
+pub async fn handle_client(
+    connect: impl Future<Output = proto::Result<Connection>>,
+    id: ConnectionId,
     peer_addr: SocketAddr,
     service: Arc<RwLock<DerpService>>,
 ) -> anyhow::Result<()> {
-    debug!("Got connection from: {peer_addr:?}");
+    debug!("[{id}] Got connection from: {peer_addr:?}");
     let sk = SecretKey::gen();
-    let (client_pk, meshkey) = handle_handshake(&mut socket, &sk).await?;
+    let (capabilities, handshake_timeout) = {
+        let service = service.read().await;
+        (service.capabilities(), service.handshake_timeout)
+    };
+    let accepted = async {
+        let mut connection = connect.await?;
+        let handshake = handle_handshake(&mut connection, &sk, capabilities).await?;
+        Ok::<_, proto::Error>((connection, handshake))
+    };
+    // Bounds the whole upgrade and handshake, so clients trickling in their requests byte by
+    // byte can not hold on to the connection
+    let accepted = match timeout(handshake_timeout, accepted).await {
+        Ok(accepted) => accepted,
+        Err(_) => {
+            service
+                .read()
+                .await
+                .handshake_timeouts
+                .fetch_add(1, Ordering::Relaxed);
+            Err(proto::Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("handshake not completed within {handshake_timeout:?}"),
+            )))
+        }
+    };
+    let (connection, handshake) = match accepted {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            service
+                .read()
+                .await
+                .audit_log
+                .record(AuditEvent::HandshakeFailed {
+                    addr: peer_addr,
+                    reason: e.to_string(),
+                });
+            return Err(e.into());
+        }
+    };
 
     service
         .write()
         .await
-        .add_new_client(socket, client_pk, meshkey)
+        .add_new_client(connection, id, handshake)
         .await?;
 
     Ok(())
@@ -161,30 +609,130 @@ async fn command_loop(
             Some(ServiceCommand::SendPacket {
                 source,
                 target,
+                ttl,
+                seq_no,
                 payload,
+                queued_at,
             }) => {
                 // TODO: to make this faster client/mesh_client should have direct access to
                 // the `peers_sinks`, instead of sending requests to service. This way clients
-                // communication will not put preasure on the services queue. With curren aproach,
-                // sink to serviced quickly will block whole service. After this change, it will
-                // only impact senders wanting to communicate with it.
+                // communication will not put preasure on the services queue. Slow sinks drop
+                // their oldest packets instead of blocking the whole service.
                 debug!("send packet to {target:?}");
-                let sink = match service.read().await.peers_sinks.get(&target) {
-                    Some(sink) => sink.clone(),
+                let shared = service.clone();
+                let service = service.read().await;
+                let age = queued_at.elapsed();
+                if age > service.max_command_age {
+                    warn!("Dropping packet from {source:?} to {target:?}: queued for {age:?}");
+                    service.stale_commands.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                if let Some(seq_no) = seq_no {
+                    let mut dedup_cache = service.dedup_cache.lock().unwrap();
+                    if !dedup_cache.insert(source, seq_no, Instant::now()) {
+                        trace!("Dropping duplicate packet {seq_no} from {source:?}");
+                        continue;
+                    }
+                }
+                if let Some(rate_limiter) = &service.rate_limiter {
+                    let mut rate_limiter = rate_limiter.lock().unwrap();
+                    if !rate_limiter.allow(source, target, payload.len(), Instant::now()) {
+                        trace!("Dropping packet from {source:?} to {target:?}: rate limited");
+                        service.rate_limited_packets.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                }
+                let size_bytes = payload.len();
+                let (sink, command, via, stats) = match service.peers_sinks.get(&target) {
+                    Some(PeerRoute::Local(sink, record)) => {
+                        service
+                            .sent_to
+                            .lock()
+                            .unwrap()
+                            .entry(source)
+                            .or_default()
+                            .insert(target);
+                        let packet = RecvPacket { source, payload };
+                        let command = match &service.delivery_seqs {
+                            Some(delivery_seqs) => {
+                                let mut delivery_seqs = delivery_seqs.lock().unwrap();
+                                let next = delivery_seqs.entry((source, target)).or_default();
+                                let seq = *next;
+                                *next += 1;
+                                WriteLoopCommands::OrderedRecvPacket { seq, packet }
+                            }
+                            None => WriteLoopCommands::RecvPacket(packet),
+                        };
+                        (
+                            sink.clone(),
+                            command,
+                            Via::Local,
+                            Some(record.stats.clone()),
+                        )
+                    }
+                    Some(PeerRoute::Mesh { sink, .. }) => {
+                        let seq_no = seq_no.unwrap_or_else(|| service.next_seq_no(source));
+                        (
+                            sink.clone(),
+                            WriteLoopCommands::ForwardPacket(ForwardPacket::new(
+                                source,
+                                target,
+                                ttl,
+                                Some(seq_no),
+                                payload,
+                            )),
+                            Via::Mesh,
+                            None,
+                        )
+                    }
                     None => {
+                        debug!("Dropping packet from {source:?} to {target:?}: unknown peer");
+                        service
+                            .unknown_target_packets
+                            .fetch_add(1, Ordering::Relaxed);
                         continue;
                     }
                 };
-                sink.send(WriteLoopCommands::SendPacket {
-                    source,
-                    target,
-                    payload,
-                })
-                .await?;
+                if let Some(journal) = &service.packet_journal {
+                    journal.lock().unwrap().record(JournalEntry {
+                        timestamp: SystemTime::now(),
+                        source_pk: source,
+                        target_pk: target,
+                        size_bytes,
+                        via,
+                    });
+                }
+                let backpressure = service.backpressure.clone();
+                drop(service);
+                if let Err(e) = backpressure.send(&sink, command, stats.as_deref()).await {
+                    // The connection behind the route is gone, but its `PeerGone` may still be
+                    // on the way
+                    warn!("Dropping route to {target:?}: {e}");
+                    shared.write().await.remove_route(target, &sink).await;
+                }
             }
             Some(ServiceCommand::SubscribeForPeerChanges(mesh_peer_pk, mesh_sink)) => {
                 let current_peers: Vec<PublicKey> = {
                     let mut service = service.write().await;
+                    if !service.mesh_watchers.contains(&mesh_peer_pk)
+                        && service
+                            .max_mesh_peers
+                            .is_some_and(|max| service.mesh_watchers.len() >= max)
+                    {
+                        warn!("Rejecting mesh peer {mesh_peer_pk:?}: too many mesh peers");
+                        // A rejected peer with a full queue must not block the command loop
+                        spawn(async move {
+                            let _ = mesh_sink
+                                .send(WriteLoopCommands::ControlMessage(
+                                    ControlMessage::Disconnect {
+                                        reason: "too many mesh peers".to_owned(),
+                                    },
+                                ))
+                                .await;
+                        });
+                        continue;
+                    }
+                    service.mesh_watchers.insert(mesh_peer_pk);
                     if let Some(_old) = service.mesh.insert(mesh_peer_pk, mesh_sink.clone()) {
                         warn!("Mesh peer for {mesh_peer_pk:?} overwriten");
                     }
@@ -202,27 +750,141 @@ async fn command_loop(
 
                 trace!("Peer {mesh_peer_pk:?} added to mesh");
             }
-            Some(ServiceCommand::PeerPresent(pk, sink)) => {
+            Some(ServiceCommand::MeshPeerPresent(via, pk, sink)) => {
                 let mut service = service.write().await;
                 match service.peers_sinks.entry(pk) {
-                    std::collections::hash_map::Entry::Occupied(_) => {
-                        warn!("Ignoring already known peer: {pk:?}");
+                    std::collections::hash_map::Entry::Occupied(e)
+                        if matches!(e.get(), PeerRoute::Local(..)) =>
+                    {
+                        warn!("Ignoring {pk:?} via a mesh peer, it is connected here");
+                    }
+                    // The peer reconnected to another mesh node. The old sink is the link to the
+                    // previous mesh node and still carries its other peers, so it stays open.
+                    std::collections::hash_map::Entry::Occupied(mut e) => {
+                        if !e.get().sink().same_channel(&sink) {
+                            info!("{pk:?} moved to mesh peer {via:?}");
+                            e.insert(PeerRoute::Mesh { via, sink });
+                        }
                     }
                     std::collections::hash_map::Entry::Vacant(e) => {
-                        info!("will insert {pk:?} to peers (via peer present)");
-                        e.insert(sink);
+                        info!("will insert {pk:?} to peers (via mesh peer {via:?})");
+                        e.insert(PeerRoute::Mesh { via, sink });
                     }
                 }
             }
+            Some(ServiceCommand::PeerGone(pk, sink)) => {
+                service.write().await.remove_route(pk, &sink).await;
+            }
+            Some(ServiceCommand::MeshPeerUp(mesh_peer_pk, mesh_sink)) => {
+                info!("Mesh peer {mesh_peer_pk:?} is up");
+                service.write().await.mesh.insert(mesh_peer_pk, mesh_sink);
+            }
+            Some(ServiceCommand::MeshPeerDown(mesh_peer_pk)) => {
+                let mut service = service.write().await;
+                let Some(mesh_sink) = service.mesh.remove(&mesh_peer_pk) else {
+                    continue;
+                };
+                let dropped = service.forget_mesh_routes(mesh_peer_pk, &mesh_sink);
+                warn!("Mesh peer {mesh_peer_pk:?} is down, dropped {dropped} clients behind it");
+            }
+            Some(ServiceCommand::ClosePeer(pk)) => {
+                if !service.write().await.disconnect_client(pk).await {
+                    debug!("Not closing {pk:?}: it is not connected here");
+                }
+            }
             Some(ServiceCommand::_Stop) => return Ok(()),
             None => return Ok(()),
         }
     }
 }
 
+/// Periodically forget forwarded packets that left the deduplication window and idle rate limit
+/// buckets.
+async fn evict_stale_state(service: Arc<RwLock<DerpService>>) {
+    let mut interval = interval(DEDUP_WINDOW);
+    loop {
+        interval.tick().await;
+        let service = service.read().await;
+        let now = Instant::now();
+        service.dedup_cache.lock().unwrap().evict_expired(now);
+        if let Some(rate_limiter) = &service.rate_limiter {
+            rate_limiter.lock().unwrap().evict_idle(now);
+        }
+    }
+}
+
+/// Log the number of peers and the counters of dropped packets and failed handshakes every
+/// `period`, with the stats of every client and the last journaled packets at debug level.
+async fn report_stats(service: Arc<RwLock<DerpService>>, period: Duration) {
+    let mut interval = interval(period);
+    // The first tick completes right away, when there is nothing to report yet
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let service = service.read().await;
+        info!(
+            "{} clients, {} mesh peers",
+            service.client_count(),
+            service.mesh_peer_count()
+        );
+        for peer in service.peer_list() {
+            debug!(
+                "{:?} connected for {:?} (can mesh: {}), sent {} and received {} bytes, dropped {} \
+                 packets",
+                peer.pk,
+                peer.connected_at.elapsed(),
+                peer.can_mesh,
+                peer.bytes_sent,
+                peer.bytes_recv,
+                peer.dropped_packets,
+            );
+        }
+        for entry in service.recent_packets(JOURNAL_REPORT_ENTRIES) {
+            debug!("Forwarded {entry:?}");
+        }
+        info!(
+            "Handshake timeouts: {}, dropped packets: {} rate limited, {} stale, {} to unknown \
+             peers, {} newest and {} oldest in full queues, {} timed out waiting for room",
+            service.handshake_timeouts(),
+            service.rate_limited_packets(),
+            service.stale_commands(),
+            service.unknown_target_packets(),
+            service.dropped_newest_packets(),
+            service.dropped_oldest_packets(),
+            service.send_timeout_packets(),
+        );
+    }
+}
+
+/// Keep connecting to mesh peers announced by the `record` SRV record, re-querying it every
+/// time its TTL expires.
+async fn discover_mesh_peers(record: String, settings: MeshPeerSettings) -> anyhow::Result<()> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+    let mut maintained = HashSet::new();
+    loop {
+        let refresh_in = match lookup_srv_peers(&resolver, &record).await {
+            Ok(peers) => {
+                debug!("SRV record {record} resolved to {:?}", peers.addrs);
+                for addr in peers.addrs.iter() {
+                    if maintained.insert(addr.clone()) {
+                        info!("Mesh peer {addr} discovered via {record}");
+                        spawn(maintain_mesh_peer(addr.clone(), settings.clone()));
+                    }
+                }
+                peers.refresh_in()
+            }
+            Err(e) => {
+                warn!("Failed to look up SRV record {record}: {e}");
+                MIN_SRV_REFRESH_INTERVAL
+            }
+        };
+        sleep(refresh_in).await;
+    }
+}
+
 fn notify_about_all_clients(
     mesh_peer_pk: PublicKey,
-    mesh_sink: Sender<WriteLoopCommands>,
+    mesh_sink: BoundedMpsc<WriteLoopCommands>,
     clients_pk: Vec<PublicKey>,
 ) {
     spawn(async move {
@@ -234,13 +896,901 @@ fn notify_about_all_clients(
     });
 }
 
+/// What the service knows about a client connected directly to it
+#[derive(Debug, Clone)]
+struct ClientRecord {
+    connected_at: Instant,
+    can_mesh: bool,
+    stats: Arc<ClientStats>,
+}
+
+/// Snapshot of a client connected directly to this server, see `DerpService::peer_list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub pk: PublicKey,
+    pub connected_at: Instant,
+    pub can_mesh: bool,
+    /// Payload bytes sent to the client
+    pub bytes_sent: u64,
+    /// Payload bytes received from the client
+    pub bytes_recv: u64,
+    /// Packets for the client dropped because its write queue was full
+    pub dropped_packets: u64,
+}
+
+/// How packets for a peer reach it
+#[derive(Debug, Clone)]
+enum PeerRoute {
+    /// Peer is connected directly to this server
+    Local(BoundedMpsc<WriteLoopCommands>, ClientRecord),
+    /// Peer is connected to the mesh peer `via`, which relays its packets through `sink`
+    Mesh {
+        via: PublicKey,
+        sink: BoundedMpsc<WriteLoopCommands>,
+    },
+}
+
+impl PeerRoute {
+    fn sink(&self) -> &BoundedMpsc<WriteLoopCommands> {
+        match self {
+            PeerRoute::Local(sink, _) | PeerRoute::Mesh { sink, .. } => sink,
+        }
+    }
+}
+
+/// What happens to a packet for a peer whose write queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BackpressurePolicy {
+    /// Drop the new packet
+    DropNewest,
+    /// Make room for the new packet by dropping the oldest queued one
+    #[default]
+    DropOldest,
+    /// Wait for room, dropping the new packet if there is none after `BLOCK_SENDER_TIMEOUT`.
+    /// Other packets wait meanwhile
+    BlockSender,
+}
+
+/// Applies the `BackpressurePolicy` to packets routed by the service and counts the packets it
+/// dropped
+#[derive(Debug)]
+struct Backpressure {
+    policy: BackpressurePolicy,
+    dropped_newest: AtomicU64,
+    dropped_oldest: AtomicU64,
+    send_timeouts: AtomicU64,
+}
+
+impl Backpressure {
+    fn new(policy: BackpressurePolicy) -> Self {
+        Self {
+            policy,
+            dropped_newest: AtomicU64::new(0),
+            dropped_oldest: AtomicU64::new(0),
+            send_timeouts: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue `command` on `sink`, failing only if the receiving end is gone. Drops are also
+    /// counted in the `stats` of the client behind `sink`, if it is connected here.
+    async fn send(
+        &self,
+        sink: &BoundedMpsc<WriteLoopCommands>,
+        command: WriteLoopCommands,
+        stats: Option<&ClientStats>,
+    ) -> Result<(), SendError<WriteLoopCommands>> {
+        let dropped = match self.policy {
+            BackpressurePolicy::DropNewest => match sink.try_send(command) {
+                Ok(()) => 0,
+                Err(TrySendError::Full(_)) => {
+                    trace!("Dropping packet: write queue full");
+                    self.dropped_newest.fetch_add(1, Ordering::Relaxed);
+                    1
+                }
+                Err(TrySendError::Closed(command)) => return Err(SendError(command)),
+            },
+            BackpressurePolicy::DropOldest => {
+                let dropped = sink.force_send_dropping_oldest(command).await?;
+                self.dropped_oldest.fetch_add(dropped, Ordering::Relaxed);
+                dropped
+            }
+            BackpressurePolicy::BlockSender => {
+                match timeout(BLOCK_SENDER_TIMEOUT, sink.send(command)).await {
+                    Ok(sent) => {
+                        sent?;
+                        0
+                    }
+                    Err(_) => {
+                        trace!("Dropping packet: write queue full for {BLOCK_SENDER_TIMEOUT:?}");
+                        self.send_timeouts.fetch_add(1, Ordering::Relaxed);
+                        1
+                    }
+                }
+            }
+        };
+        if let Some(stats) = stats {
+            stats.dropped_packets.fetch_add(dropped, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
 pub enum ServiceCommand {
     _Stop,
     SendPacket {
         source: PublicKey,
         target: PublicKey,
+        ttl: u8,
+        /// Set for packets that already went through the mesh
+        seq_no: Option<u32>,
         payload: Vec<u8>,
+        /// When the packet was queued, packets waiting for too long are dropped
+        queued_at: Instant,
     },
-    SubscribeForPeerChanges(PublicKey, Sender<WriteLoopCommands>),
-    PeerPresent(PublicKey, Sender<WriteLoopCommands>),
+    SubscribeForPeerChanges(PublicKey, BoundedMpsc<WriteLoopCommands>),
+    /// Mesh peer with the first key relays the peer with the second key through this sink
+    MeshPeerPresent(PublicKey, PublicKey, BoundedMpsc<WriteLoopCommands>),
+    /// Peer is no longer reachable through this sink, either because the client disconnected
+    /// or because a mesh peer told us so
+    PeerGone(PublicKey, BoundedMpsc<WriteLoopCommands>),
+    /// Connection to a mesh peer was (re)established
+    MeshPeerUp(PublicKey, BoundedMpsc<WriteLoopCommands>),
+    /// Connection to a mesh peer was lost, it will be retried in the background
+    MeshPeerDown(PublicKey),
+    /// A mesh peer asked to close the connection of a client connected here
+    ClosePeer(PublicKey),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        inout::DerpReader,
+        proto::{
+            data::{
+                ClosePeer, Frame, FrameType, PeerGone, PeerPresent, SendPacket, DEFAULT_FORWARD_TTL,
+            },
+            exchange_keys, read_server_info, write_watch_conns,
+        },
+        testing::wait_until,
+    };
+    use clap::Parser;
+    use codec::Encode;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{
+            tcp::{OwnedReadHalf, OwnedWriteHalf},
+            TcpStream,
+        },
+    };
+
+    async fn connect_client(
+        addr: SocketAddr,
+        sk: SecretKey,
+        meshkey: Option<&str>,
+    ) -> (DerpReader<OwnedReadHalf>, OwnedWriteHalf) {
+        let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        w.write_all(b"GET /derp HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: DERP\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = [0; 19];
+        r.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 OK\r\n\r\n");
+        let mut reader = DerpReader::new(r);
+        exchange_keys(&mut reader, &mut w, sk, meshkey, false)
+            .await
+            .unwrap();
+        read_server_info(&mut reader).await.unwrap();
+        (reader, w)
+    }
+
+    #[tokio::test]
+    async fn recipients_are_told_when_sender_disconnects() {
+        let service = DerpService::new(Config::parse_from(["dersp"]))
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move { service.run(listener).await });
+
+        let (a_sk, b_sk) = (SecretKey::gen(), SecretKey::gen());
+        let (a_reader, mut a_writer) = connect_client(addr, a_sk, None).await;
+        let (mut b_reader, _b_writer) = connect_client(addr, b_sk, None).await;
+
+        let mut buf = Vec::new();
+        Frame::new(SendPacket {
+            target: b_sk.public(),
+            payload: vec![1, 2, 3],
+        })
+        .encode(&mut buf)
+        .unwrap();
+        // B may still be registering with the service, so A sends until B got a packet
+        let message = timeout(Duration::from_secs(1), async {
+            loop {
+                a_writer.write_all(&buf).await.unwrap();
+                if let Ok(message) =
+                    timeout(Duration::from_millis(50), b_reader.get_next_message()).await
+                {
+                    break message.unwrap();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(message.ty, FrameType::RecvPacket);
+
+        drop((a_reader, a_writer));
+        let message = timeout(Duration::from_secs(1), async {
+            loop {
+                let message = b_reader.get_next_message().await.unwrap();
+                if message.ty != FrameType::RecvPacket {
+                    break message;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(message.ty, FrameType::PeerGone);
+        let peer_gone = message.decode_body::<PeerGone>().unwrap();
+        assert_eq!(peer_gone.public_key, a_sk.public());
+    }
+
+    #[tokio::test]
+    async fn peer_list_has_connected_clients() {
+        let service = DerpService::new(Config::parse_from(["dersp"]))
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn({
+            let service = service.clone();
+            async move { service.run(listener).await }
+        });
+
+        let (a_sk, b_sk) = (SecretKey::gen(), SecretKey::gen());
+        let before = Instant::now();
+        let (_a_reader, mut a_writer) = connect_client(addr, a_sk, None).await;
+        let (mut b_reader, _b_writer) = connect_client(addr, b_sk, None).await;
+        wait_until("both clients are listed", || async {
+            service.read().await.peer_list().len() == 2
+        })
+        .await;
+        Frame::new(SendPacket {
+            target: b_sk.public(),
+            payload: vec![1, 2, 3],
+        })
+        .write_all(&mut a_writer)
+        .await
+        .unwrap();
+        let message = b_reader.get_next_message().await.unwrap();
+        assert_eq!(message.ty, FrameType::RecvPacket);
+
+        let mut peers = service.read().await.peer_list();
+        peers.sort_by_key(|peer| peer.pk != a_sk.public());
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].pk, a_sk.public());
+        assert_eq!(peers[1].pk, b_sk.public());
+        assert!(peers
+            .iter()
+            .all(|peer| peer.connected_at >= before && !peer.can_mesh));
+        assert_eq!(peers[0].bytes_recv, 3);
+        // Counted once the write completed, which is just before the client can read it
+        wait_until("bytes sent to the client are counted", || async {
+            service
+                .read()
+                .await
+                .peer_list()
+                .iter()
+                .any(|peer| peer.pk == b_sk.public() && peer.bytes_sent == 3)
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn watching_mesh_peer_learns_about_existing_clients() {
+        let config = Config::parse_from(["dersp", "--meshkey", "meshkey"]);
+        let service = DerpService::new(config).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn({
+            let service = service.clone();
+            async move { service.run(listener).await }
+        });
+
+        let (a_sk, b_sk) = (SecretKey::gen(), SecretKey::gen());
+        let _a = connect_client(addr, a_sk, None).await;
+        let _b = connect_client(addr, b_sk, None).await;
+        wait_until("both peers are connected", || async {
+            service.read().await.client_count() == 2
+        })
+        .await;
+
+        let (mut mesh_reader, mut mesh_writer) =
+            connect_client(addr, SecretKey::gen(), Some("meshkey")).await;
+        write_watch_conns(&mut mesh_writer).await.unwrap();
+
+        let mut present = HashSet::new();
+        for _ in 0..2 {
+            let message = timeout(Duration::from_secs(1), mesh_reader.get_next_message())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(message.ty, FrameType::PeerPresent);
+            let peer_present = message.decode_body::<PeerPresent>().unwrap();
+            present.insert(peer_present.public_key);
+        }
+        assert_eq!(present, HashSet::from([a_sk.public(), b_sk.public()]));
+
+        // Nothing else happened, so nothing else is announced
+        assert!(
+            timeout(Duration::from_millis(100), mesh_reader.get_next_message())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_mesh_peer_is_disconnected() {
+        let config = Config::parse_from(["dersp", "--meshkey", "meshkey", "--max-mesh-peers", "0"]);
+        let service = DerpService::new(config).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn({
+            let service = service.clone();
+            async move { service.run(listener).await }
+        });
+
+        let sk = SecretKey::gen();
+        let (mut mesh_reader, mut mesh_writer) = connect_client(addr, sk, Some("meshkey")).await;
+        write_watch_conns(&mut mesh_writer).await.unwrap();
+        let message = timeout(Duration::from_secs(1), mesh_reader.get_next_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.ty, FrameType::ControlMessage);
+        assert!(matches!(
+            timeout(Duration::from_secs(1), mesh_reader.get_next_message())
+                .await
+                .unwrap(),
+            Err(proto::Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+
+        // The mesh peer keeps its writer open, still it is no longer routed to
+        wait_until("the mesh peer is no longer routed to", || async {
+            !service.read().await.peers_sinks.contains_key(&sk.public())
+        })
+        .await;
+        assert_eq!(service.read().await.mesh_peer_count(), 0);
+        drop(mesh_writer);
+    }
+
+    #[tokio::test]
+    async fn mesh_peers_are_told_when_client_disconnects() {
+        let config = Config::parse_from(["dersp", "--meshkey", "meshkey"]);
+        let b = DerpService::new(config).await.unwrap();
+        let b_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let b_addr = b_listener.local_addr().unwrap();
+        spawn({
+            let b = b.clone();
+            async move { b.run(b_listener).await }
+        });
+        let config = Config::parse_from([
+            "dersp",
+            "--meshkey",
+            "meshkey",
+            "--mesh-peers",
+            &b_addr.to_string(),
+        ]);
+        let a = DerpService::new(config).await.unwrap();
+        let a_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let a_addr = a_listener.local_addr().unwrap();
+        spawn({
+            let a = a.clone();
+            async move { a.run(a_listener).await }
+        });
+        timeout(Duration::from_secs(5), async {
+            while a.read().await.mesh_peer_count() == 0 || b.read().await.mesh_peer_count() == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // The client of A sends to the client of B through the mesh
+        let (a_sk, b_sk) = (SecretKey::gen(), SecretKey::gen());
+        let (a_reader, mut a_writer) = connect_client(a_addr, a_sk, None).await;
+        let (mut b_reader, _b_writer) = connect_client(b_addr, b_sk, None).await;
+        timeout(Duration::from_secs(5), async {
+            while !a.read().await.peers_sinks.contains_key(&b_sk.public())
+                || !b.read().await.peers_sinks.contains_key(&a_sk.public())
+            {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        Frame::new(SendPacket {
+            target: b_sk.public(),
+            payload: vec![1, 2, 3],
+        })
+        .write_all(&mut a_writer)
+        .await
+        .unwrap();
+        let message = timeout(Duration::from_secs(1), b_reader.get_next_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.ty, FrameType::RecvPacket);
+
+        drop((a_reader, a_writer));
+        let message = timeout(Duration::from_secs(1), b_reader.get_next_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.ty, FrameType::PeerGone);
+        assert_eq!(
+            message.decode_body::<PeerGone>().unwrap().public_key,
+            a_sk.public()
+        );
+        assert!(!b.read().await.peers_sinks.contains_key(&a_sk.public()));
+    }
+
+    #[tokio::test]
+    async fn stale_packets_are_dropped() {
+        let config = Config::parse_from(["dersp", "--max-command-age-ms", "100"]);
+        let service = DerpService::new(config).await.unwrap();
+        let command_sender = service.read().await.command_sender.clone();
+
+        for queued_at in [Instant::now() - Duration::from_secs(1), Instant::now()] {
+            command_sender
+                .send(ServiceCommand::SendPacket {
+                    source: SecretKey::gen().public(),
+                    target: SecretKey::gen().public(),
+                    ttl: DEFAULT_FORWARD_TTL,
+                    seq_no: None,
+                    payload: vec![1, 2, 3],
+                    queued_at,
+                })
+                .await
+                .unwrap();
+        }
+        // The channel holds a single command, so the first one was already handled
+        command_sender.send(ServiceCommand::_Stop).await.unwrap();
+        assert_eq!(service.read().await.stale_commands(), 1);
+    }
+
+    #[tokio::test]
+    async fn packets_over_rate_limit_are_counted() {
+        let config = Config::parse_from(["dersp", "--max-bytes-per-sec-per-pair", "100000"]);
+        let service = DerpService::new(config).await.unwrap();
+        let command_sender = service.read().await.command_sender.clone();
+        let (sink, mut mesh) = BoundedMpsc::channel(4);
+        let (source, peer) = (SecretKey::gen().public(), SecretKey::gen().public());
+
+        let via = SecretKey::gen().public();
+        command_sender
+            .send(ServiceCommand::MeshPeerPresent(via, peer, sink))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            command_sender
+                .send(ServiceCommand::SendPacket {
+                    source,
+                    target: peer,
+                    ttl: DEFAULT_FORWARD_TTL,
+                    seq_no: None,
+                    payload: vec![0; 60_000],
+                    queued_at: Instant::now(),
+                })
+                .await
+                .unwrap();
+        }
+        command_sender.send(ServiceCommand::_Stop).await.unwrap();
+        command_sender.closed().await;
+
+        assert!(matches!(
+            mesh.recv().await,
+            Some(WriteLoopCommands::ForwardPacket(_))
+        ));
+        assert!(timeout(Duration::from_millis(50), mesh.recv())
+            .await
+            .is_err());
+        assert_eq!(service.read().await.rate_limited_packets(), 1);
+    }
+
+    #[tokio::test]
+    async fn closed_routes_are_removed() {
+        let service = DerpService::new(Config::parse_from(["dersp"]))
+            .await
+            .unwrap();
+        let command_sender = service.read().await.command_sender.clone();
+        let (dead_sink, dead) = BoundedMpsc::channel(4);
+        let (live_sink, mut live) = BoundedMpsc::channel(4);
+        drop(dead);
+        let (source, via) = (SecretKey::gen().public(), SecretKey::gen().public());
+        let (dead_peer, live_peer) = (SecretKey::gen().public(), SecretKey::gen().public());
+
+        for (peer, sink) in [(dead_peer, dead_sink), (live_peer, live_sink)] {
+            command_sender
+                .send(ServiceCommand::MeshPeerPresent(via, peer, sink))
+                .await
+                .unwrap();
+        }
+        for target in [dead_peer, live_peer] {
+            command_sender
+                .send(ServiceCommand::SendPacket {
+                    source,
+                    target,
+                    ttl: DEFAULT_FORWARD_TTL,
+                    seq_no: None,
+                    payload: vec![1, 2, 3],
+                    queued_at: Instant::now(),
+                })
+                .await
+                .unwrap();
+        }
+        command_sender.send(ServiceCommand::_Stop).await.unwrap();
+        command_sender.closed().await;
+
+        assert!(matches!(
+            live.recv().await,
+            Some(WriteLoopCommands::ForwardPacket(_))
+        ));
+        let service = service.read().await;
+        assert!(!service.peers_sinks.contains_key(&dead_peer));
+        assert!(service.peers_sinks.contains_key(&live_peer));
+    }
+
+    #[tokio::test]
+    async fn forwarded_packets_are_journaled() {
+        let config = Config::parse_from(["dersp", "--enable-packet-journal"]);
+        let service = DerpService::new(config).await.unwrap();
+        let command_sender = service.read().await.command_sender.clone();
+        let (sink, _mesh) = BoundedMpsc::channel(4);
+        let (source, peer, via) = (
+            SecretKey::gen().public(),
+            SecretKey::gen().public(),
+            SecretKey::gen().public(),
+        );
+
+        command_sender
+            .send(ServiceCommand::MeshPeerPresent(via, peer, sink))
+            .await
+            .unwrap();
+        for target in [peer, SecretKey::gen().public()] {
+            command_sender
+                .send(ServiceCommand::SendPacket {
+                    source,
+                    target,
+                    ttl: DEFAULT_FORWARD_TTL,
+                    seq_no: None,
+                    payload: vec![1, 2, 3],
+                    queued_at: Instant::now(),
+                })
+                .await
+                .unwrap();
+        }
+        command_sender.send(ServiceCommand::_Stop).await.unwrap();
+        command_sender.closed().await;
+
+        // Packets to unknown peers are not forwarded, so they are not journaled either
+        let recent = service.read().await.recent_packets(100);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].source_pk, source);
+        assert_eq!(recent[0].target_pk, peer);
+        assert_eq!(recent[0].size_bytes, 3);
+        assert_eq!(recent[0].via, Via::Mesh);
+
+        let service = DerpService::new(Config::parse_from(["dersp"]))
+            .await
+            .unwrap();
+        assert!(service.read().await.recent_packets(100).is_empty());
+    }
+
+    #[tokio::test]
+    async fn packets_to_unknown_peers_are_counted() {
+        let service = DerpService::new(Config::parse_from(["dersp"]))
+            .await
+            .unwrap();
+        let command_sender = service.read().await.command_sender.clone();
+
+        command_sender
+            .send(ServiceCommand::SendPacket {
+                source: SecretKey::gen().public(),
+                target: SecretKey::gen().public(),
+                ttl: DEFAULT_FORWARD_TTL,
+                seq_no: None,
+                payload: vec![1, 2, 3],
+                queued_at: Instant::now(),
+            })
+            .await
+            .unwrap();
+        command_sender.send(ServiceCommand::_Stop).await.unwrap();
+        command_sender.closed().await;
+        assert_eq!(service.read().await.unknown_target_packets(), 1);
+    }
+
+    #[tokio::test]
+    async fn slow_handshakes_time_out() {
+        let config = Config::parse_from(["dersp", "--handshake-timeout-secs", "1"]);
+        let service = DerpService::new(config).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn({
+            let service = service.clone();
+            async move { service.run(listener).await }
+        });
+
+        // The client upgrades, but never answers the server key
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /derp HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: DERP\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(3), stream.read_to_end(&mut received))
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+        assert!(received.starts_with(b"HTTP/1.1 200 OK\r\n\r\n"));
+        // The connection is closed just before the timeout is counted
+        wait_until("the handshake timeout is counted", || async {
+            service.read().await.handshake_timeouts() == 1
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn peer_moving_between_mesh_peers_is_rerouted() {
+        let service = DerpService::new(Config::parse_from(["dersp"]))
+            .await
+            .unwrap();
+        let command_sender = service.read().await.command_sender.clone();
+        let (old_sink, mut old_mesh) = BoundedMpsc::channel(4);
+        let (new_sink, mut new_mesh) = BoundedMpsc::channel(4);
+        let (source, peer) = (SecretKey::gen().public(), SecretKey::gen().public());
+
+        for sink in [old_sink.clone(), new_sink] {
+            let via = SecretKey::gen().public();
+            command_sender
+                .send(ServiceCommand::MeshPeerPresent(via, peer, sink))
+                .await
+                .unwrap();
+        }
+        command_sender
+            .send(ServiceCommand::SendPacket {
+                source,
+                target: peer,
+                ttl: DEFAULT_FORWARD_TTL,
+                seq_no: None,
+                payload: vec![1, 2, 3],
+                queued_at: Instant::now(),
+            })
+            .await
+            .unwrap();
+        command_sender.send(ServiceCommand::_Stop).await.unwrap();
+        command_sender.closed().await;
+
+        match new_mesh.recv().await {
+            Some(WriteLoopCommands::ForwardPacket(forward_packet)) => {
+                assert_eq!(forward_packet.target, peer);
+                assert_eq!(forward_packet.payload, vec![1, 2, 3]);
+            }
+            command => panic!("Unexpected command: {command:?}"),
+        }
+        // The link to the old mesh peer is left alone
+        assert!(old_sink.is_empty());
+        drop((service, old_sink));
+        assert!(old_mesh.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn full_write_queues_follow_backpressure_policy() {
+        // Packets delivered through a queue with room for two, and the drops counted as newest,
+        // oldest and timed out, after sending three
+        let cases = [
+            ("drop-newest", [1, 2], [1, 0, 0]),
+            ("drop-oldest", [2, 3], [0, 1, 0]),
+            ("block-sender", [1, 2], [0, 0, 1]),
+        ];
+        for (policy, delivered, drops) in cases {
+            let config = Config::parse_from(["dersp", "--write-backpressure", policy]);
+            let service = DerpService::new(config).await.unwrap();
+            let command_sender = service.read().await.command_sender.clone();
+            let (sink, mut mesh) = BoundedMpsc::channel(2);
+            let (source, peer) = (SecretKey::gen().public(), SecretKey::gen().public());
+
+            let via = SecretKey::gen().public();
+            command_sender
+                .send(ServiceCommand::MeshPeerPresent(via, peer, sink))
+                .await
+                .unwrap();
+            for byte in 1..=3 {
+                command_sender
+                    .send(ServiceCommand::SendPacket {
+                        source,
+                        target: peer,
+                        ttl: DEFAULT_FORWARD_TTL,
+                        seq_no: None,
+                        payload: vec![byte],
+                        queued_at: Instant::now(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            command_sender.send(ServiceCommand::_Stop).await.unwrap();
+            command_sender.closed().await;
+
+            for byte in delivered {
+                match mesh.recv().await {
+                    Some(WriteLoopCommands::ForwardPacket(forward_packet)) => {
+                        assert_eq!(forward_packet.payload, vec![byte], "{policy}");
+                    }
+                    command => panic!("Unexpected command: {command:?}"),
+                }
+            }
+            let service = service.read().await;
+            let counted = [
+                service.dropped_newest_packets(),
+                service.dropped_oldest_packets(),
+                service.send_timeout_packets(),
+            ];
+            assert_eq!(counted, drops, "{policy}");
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_packets_are_counted_per_client() {
+        let config = Config::parse_from(["dersp", "--write-backpressure", "drop-oldest"]);
+        let service = DerpService::new(config).await.unwrap();
+        let command_sender = service.read().await.command_sender.clone();
+        let (sink, _receiver) = BoundedMpsc::channel(2);
+        let stats = Arc::new(ClientStats::default());
+        let (source, peer) = (SecretKey::gen().public(), SecretKey::gen().public());
+        let record = ClientRecord {
+            connected_at: Instant::now(),
+            can_mesh: false,
+            stats: stats.clone(),
+        };
+        service
+            .write()
+            .await
+            .peers_sinks
+            .insert(peer, PeerRoute::Local(sink, record));
+
+        for byte in 1..=3 {
+            command_sender
+                .send(ServiceCommand::SendPacket {
+                    source,
+                    target: peer,
+                    ttl: DEFAULT_FORWARD_TTL,
+                    seq_no: None,
+                    payload: vec![byte],
+                    queued_at: Instant::now(),
+                })
+                .await
+                .unwrap();
+        }
+        command_sender.send(ServiceCommand::_Stop).await.unwrap();
+        command_sender.closed().await;
+
+        assert_eq!(stats.dropped_packets.load(Ordering::Relaxed), 1);
+        assert_eq!(service.read().await.peer_list()[0].dropped_packets, 1);
+    }
+
+    #[tokio::test]
+    async fn disconnecting_client_closes_its_connection() {
+        let service = DerpService::new(Config::parse_from(["dersp"]))
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn({
+            let service = service.clone();
+            async move { service.run(listener).await }
+        });
+
+        let sk = SecretKey::gen();
+        let (mut reader, _writer) = connect_client(addr, sk, None).await;
+        wait_until("the client is connected", || async {
+            service.read().await.client_count() == 1
+        })
+        .await;
+
+        assert!(service.write().await.disconnect_client(sk.public()).await);
+        assert_eq!(service.read().await.client_count(), 0);
+        assert!(matches!(
+            timeout(Duration::from_secs(1), reader.get_next_message())
+                .await
+                .unwrap(),
+            Err(proto::Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+        assert!(!service.write().await.disconnect_client(sk.public()).await);
+    }
+
+    #[tokio::test]
+    async fn mesh_peer_can_close_clients() {
+        let config = Config::parse_from(["dersp", "--meshkey", "meshkey"]);
+        let service = DerpService::new(config).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn({
+            let service = service.clone();
+            async move { service.run(listener).await }
+        });
+
+        let sk = SecretKey::gen();
+        let (mut reader, _writer) = connect_client(addr, sk, None).await;
+        let (_mesh_reader, mut mesh_writer) =
+            connect_client(addr, SecretKey::gen(), Some("meshkey")).await;
+        wait_until("both peers are connected", || async {
+            service.read().await.client_count() == 2
+        })
+        .await;
+
+        Frame::new(ClosePeer {
+            public_key: sk.public(),
+        })
+        .write_all(&mut mesh_writer)
+        .await
+        .unwrap();
+        assert!(matches!(
+            timeout(Duration::from_secs(1), reader.get_next_message())
+                .await
+                .unwrap(),
+            Err(proto::Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+        assert!(!service.read().await.peers_sinks.contains_key(&sk.public()));
+    }
+
+    #[tokio::test]
+    async fn recipients_are_told_when_mesh_peer_goes_down() {
+        let service = DerpService::new(Config::parse_from(["dersp"]))
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn({
+            let service = service.clone();
+            async move { service.run(listener).await }
+        });
+        let command_sender = service.read().await.command_sender.clone();
+
+        let sk = SecretKey::gen();
+        let (mut reader, _writer) = connect_client(addr, sk, None).await;
+        wait_until("the client is connected", || async {
+            service.read().await.client_count() == 1
+        })
+        .await;
+
+        let (mesh_peer, peer) = (SecretKey::gen().public(), SecretKey::gen().public());
+        let (mesh_sink, _mesh_receiver) = BoundedMpsc::channel(4);
+        for command in [
+            ServiceCommand::MeshPeerUp(mesh_peer, mesh_sink.clone()),
+            ServiceCommand::MeshPeerPresent(mesh_peer, peer, mesh_sink),
+            ServiceCommand::SendPacket {
+                source: peer,
+                target: sk.public(),
+                ttl: DEFAULT_FORWARD_TTL,
+                seq_no: Some(1),
+                payload: vec![1, 2, 3],
+                queued_at: Instant::now(),
+            },
+            ServiceCommand::MeshPeerDown(mesh_peer),
+        ] {
+            command_sender.send(command).await.unwrap();
+        }
+
+        let message = timeout(Duration::from_secs(1), reader.get_next_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.ty, FrameType::RecvPacket);
+        let message = timeout(Duration::from_secs(1), reader.get_next_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.ty, FrameType::PeerGone);
+        assert_eq!(message.decode_body::<PeerGone>().unwrap().public_key, peer);
+    }
 }
@@ -0,0 +1,40 @@
+use log::{info, warn};
+use sd_notify::NotifyState;
+use std::time::Duration;
+use tokio::{spawn, time::interval};
+
+/// Keepalive interval used when the watchdog is forced on but systemd did not tell us its timeout
+const DEFAULT_WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tell systemd the service is ready to accept connections.
+pub fn notify_ready() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!("Failed to notify systemd about readiness: {e}");
+    }
+}
+
+/// Periodically send `WATCHDOG=1` at half of the systemd watchdog timeout. With `enabled` unset
+/// the watchdog runs only when systemd requested it through `WATCHDOG_USEC`.
+pub fn start_watchdog(enabled: Option<bool>) {
+    let mut usec = 0;
+    let requested = sd_notify::watchdog_enabled(false, &mut usec);
+    if !enabled.unwrap_or(requested) {
+        return;
+    }
+
+    let period = if requested && usec > 0 {
+        Duration::from_micros(usec) / 2
+    } else {
+        DEFAULT_WATCHDOG_INTERVAL
+    };
+    info!("Sending systemd watchdog keepalives every {period:?}");
+    spawn(async move {
+        let mut interval = interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                warn!("Failed to send systemd watchdog keepalive: {e}");
+            }
+        }
+    });
+}
@@ -0,0 +1,414 @@
+use crate::{
+    audit::AuditLog,
+    client::{Client, ConnectionId, WriteLoopCommands},
+    connection::Connection,
+    crypto::{PublicKey, SecretKey},
+    proto::{
+        data::{RecvPacket, ServerCapabilities},
+        handle_handshake,
+    },
+    queue::{BoundedMpsc, BoundedMpscReceiver},
+    service::{Service, ServiceCommand},
+};
+use log::warn;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    spawn,
+    sync::mpsc::{channel, Sender},
+    time::{sleep, timeout},
+};
+
+/// Write watchdog of clients created by the mock
+pub const MOCK_WRITE_WATCHDOG: Duration = Duration::from_secs(30);
+/// Reorder timeout of clients created by the mock
+pub const MOCK_REORDER_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Poll `condition` every 10ms and panic if it does not hold within a second.
+pub async fn wait_until<F: Future<Output = bool>>(what: &str, mut condition: impl FnMut() -> F) {
+    timeout(Duration::from_secs(1), async {
+        while !condition().await {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("Timed out waiting until {what}"));
+}
+
+/// In memory replacement of `DerpService` that handles `ServiceCommand`s synchronously and
+/// records every packet it routes.
+#[derive(Default)]
+pub struct MockDerpService {
+    clients: Mutex<HashMap<PublicKey, BoundedMpsc<WriteLoopCommands>>>,
+    sent_packets: Mutex<Vec<(PublicKey, Vec<u8>)>>,
+}
+
+impl MockDerpService {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Register a client and return the receiving end of its write loop.
+    pub fn add_client(&self, pk: PublicKey) -> BoundedMpscReceiver<WriteLoopCommands> {
+        let (s, r) = BoundedMpsc::channel(16);
+        self.clients.lock().unwrap().insert(pk, s);
+        r
+    }
+
+    /// Whether the write loop of `pk` is still registered.
+    pub fn has_client(&self, pk: &PublicKey) -> bool {
+        self.clients.lock().unwrap().contains_key(pk)
+    }
+
+    /// Packets delivered so far, as `(target, payload)` pairs.
+    pub fn sent_packets(&self) -> Vec<(PublicKey, Vec<u8>)> {
+        self.sent_packets.lock().unwrap().clone()
+    }
+
+    pub fn handle_command(&self, command: ServiceCommand) {
+        match command {
+            ServiceCommand::SendPacket {
+                source,
+                target,
+                payload,
+                ..
+            } => {
+                let clients = self.clients.lock().unwrap();
+                let Some(sink) = clients.get(&target) else {
+                    return;
+                };
+                self.sent_packets
+                    .lock()
+                    .unwrap()
+                    .push((target, payload.clone()));
+                if let Err(e) = sink.try_send(WriteLoopCommands::RecvPacket(RecvPacket {
+                    source,
+                    payload,
+                })) {
+                    warn!("Mock failed to deliver packet to {target:?}: {e}");
+                }
+            }
+            ServiceCommand::SubscribeForPeerChanges(..) => (),
+            ServiceCommand::MeshPeerPresent(_, pk, sink) => {
+                self.clients.lock().unwrap().entry(pk).or_insert(sink);
+            }
+            ServiceCommand::PeerGone(pk, sink) => {
+                let mut clients = self.clients.lock().unwrap();
+                if clients.get(&pk).is_some_and(|s| s.same_channel(&sink)) {
+                    clients.remove(&pk);
+                }
+            }
+            ServiceCommand::MeshPeerUp(..) | ServiceCommand::MeshPeerDown(..) => (),
+            ServiceCommand::ClosePeer(..) => (),
+            ServiceCommand::_Stop => (),
+        }
+    }
+
+    /// Returns a sender whose commands are handled by this mock.
+    pub fn command_sender(self: &Arc<Self>) -> Sender<ServiceCommand> {
+        let (s, mut r) = channel(16);
+        let service = self.clone();
+        spawn(async move {
+            while let Some(command) = r.recv().await {
+                service.handle_command(command);
+            }
+        });
+        s
+    }
+}
+
+impl Service for Arc<MockDerpService> {
+    async fn run(&self, listener: TcpListener) -> anyhow::Result<()> {
+        for id in 0.. {
+            let (socket, _) = listener.accept().await?;
+            let mut connection = Connection::accept(socket).await?;
+            let sk = SecretKey::gen();
+            let pk = handle_handshake(&mut connection, &sk, ServerCapabilities::default())
+                .await?
+                .public_key;
+            let sink = Client::new(
+                connection,
+                ConnectionId(id),
+                pk,
+                false,
+                None,
+                AuditLog::default(),
+                MOCK_WRITE_WATCHDOG,
+                MOCK_REORDER_TIMEOUT,
+                None,
+            )
+            .run(self.command_sender())
+            .await?;
+            self.clients.lock().unwrap().insert(pk, sink);
+        }
+        Ok(())
+    }
+}
+
+mod tests {
+    use super::*;
+    use crate::{
+        client::ClientStats,
+        inout::DerpReader,
+        proto::data::{ForwardPacket, Frame, FrameType, SendPacket, DEFAULT_FORWARD_TTL},
+    };
+    use codec::Encode;
+    use std::sync::atomic::Ordering;
+    use tokio::io::{duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    const A: PublicKey = PublicKey::new([1; 32]);
+    const B: PublicKey = PublicKey::new([2; 32]);
+
+    /// Run a client for `A` on the given ends of its connection and register it with `service`.
+    async fn spawn_client(
+        service: &Arc<MockDerpService>,
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> BoundedMpsc<WriteLoopCommands> {
+        spawn_client_with(service, reader, writer, MOCK_WRITE_WATCHDOG, None).await
+    }
+
+    async fn spawn_client_with(
+        service: &Arc<MockDerpService>,
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+        write_watchdog: Duration,
+        idle_timeout: Option<Duration>,
+    ) -> BoundedMpsc<WriteLoopCommands> {
+        let connection = Connection {
+            peer: "127.0.0.1:1".parse().unwrap(),
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+        };
+        let sink = Client::new(
+            connection,
+            ConnectionId(0),
+            A,
+            false,
+            None,
+            AuditLog::default(),
+            write_watchdog,
+            MOCK_REORDER_TIMEOUT,
+            idle_timeout,
+        )
+        .run(service.command_sender())
+        .await
+        .unwrap();
+        service.clients.lock().unwrap().insert(A, sink.clone());
+        sink
+    }
+
+    async fn write_send_packet<W: AsyncWrite + Unpin>(writer: &mut W, payload: Vec<u8>) {
+        let mut buf = Vec::new();
+        Frame::new(SendPacket { target: B, payload })
+            .encode(&mut buf)
+            .unwrap();
+        writer.write_all(&buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_read_loop_sends_packets_to_service() {
+        let service = MockDerpService::new();
+        let _b_commands = service.add_client(B);
+        let (reader, mut remote_writer) = duplex(1024);
+        let (writer, _remote_reader) = duplex(1024);
+        spawn_client(&service, reader, writer).await;
+
+        write_send_packet(&mut remote_writer, vec![4, 5, 6]).await;
+
+        wait_until("the packet is routed", || async {
+            !service.sent_packets().is_empty()
+        })
+        .await;
+        assert_eq!(service.sent_packets(), vec![(B, vec![4, 5, 6])]);
+    }
+
+    #[tokio::test]
+    async fn client_ignores_mesh_frames_without_meshkey() {
+        let service = MockDerpService::new();
+        let _b_commands = service.add_client(B);
+        let (reader, mut remote_writer) = duplex(1024);
+        let (writer, _remote_reader) = duplex(1024);
+        spawn_client(&service, reader, writer).await;
+
+        let forward_packet = ForwardPacket {
+            source: A,
+            target: B,
+            ttl: DEFAULT_FORWARD_TTL,
+            seq_no: None,
+            payload: vec![1, 2, 3],
+        };
+        crate::proto::write_forward_packet(&mut remote_writer, forward_packet)
+            .await
+            .unwrap();
+        crate::proto::write_peer_gone(&mut remote_writer, &B)
+            .await
+            .unwrap();
+        write_send_packet(&mut remote_writer, vec![4, 5, 6]).await;
+
+        // Only the packet sent as a client goes through, and B is still known to the service
+        wait_until("the packet is routed", || async {
+            !service.sent_packets().is_empty()
+        })
+        .await;
+        assert_eq!(service.sent_packets(), vec![(B, vec![4, 5, 6])]);
+    }
+
+    #[tokio::test]
+    async fn disconnected_client_is_gone() {
+        let service = MockDerpService::new();
+        let (reader, remote_writer) = duplex(1024);
+        let (writer, remote_reader) = duplex(1024);
+        spawn_client(&service, reader, writer).await;
+
+        drop((remote_writer, remote_reader));
+        wait_until("the client is gone", || async { !service.has_client(&A) }).await;
+    }
+
+    #[tokio::test]
+    async fn keepalives_keep_idle_client_connected() {
+        let service = MockDerpService::new();
+        let (reader, mut remote_writer) = duplex(1024);
+        let (writer, _remote_reader) = duplex(1024);
+        spawn_client_with(
+            &service,
+            reader,
+            writer,
+            MOCK_WRITE_WATCHDOG,
+            Some(Duration::from_millis(200)),
+        )
+        .await;
+
+        // Keepalives for three times the idle timeout
+        for _ in 0..12 {
+            remote_writer.write_all(&[6, 0, 0, 0, 0]).await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+        }
+        assert!(service.has_client(&A));
+
+        wait_until("the idle client is gone", || async {
+            !service.has_client(&A)
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stuck_write_closes_the_connection() {
+        let (writer, mut remote) = duplex(16);
+        let (sink, _) = Client::start_write_loop(
+            Box::new(writer),
+            ConnectionId(0),
+            A,
+            None,
+            Default::default(),
+            Duration::from_millis(100),
+            MOCK_REORDER_TIMEOUT,
+        );
+
+        // The remote never reads, so the write can not finish
+        sink.send(WriteLoopCommands::RecvPacket(RecvPacket {
+            source: B,
+            payload: vec![0; 64],
+        }))
+        .await
+        .unwrap();
+        sleep(Duration::from_millis(300)).await;
+
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(1), remote.read_to_end(&mut received))
+            .await
+            .expect("writer was not closed")
+            .unwrap();
+        assert_eq!(received.len(), 16);
+    }
+
+    #[tokio::test]
+    async fn stuck_write_reports_client_as_gone() {
+        let service = MockDerpService::new();
+        let (reader, _remote_writer) = duplex(16);
+        let (writer, _remote_reader) = duplex(16);
+        let sink =
+            spawn_client_with(&service, reader, writer, Duration::from_millis(100), None).await;
+
+        // The remote never reads, but keeps its writer open, so only the watchdog ends the client
+        sink.send(WriteLoopCommands::RecvPacket(RecvPacket {
+            source: B,
+            payload: vec![0; 64],
+        }))
+        .await
+        .unwrap();
+        wait_until("the stuck client is gone", || async {
+            !service.has_client(&A)
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stopped_client_stops_reading() {
+        let service = MockDerpService::new();
+        let (reader, mut remote_writer) = duplex(1024);
+        let (writer, _remote_reader) = duplex(1024);
+        let sink = spawn_client(&service, reader, writer).await;
+
+        sink.send(WriteLoopCommands::_Stop).await.unwrap();
+        // Once the read loop is gone, writes of the remote fail
+        timeout(Duration::from_secs(1), async {
+            while remote_writer.write_all(&[6, 0, 0, 0, 0]).await.is_ok() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("read loop of a stopped client kept running");
+        wait_until("the stopped client is gone", || async {
+            !service.has_client(&A)
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn dropped_forward_packets_are_not_counted_as_sent() {
+        let (writer, remote) = duplex(1024);
+        let stats = Arc::new(ClientStats::default());
+        let (sink, _) = Client::start_write_loop(
+            Box::new(writer),
+            ConnectionId(0),
+            A,
+            None,
+            stats.clone(),
+            MOCK_WRITE_WATCHDOG,
+            MOCK_REORDER_TIMEOUT,
+        );
+
+        for payload_len in [ForwardPacket::MAX_PAYLOAD_SIZE + 1, 3] {
+            sink.send(WriteLoopCommands::ForwardPacket(ForwardPacket::new(
+                B,
+                PublicKey::new([3; 32]),
+                DEFAULT_FORWARD_TTL,
+                Some(1),
+                vec![0; payload_len],
+            )))
+            .await
+            .unwrap();
+        }
+        drop(sink);
+
+        let mut reader = DerpReader::new(remote);
+        let message = reader.get_next_message().await.unwrap();
+        assert_eq!(message.ty, FrameType::ForwardPacket);
+        assert_eq!(
+            message
+                .decode_body::<ForwardPacket>()
+                .unwrap()
+                .payload
+                .len(),
+            3
+        );
+        assert!(reader.get_next_message().await.is_err());
+        assert_eq!(stats.bytes_sent.load(Ordering::Relaxed), 3);
+    }
+}
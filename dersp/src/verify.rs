@@ -0,0 +1,122 @@
+//! `dersp verify`: check the options and the mesh peers before deploying them.
+use crate::{crypto::SecretKey, mesh_client::MeshClient, Config};
+use futures_util::future::join_all;
+use tokio::{net::lookup_host, sync::mpsc::channel};
+
+/// Exit code when some mesh peers could not be reached
+pub const EXIT_UNREACHABLE_PEERS: i32 = 1;
+/// Exit code when the options are invalid
+pub const EXIT_INVALID_CONFIG: i32 = 2;
+
+/// Validate `config` and try a handshake with every mesh peer, printing a summary. Returns the
+/// exit code.
+///
+/// The handshake ends before the mesh key is checked by the peer, a wrong key is not detected.
+pub async fn run(config: &Config) -> i32 {
+    if let Err(e) = validate(config).await {
+        println!("Invalid config: {e:#}");
+        return EXIT_INVALID_CONFIG;
+    }
+    // Mesh peers are only connected to, never registered with a service, so nothing is ever sent
+    let (command_sender, _) = channel(1);
+    let settings = match config.mesh_peer_settings(SecretKey::gen(), command_sender) {
+        Ok(Some(settings)) => settings,
+        Ok(None) if config.mesh_peers.is_empty() => {
+            println!("No mesh peers to check");
+            return 0;
+        }
+        Ok(None) => {
+            println!("Invalid config: --mesh-peers needs a mesh key");
+            return EXIT_INVALID_CONFIG;
+        }
+        Err(e) => {
+            println!("Invalid config: {e:#}");
+            return EXIT_INVALID_CONFIG;
+        }
+    };
+
+    let results = join_all(config.mesh_peers.iter().map(|addr| {
+        let settings = &settings;
+        async move { MeshClient::new(addr, settings).await?.verify().await }
+    }))
+    .await;
+    let mut reachable = 0;
+    for (addr, result) in config.mesh_peers.iter().zip(results) {
+        match result {
+            Ok(pk) => {
+                reachable += 1;
+                println!("{addr}: reachable, public key {pk}");
+            }
+            Err(e) => println!("{addr}: unreachable: {e:#}"),
+        }
+    }
+    println!(
+        "{reachable} of {} mesh peers reachable",
+        config.mesh_peers.len()
+    );
+    if reachable < config.mesh_peers.len() {
+        return EXIT_UNREACHABLE_PEERS;
+    }
+    0
+}
+
+/// Check the options the server would fail to start with.
+async fn validate(config: &Config) -> anyhow::Result<()> {
+    if let Some(listen_on) = &config.listen_on {
+        anyhow::ensure!(
+            lookup_host(listen_on).await?.next().is_some(),
+            "{listen_on} does not resolve to any address"
+        );
+    }
+    #[cfg(feature = "quic-transport")]
+    if let Some(quic_listen_on) = &config.quic_listen_on {
+        quic_listen_on.parse::<std::net::SocketAddr>()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::{DerpService, Service};
+    use clap::Parser;
+    use tokio::{net::TcpListener, spawn};
+
+    #[tokio::test]
+    async fn exit_code_tells_unreachable_peers_from_invalid_config() {
+        let config = Config::parse_from(["dersp", "--meshkey", "mesh"]);
+        let service = DerpService::new(config).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        spawn(async move { service.run(listener).await });
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap().to_string();
+        drop(closed);
+
+        let config = [
+            "dersp",
+            "--meshkey",
+            "mesh",
+            "--mesh-peers",
+            &addr,
+            "verify",
+        ];
+        assert_eq!(run(&Config::parse_from(config)).await, 0);
+        let config = [
+            "dersp",
+            "--meshkey",
+            "mesh",
+            "--mesh-peers",
+            &addr,
+            "--mesh-peers",
+            &closed_addr,
+            "verify",
+        ];
+        assert_eq!(
+            run(&Config::parse_from(config)).await,
+            EXIT_UNREACHABLE_PEERS
+        );
+        let config = ["dersp", "--mesh-peers", &addr, "verify"];
+        assert_eq!(run(&Config::parse_from(config)).await, EXIT_INVALID_CONFIG);
+    }
+}